@@ -0,0 +1,222 @@
+//! The external programs that download, normalize and tag music,
+//! every kind of program has a trait so the program can be chosen in the config
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+};
+
+use log::error;
+use serde::Deserialize;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::config::{Config, DownloaderProgram, NormalizerProgram, TaggerProgram, ToolConfig};
+
+pub mod downloader;
+pub mod normalizer;
+pub mod tagger;
+
+/// Downloads music
+pub trait Downloader {
+    /// Download the music at the url to the directory, a video or a playlist or channel
+    fn download(
+        &self,
+        url: &str,
+        dir: &Path,
+        options: &DownloadOptions,
+        quiet: &bool,
+    ) -> Result<Downloaded>;
+}
+
+/// Which videos are downloaded and how
+#[derive(Clone, Debug)]
+pub struct DownloadOptions {
+    /// The file with the videos that were downloaded before, they are skipped
+    pub archive: Option<PathBuf>,
+    /// Only videos uploaded on or after this date, like 20240131 or today-2weeks
+    pub since: Option<String>,
+    /// The audio format, opus by default
+    pub format: Option<String>,
+    /// If videos are split in their chapters
+    pub split_chapters: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            archive: None,
+            since: None,
+            format: None,
+            split_chapters: true,
+        }
+    }
+}
+
+/// What a download gave
+#[derive(Default, Debug)]
+pub struct Downloaded {
+    /// The videos that were downloaded
+    pub downloads: Vec<Download>,
+    /// The ids of all videos at the url, also the skipped ones, empty for a single video
+    pub found: BTreeSet<String>,
+}
+
+/// A downloaded video, with the files it was saved to
+#[derive(Deserialize, Clone, Debug)]
+pub struct Download {
+    pub id: String,
+    pub title: Option<String>,
+    pub uploader: Option<String>,
+    pub channel: Option<String>,
+    pub webpage_url: Option<String>,
+    /// Like 20240131
+    pub upload_date: Option<String>,
+    /// The music in the video, if the site knows it
+    pub track: Option<String>,
+    pub track_number: Option<u32>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub release_year: Option<u32>,
+    /// In seconds
+    pub duration: Option<f64>,
+    /// The file of the whole video
+    pub filepath: PathBuf,
+    /// The chapters, with split chapters every chapter has a file of its own
+    pub chapters: Option<Vec<Chapter>>,
+}
+
+/// A part of a video
+#[derive(Deserialize, Clone, Debug)]
+pub struct Chapter {
+    pub title: Option<String>,
+    /// In seconds
+    pub start_time: f64,
+    pub end_time: f64,
+    pub filepath: Option<PathBuf>,
+}
+
+/// Writes replaygain tags
+pub trait Normalizer {
+    /// Write the replaygain tags of the files of an album, existing tags are replaced
+    fn normalize(&self, dir: &Path, files: &[&Path], quiet: &bool) -> Result<()>;
+}
+
+/// Tags music
+pub trait Tagger {
+    /// Tag the files, files that could not be tagged are left without tags
+    fn tag(&self, dir: &Path, files: &[String], quiet: &bool) -> Result<()>;
+}
+
+/// The downloader from the config
+pub fn downloader(config: &Config) -> Box<dyn Downloader> {
+    let program = |name| Program::new(name, &config.downloader);
+    match config.downloader.as_ref().and_then(|tool| tool.program) {
+        Some(DownloaderProgram::YtDlp) | None => Box::new(downloader::YtDlp(program("yt-dlp"))),
+    }
+}
+
+/// The normalizer from the config, native if it is built in, otherwise rsgain
+pub fn normalizer(config: &Config) -> Box<dyn Normalizer> {
+    let program = |name| Program::new(name, &config.normalizer);
+    match config.normalizer.as_ref().and_then(|tool| tool.program) {
+        Some(NormalizerProgram::Rsgain) => Box::new(normalizer::Rsgain(program("rsgain"))),
+        Some(NormalizerProgram::Loudgain) => Box::new(normalizer::Loudgain(program("loudgain"))),
+        Some(NormalizerProgram::Ffmpeg) => Box::new(normalizer::Ffmpeg(program("ffmpeg"))),
+        // the path and arguments are for rsgain, for the files native can not decode
+        Some(NormalizerProgram::Native) => {
+            Box::new(normalizer::Native(normalizer::Rsgain(program("rsgain"))))
+        }
+        #[cfg(feature = "native-replaygain")]
+        None => Box::new(normalizer::Native(normalizer::Rsgain(program("rsgain")))),
+        #[cfg(not(feature = "native-replaygain"))]
+        None => Box::new(normalizer::Rsgain(program("rsgain"))),
+    }
+}
+
+/// The tagger from the config
+pub fn tagger(config: &Config) -> Box<dyn Tagger> {
+    let program = |name| Program::new(name, &config.tagger);
+    match config.tagger.as_ref().and_then(|tool| tool.program) {
+        Some(TaggerProgram::Picard) | None => Box::new(tagger::Picard(program("picard"))),
+        Some(TaggerProgram::Beets) => Box::new(tagger::Beets(program("beet"))),
+    }
+}
+
+/// An external program, with the path and extra arguments from the config
+pub struct Program {
+    path: PathBuf,
+    args: Vec<String>,
+}
+
+impl Program {
+    /// The program with its default name, unless the config has another path
+    fn new<P>(name: &str, tool: &Option<ToolConfig<P>>) -> Program {
+        let tool = tool.as_ref();
+        Program {
+            path: tool
+                .and_then(|tool| tool.path.clone())
+                .unwrap_or(PathBuf::from(name)),
+            args: tool.and_then(|tool| tool.args.clone()).unwrap_or_default(),
+        }
+    }
+
+    fn name(&self) -> String {
+        self.path
+            .file_name()
+            .unwrap_or(self.path.as_os_str())
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// A command that runs in the directory, without output if quiet
+    fn command(&self, dir: &Path, quiet: &bool) -> Command {
+        let output = || match quiet {
+            true => Stdio::null(),
+            false => Stdio::inherit(),
+        };
+        let mut command = Command::new(&self.path);
+        command.current_dir(dir).stdout(output()).stderr(output());
+        command
+    }
+
+    /// Run a command with the extra arguments before the arguments at the end,
+    /// gives an error if it was not successful
+    fn run(&self, mut command: Command, end: &[impl AsRef<std::ffi::OsStr>]) -> Result<()> {
+        let status = command
+            .args(&self.args)
+            .args(end)
+            .status()
+            .with_context(|| format!("Could not execute {}", self.name()))?;
+        self.check(status)
+    }
+
+    /// Run a command like `run`, but give what it printed instead of showing it,
+    /// the status is not checked because a part might have worked
+    fn output(
+        &self,
+        mut command: Command,
+        end: &[impl AsRef<std::ffi::OsStr>],
+    ) -> Result<(ExitStatus, String)> {
+        let output = command
+            .args(&self.args)
+            .args(end)
+            .stdout(Stdio::piped())
+            .output()
+            .with_context(|| format!("Could not execute {}", self.name()))?;
+        Ok((
+            output.status,
+            String::from_utf8_lossy(&output.stdout).to_string(),
+        ))
+    }
+
+    fn check(&self, status: ExitStatus) -> Result<()> {
+        if !status.success() {
+            let name = self.name();
+            error!("{name} {status}");
+            return Err(anyhow!("{name} was not successful, {status}"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use std::{collections::BTreeSet, path::Path};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::Deserialize;
+
+use super::{Download, DownloadOptions, Downloaded, Downloader, Program};
+
+/// What yt-dlp prints of every video after it is saved, the fields of `Download`
+const PRINT_VIDEO: &str = "after_move:%(.{id,title,uploader,channel,webpage_url,upload_date,\
+    track,track_number,artist,album,release_year,duration,filepath,chapters})j";
+/// What yt-dlp prints of every playlist, the ids of all its entries, also the skipped ones
+const PRINT_PLAYLIST: &str = r#"playlist:{"playlist": %(id)j, "entries": %(entries.:.id|[])j}"#;
+
+/// A line yt-dlp printed
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Printed {
+    Video(Box<Download>),
+    Playlist {
+        playlist: String,
+        entries: Vec<String>,
+    },
+}
+
+/// yt-dlp, downloads the best audio as opus and splits it in its chapters by default
+pub struct YtDlp(pub(crate) Program);
+
+impl Downloader for YtDlp {
+    fn download(
+        &self,
+        url: &str,
+        dir: &Path,
+        options: &DownloadOptions,
+        quiet: &bool,
+    ) -> Result<Downloaded> {
+        let mut command = self.0.command(dir, quiet);
+        command.args([
+            "--extract-audio",
+            "-f",
+            "bestaudio",
+            "--audio-format",
+            options.format.as_deref().unwrap_or("opus"),
+            // a video that is not available should not stop a playlist
+            "--ignore-errors",
+            "--print",
+            PRINT_VIDEO,
+            "--print",
+            PRINT_PLAYLIST,
+        ]);
+        if options.split_chapters {
+            command.arg("--split-chapters");
+        }
+        if let Some(archive) = &options.archive {
+            command.arg("--download-archive").arg(archive);
+        }
+        if let Some(since) = &options.since {
+            command.args(["--dateafter", since]);
+        }
+        // printing makes yt-dlp quiet, but the progress is still useful
+        if !quiet {
+            command.arg("--progress");
+        }
+        let (status, output) = self.0.output(command, &[url])?;
+
+        let mut downloaded = Downloaded::default();
+        let mut playlists = BTreeSet::new();
+        for line in output.lines().map(str::trim) {
+            if !line.starts_with('{') {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(Printed::Video(download)) => downloaded.downloads.push(*download),
+                Ok(Printed::Playlist { playlist, entries }) => {
+                    playlists.insert(playlist);
+                    downloaded.found.extend(entries);
+                }
+                Err(err) => warn!("Could not read what yt-dlp printed: {err}, {line}"),
+            }
+        }
+        // the entries of a channel can be playlists themselves
+        downloaded.found.retain(|id| !playlists.contains(id));
+
+        if !status.success() {
+            if downloaded.downloads.is_empty() {
+                return Err(anyhow!("yt-dlp was not successful, {status}"));
+            }
+            warn!("yt-dlp could not download everything, {status}");
+        }
+        Ok(downloaded)
+    }
+}
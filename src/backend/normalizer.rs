@@ -0,0 +1,136 @@
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::music_tag::{write_replaygain_tags, Gain};
+
+use super::{Normalizer, Program};
+
+/// rsgain in custom mode, with album gain
+pub struct Rsgain(pub(crate) Program);
+
+impl Normalizer for Rsgain {
+    fn normalize(&self, dir: &Path, files: &[&Path], quiet: &bool) -> Result<()> {
+        let mut command = self.0.command(dir, quiet);
+        command
+            .arg("custom")
+            .arg(match quiet {
+                true => "-aq",
+                false => "-a",
+            }) // album mode, and quiet if needed
+            .args(["-s", "i"]); // output mode i =  write replaygain2.0 tags plus extra tags
+        self.0.run(command, files)
+    }
+}
+
+/// loudgain, with album gain and without clipping
+pub struct Loudgain(pub(crate) Program);
+
+impl Normalizer for Loudgain {
+    fn normalize(&self, dir: &Path, files: &[&Path], quiet: &bool) -> Result<()> {
+        let mut command = self.0.command(dir, quiet);
+        command.args(["-a", "-k", "-s", "e"]); // album mode, no clipping, write extended tags
+        if *quiet {
+            command.arg("-q");
+        }
+        self.0.run(command, files)
+    }
+}
+
+/// ffmpeg measures the loudness with its ebur128 filter, music_manager writes the tags
+pub struct Ffmpeg(pub(crate) Program);
+
+impl Ffmpeg {
+    /// Measure the loudness of the files played after each other
+    fn measure(&self, dir: &Path, files: &[&Path]) -> Result<Gain> {
+        let mut command = Command::new(&self.0.path);
+        command.current_dir(dir).args(&self.0.args);
+        command.args(["-hide_banner", "-nostats"]);
+        for file in files {
+            command.arg("-i").arg(file);
+        }
+        let inputs: String = (0..files.len()).map(|i| format!("[{i}:a]")).collect();
+        command.args([
+            "-filter_complex",
+            &format!("{inputs}concat=n={}:v=0:a=1,ebur128=peak=true", files.len()),
+            "-f",
+            "null",
+            "-",
+        ]);
+        let output = command
+            .output()
+            .with_context(|| format!("Could not execute {}", self.0.name()))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "{} was not successful, {}",
+                self.0.name(),
+                output.status
+            ));
+        }
+        parse_summary(&String::from_utf8_lossy(&output.stderr))
+    }
+}
+
+impl Normalizer for Ffmpeg {
+    fn normalize(&self, dir: &Path, files: &[&Path], _quiet: &bool) -> Result<()> {
+        let album = self.measure(dir, files)?;
+        for file in files {
+            let track = match files.len() {
+                1 => album,
+                _ => self.measure(dir, &[file])?,
+            };
+            write_replaygain_tags(file, &track, &album)?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the integrated loudness and the true peak from the summary of the ebur128 filter
+fn parse_summary(output: &str) -> Result<Gain> {
+    let (_, summary) = output
+        .rsplit_once("Summary:")
+        .ok_or(anyhow!("ffmpeg did not give a loudness summary"))?;
+    let value = |name: &str| {
+        summary
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(name))
+            .and_then(|value| value.split_whitespace().next())
+            .ok_or(anyhow!("ffmpeg did not give the {name} value"))
+    };
+    let loudness: f64 = value("I:")?.parse()?;
+    // silence has a peak of -inf dBFS
+    let peak: f64 = value("Peak:")?.parse()?;
+    Ok(Gain {
+        loudness,
+        peak: 10f64.powf(peak / 20.0),
+    })
+}
+
+/// The replaygain analysis of music_manager, rsgain is used for files it can not decode
+#[cfg_attr(not(feature = "native-replaygain"), allow(dead_code))]
+pub struct Native(pub(crate) Rsgain);
+
+#[cfg(feature = "native-replaygain")]
+impl Normalizer for Native {
+    fn normalize(&self, dir: &Path, files: &[&Path], quiet: &bool) -> Result<()> {
+        match crate::replaygain::normalize_album(files) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                let Native(rsgain) = self;
+                log::warn!("{err:#}, trying {}", rsgain.0.name());
+                rsgain.normalize(dir, files, quiet)
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "native-replaygain"))]
+impl Normalizer for Native {
+    fn normalize(&self, _dir: &Path, _files: &[&Path], _quiet: &bool) -> Result<()> {
+        Err(anyhow!(
+            "music_manager is built without the native-replaygain feature, \
+            choose another program for the normalizer in the config"
+        ))
+    }
+}
@@ -0,0 +1,30 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::{Program, Tagger};
+
+/// Picard, every file is tagged in a standalone instance
+pub struct Picard(pub(crate) Program);
+
+impl Tagger for Picard {
+    fn tag(&self, dir: &Path, files: &[String], quiet: &bool) -> Result<()> {
+        let mut command = self.0.command(dir, quiet);
+        command.arg("-s"); // standalone instance of Picard
+        self.0.run(command, files)
+    }
+}
+
+/// beets, the files are imported without copying them, so they stay where they are
+pub struct Beets(pub(crate) Program);
+
+impl Tagger for Beets {
+    fn tag(&self, dir: &Path, files: &[String], quiet: &bool) -> Result<()> {
+        let mut command = self.0.command(dir, quiet);
+        command.args(["import", "-C"]);
+        if *quiet {
+            command.arg("-q"); // do not ask anything
+        }
+        self.0.run(command, files)
+    }
+}
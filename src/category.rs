@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 
 use anyhow::{Context, Result};
 
-use crate::create_file;
+use crate::{config::DiscLayout, create_file, planner};
 
 /// Config for the category
 #[derive(Deserialize, Serialize)]
@@ -16,6 +16,13 @@ pub struct CategoryConfig {
     pub artist_category: Option<bool>,
     /// Files to check if they are in the album directory, as a glob pattern
     pub album_files: Option<Vec<String>>,
+    /// Where music files are put in this category, overrides the template in the config
+    pub path_template: Option<String>,
+    /// The directory for albums with music of different artists, "Various Artists" by default,
+    /// it takes the place of the album artist in the path template
+    pub compilations_dir: Option<String>,
+    /// Where the discs of albums with more than one disc go, overrides the config
+    pub disc_layout: Option<DiscLayout>,
 }
 
 /// Get the config for a category
@@ -33,6 +40,10 @@ pub fn get_category_config(category_path: &Path) -> Result<CategoryConfig> {
             description_path.display()
         );
         create_category_config(category_path, None, None)?;
+        // the config is not written in a dry run
+        if planner::is_dry_run() {
+            return Ok(default_category_config(category_path, None, None));
+        }
     }
 
     let contents = fs::read_to_string(&description_path)?;
@@ -65,6 +76,28 @@ pub fn create_category_config(
 
     info!("Creating category config at {}", config_path.display());
 
+    let content = default_category_config(category_path, category_name, category_description);
+
+    let toml = toml::to_string(&content)?;
+    create_file(&config_path, toml)?;
+
+    if !planner::is_dry_run() {
+        info!(
+            "Created category config for {} at \"{}\"",
+            content.name,
+            config_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// The config for a new category, the name defaults to the directory name
+fn default_category_config(
+    category_path: &Path,
+    category_name: Option<&str>,
+    category_description: Option<&str>,
+) -> CategoryConfig {
     let name = match category_name {
         Some(name) => name,
         None => category_path
@@ -77,21 +110,13 @@ pub fn create_category_config(
     let description = category_description
         .unwrap_or("This is a default description for a category, please add your own");
 
-    let content = CategoryConfig {
+    CategoryConfig {
         name: name.to_string(),
         description: description.to_string(),
         artist_category: None,
         album_files: None,
-    };
-
-    let toml = toml::to_string(&content)?;
-    create_file(&config_path, toml)?;
-
-    info!(
-        "Created category config for {} at \"{}\"",
-        name,
-        config_path.display()
-    );
-
-    Ok(())
+        path_template: None,
+        compilations_dir: None,
+        disc_layout: None,
+    }
 }
@@ -19,21 +19,35 @@ pub struct Cli {
     #[clap(short, long)]
     pub quiet: bool,
 
+    /// Only print the directories that would be created and the files that would be moved,
+    /// without changing anything
+    #[clap(short = 'n', long, global = true)]
+    pub dry_run: bool,
+
     #[clap(subcommand)]
     pub command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    /// Download YouTube music and move in a category directory
+    /// Download YouTube music and move in a category directory,
+    /// a video, playlist or channel, videos that were downloaded before are skipped
     #[clap(name = "down")]
     Download {
         #[clap(value_hint=ValueHint::Url)]
         url: String,
         #[clap(default_value_t = String::from("other"))]
         category: String,
+
+        /// Only videos uploaded on or after this date, like 2024-01-31 or today-2weeks
+        #[clap(long)]
+        since: Option<String>,
     },
 
+    /// Download the new videos of the subscriptions of a category, or of all categories
+    #[clap(name = "sync")]
+    Sync { category: Option<String> },
+
     /// Add music to library
     #[clap(name = "add")]
     AddToLib {
@@ -47,6 +61,16 @@ pub enum Commands {
 
         #[clap(short, long)]
         category: String,
+
+        /// What to do when a file already exists in the album,
+        /// overrides conflict_policy in the config
+        #[clap(long, value_enum)]
+        conflict: Option<Conflict>,
+
+        /// How many albums are normalized at the same time,
+        /// overrides normalize_jobs in the config
+        #[clap(short, long)]
+        jobs: Option<usize>,
     },
 
     /// Print categories with a description
@@ -71,6 +95,21 @@ pub enum Commands {
         /// If the tags and path need to be checked, this is a lot slower
         #[clap(short, long)]
         tags_path: bool,
+
+        /// Move misplaced files, normalize files without replaygain tags
+        /// and remove empty directories, implies tags_path
+        #[clap(short, long)]
+        fix: bool,
+
+        /// How to print the report,
+        /// will exit with 2 if any issues were found, or are left after fixing
+        #[clap(long, value_enum, default_value_t = CheckFormat::Text)]
+        format: CheckFormat,
+
+        /// How many albums are normalized at the same time with fix,
+        /// overrides normalize_jobs in the config
+        #[clap(short, long)]
+        jobs: Option<usize>,
     },
 
     /// Tag music and move to the library
@@ -86,5 +125,178 @@ pub enum Commands {
         /// The category that the tagged file will be moved to
         #[clap(short, long)]
         category: String,
+
+        /// What to do when a file already exists in the album,
+        /// overrides conflict_policy in the config
+        #[clap(long, value_enum)]
+        conflict: Option<Conflict>,
+    },
+
+    /// Search the tags of all the music in the library
+    #[clap(name = "search")]
+    Search {
+        /// Search terms, use field:value to search a specific field,
+        /// fields are artist, albumartist, album, title, genre, year and composer
+        #[clap(required = true)]
+        query: Vec<String>,
+
+        /// Print the results as json
+        #[clap(short, long)]
+        json: bool,
+    },
+
+    /// Find music that is in the library more than once, also across categories
+    #[clap(name = "dupes")]
+    Dupes {
+        /// How to find the same music, tags and musicbrainz by default
+        #[clap(short, long, value_enum, value_delimiter = ',')]
+        by: Vec<DupeMatch>,
+        /// What decides which copy is kept, tried in order, format,bitrate,category by default
+        #[clap(short, long, value_enum, value_delimiter = ',')]
+        keep: Vec<KeepRule>,
+        /// Formats from best to worst, like flac,opus, by default lossless before lossy
+        #[clap(long, value_delimiter = ',')]
+        formats: Vec<String>,
+        /// Categories from best to worst, like rock,other
+        #[clap(long, value_delimiter = ',')]
+        categories: Vec<String>,
+        /// Move the copies that are not kept to this directory, outside the music directory
+        #[clap(long, value_hint=ValueHint::DirPath)]
+        move_to: Option<String>,
+        /// Print the duplicates as json
+        #[clap(short, long)]
+        json: bool,
+    },
+    /// Rename music files from their tags
+    #[clap(name = "rename")]
+    Rename {
+        /// An album directory or a category
+        target: String,
+
+        /// The template for the file names, like "{track:02} {title}.{ext}",
+        /// by default the file name from the path template of the category
+        #[clap(short, long)]
+        template: Option<String>,
+    },
+
+    /// View and edit the tags of music files
+    #[clap(name = "tags")]
+    Tags {
+        #[clap(subcommand)]
+        command: TagsCommands,
+    },
+
+    /// Undo the last moves and created directories
+    #[clap(name = "undo")]
+    Undo {
+        /// The number of operations to undo
+        #[clap(default_value_t = 1)]
+        count: usize,
+
+        /// Count commands instead of operations, and undo everything the last commands did
+        #[clap(short, long)]
+        commands: bool,
     },
+
+    /// Manage the library index
+    #[clap(name = "index")]
+    Index {
+        #[clap(subcommand)]
+        command: IndexCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IndexCommands {
+    /// Throw away the index and read all the music files again
+    #[clap(name = "rebuild")]
+    Rebuild,
+
+    /// Read only the music files that changed since the last update
+    #[clap(name = "update")]
+    Update,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TagsCommands {
+    /// Print the tags of music files, or of all music files in album directories
+    #[clap(name = "get")]
+    Get {
+        #[clap(required = true, value_hint=ValueHint::AnyPath)]
+        paths: Vec<String>,
+
+        /// Only print these fields, like title,track
+        #[clap(short, long)]
+        field: Vec<String>,
+    },
+
+    /// Set tag fields of music files, or of all music files in album directories
+    #[clap(name = "set")]
+    Set {
+        #[clap(required = true, value_hint=ValueHint::AnyPath)]
+        paths: Vec<String>,
+
+        /// A field and its value, like album_artist=Radiohead
+        #[clap(short, long)]
+        value: Vec<String>,
+
+        /// Get values from the end of the path with a path template,
+        /// like "{album_artist}/{album}/[{track:02} ]{title}"
+        #[clap(long)]
+        from_path: Option<String>,
+    },
+
+    /// Remove tag fields from music files, or from all music files in album directories
+    #[clap(name = "remove")]
+    Remove {
+        #[clap(required = true, value_hint=ValueHint::AnyPath)]
+        paths: Vec<String>,
+
+        /// The fields to remove, like genre,composer
+        #[clap(short, long, required = true)]
+        field: Vec<String>,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum CheckFormat {
+    /// Log every issue and print a summary
+    Text,
+    Json,
+    /// JUnit XML, for CI systems
+    Junit,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum DupeMatch {
+    /// The same artist and title, ignoring case and punctuation, and about as long
+    Tags,
+    /// The same MusicBrainz recording id
+    Musicbrainz,
+    /// The same audio, reads the files with the same duration
+    Audio,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum KeepRule {
+    /// The format that comes first in --formats, or lossless
+    Format,
+    /// The highest bitrate
+    Bitrate,
+    /// The category that comes first in --categories
+    Category,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Conflict {
+    /// Leave the file where it is
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Add a number to the file name
+    Rename,
+    /// Keep the file with the best quality
+    KeepBest,
+    /// Skip if the audio is the same, otherwise rename
+    CompareAudio,
 }
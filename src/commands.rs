@@ -1,17 +1,15 @@
 use std::collections::HashMap;
-use std::{
-    fs,
-    path::{Path, PathBuf},
-};
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
 
 use glob::Pattern;
 use log::{debug, error, info, warn};
 
-use crate::category::CategoryConfig;
+use crate::config::ConflictPolicy;
+use crate::template::{is_disc_dir, Template};
 use crate::{
-    category::get_category_config, config, move_file, move_files, music_tag::get_music_tag,
+    category::get_category_config, config, move_file_to, move_files, music_tag::TagReader, planner,
     read_dir, read_pattern, search,
 };
 
@@ -19,6 +17,13 @@ pub mod add;
 pub mod cat;
 pub mod check;
 pub mod down;
+pub mod dupes;
+pub mod index;
+pub mod rename;
+pub mod search;
+pub mod sync;
+pub mod tags;
+pub mod undo;
 
 /// Searches for a category, and returns the full category name
 fn find_category(category: &str) -> Result<PathBuf> {
@@ -52,135 +57,141 @@ fn find_category(category: &str) -> Result<PathBuf> {
 }
 
 /// Move a files to a category
-pub fn move_to_category(category: &str, files: &Vec<String>) -> Result<()> {
-    let (category_dir, category_config) = move_setup(category)?;
+pub fn move_to_category(
+    category_dir: &Path,
+    files: &Vec<String>,
+    conflict: Option<ConflictPolicy>,
+) -> Result<()> {
+    let (template, conflict, reader) = move_setup(category_dir, conflict)?;
 
     debug!("Start move to category");
 
     for file in files {
         let file = PathBuf::from(file);
 
-        let album_dir = get_album_dir(&file, &category_dir, &category_config)?;
+        let target = get_target_path(&file, category_dir, &template, &reader)?;
 
-        move_file(&file, &album_dir)?;
-        info!(
-            "Moved \"{}\" to \"{}\"",
-            file.display(),
-            album_dir.display()
-        );
+        move_file_to(&file, &target, conflict)?;
     }
 
     Ok(())
 }
 
 /// Move folder per album to a category
-pub fn move_album_to_category(category: &str, files: &Vec<String>, cover: bool) -> Result<()> {
-    let (category_dir, category_config) = move_setup(category)?;
+pub fn move_album_to_category(
+    category_dir: &Path,
+    files: &Vec<String>,
+    cover: bool,
+    conflict: Option<ConflictPolicy>,
+) -> Result<()> {
+    let (template, conflict, reader) = move_setup(category_dir, conflict)?;
     debug!("Start move album to category");
 
-    let folder_item: HashMap<&Path, PathBuf> = HashMap::new();
+    let mut folder_item: HashMap<PathBuf, PathBuf> = HashMap::new();
 
     for file in files {
         debug!("Start getting album for file: {}", file);
         let file = PathBuf::from(file).canonicalize()?;
         let parent = file.parent().unwrap();
-        let album_dir = match folder_item.get(parent) {
-            Some(a) => a.to_owned(),
-            None => {
-                let album_dir = get_album_dir(&file, &category_dir, &category_config)?;
-                if cover {
-                    debug!("Trying to find cover for album {}", parent.display());
-                    let covers = read_pattern(
-                        &format!(
-                            "{}/{}",
-                            Pattern::escape(parent.to_str().unwrap()),
-                            "cover.*"
-                        ),
-                        false,
-                    )?;
-                    debug!("Found cover: {:?}", covers);
-                    move_files(&covers, &album_dir)?;
-                    for cover in covers {
-                        info!(
-                            "Moved \"{}\" to \"{}\"",
-                            cover.display(),
-                            album_dir.display()
-                        );
-                    }
-                }
-                album_dir
-            }
-        };
+        let target = get_target_path(&file, category_dir, &template, &reader)?;
+        if cover && !folder_item.contains_key(parent) {
+            // the cover goes in the album directory, not in the directory of a disc
+            let album_dir = match target.parent() {
+                Some(dir) if is_disc_dir(dir) => dir.parent().unwrap_or(category_dir),
+                Some(dir) => dir,
+                None => category_dir,
+            };
+            debug!("Trying to find cover for album {}", parent.display());
+            let covers = read_pattern(
+                &format!(
+                    "{}/{}",
+                    Pattern::escape(parent.to_str().unwrap()),
+                    "cover.*"
+                ),
+                false,
+            )?;
+            debug!("Found cover: {:?}", covers);
+            move_files(&covers, album_dir, conflict)?;
+            folder_item.insert(parent.to_path_buf(), album_dir.to_path_buf());
+        }
 
-        move_file(&file, &album_dir)?;
-        info!(
-            "Moved \"{}\" to \"{}\"",
-            file.display(),
-            album_dir.display()
-        );
+        move_file_to(&file, &target, conflict)?;
     }
 
     Ok(())
 }
 
-fn get_album_dir(
+/// Get the path a music file should be moved to according to the template,
+/// and create the directories for it, untagged files go to the Untagged directory
+pub(crate) fn get_target_path(
     file: &Path,
     category_dir: &Path,
-    category_config: &CategoryConfig,
+    template: &Template,
+    reader: &TagReader,
 ) -> Result<PathBuf> {
     // get music tags
-    let music_tag = match get_music_tag(file) {
-        Ok(tag) => tag,
+    let path = match reader
+        .read(file)
+        .and_then(|tag| template.render(&tag, file))
+    {
+        Ok(path) => category_dir.join(path),
         Err(err) => {
-            warn!("could not find music tag because {err}");
+            warn!(
+                "could not find the path for \"{}\" because {err}",
+                file.display()
+            );
 
             // try the untagged directory
             let untagged_dir = category_dir.join("Untagged");
-            if untagged_dir.is_dir() {
-                return Ok(untagged_dir);
+            if !untagged_dir.is_dir() {
+                return Err(anyhow!("Could not find music tags and untagged directory"));
             }
-            return Err(anyhow!("Could not find music tags and untagged directory"));
+            let file_name = file
+                .file_name()
+                .ok_or(anyhow!("\"{}\" is not a valid file", file.display()))?;
+            return Ok(untagged_dir.join(file_name));
         }
     };
 
-    let album_dir;
-
-    // check if the category is one artist only
-    if category_config.artist_category.unwrap_or(false) {
-        album_dir = category_dir.join(change_forbidden_chars(&music_tag.album_title));
-        if !album_dir.is_dir() {
-            // if we can't create a directory, we won't try the rest
-            fs::create_dir(&album_dir)?;
-            info!("Created \"{}\" album directory", album_dir.display());
-        }
-    } else {
-        // create the artist and album directories if they do not exist
-        let artist_dir = category_dir.join(change_forbidden_chars(&music_tag.album_artist));
-        if !artist_dir.is_dir() {
-            fs::create_dir(&artist_dir)?;
-            info!("Created \"{}\" artist directory", artist_dir.display());
-        }
+    // create the artist and album directories if they do not exist
+    create_parent_dirs(&path)?;
+    Ok(path)
+}
 
-        album_dir = artist_dir.join(change_forbidden_chars(&music_tag.album_title));
-        if !album_dir.is_dir() {
-            fs::create_dir(&album_dir)?;
-            info!("Created \"{}\" album directory", album_dir.display());
-        }
+/// Create the directories of a path that do not exist yet
+pub(crate) fn create_parent_dirs(path: &Path) -> Result<()> {
+    let mut new_dirs: Vec<&Path> = path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| !planner::dir_exists(dir))
+        .collect();
+    new_dirs.reverse();
+    for dir in new_dirs {
+        // if we can't create a directory, we won't try the rest
+        planner::create_dir(dir)?;
     }
-    Ok(album_dir)
+    Ok(())
 }
 
-fn move_setup(category: &str) -> Result<(PathBuf, CategoryConfig)> {
-    let config = config::get_config()?;
-    // search for the directory, so short names are possible,
-    // otherwise try to use the default directory
-    let category_dir = match find_category(category) {
-        Ok(dir) => dir,
+/// The name of the category directory a file is in
+fn category_of(music_dir: &Path, file: &Path) -> String {
+    file.strip_prefix(music_dir)
+        .ok()
+        .and_then(|path| path.components().next())
+        .map(|dir| dir.as_os_str().to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Search for a category, so short names are possible,
+/// the default directory is used if it is not found
+pub fn find_category_or_default(category: &str) -> Result<PathBuf> {
+    match find_category(category) {
+        Ok(dir) => Ok(dir),
         Err(_) => {
             error!("category {category} not found");
 
             // try moving to the default directory
-            let default_dir = config.default_dir;
+            let default_dir = config::get_config()?.default_dir;
 
             if !Path::new(&default_dir).is_dir() {
                 warn!("The default_dir is not in {}", default_dir.display());
@@ -191,12 +202,26 @@ fn move_setup(category: &str) -> Result<(PathBuf, CategoryConfig)> {
                 "The files where moved to \"{}\" because the category was not found",
                 default_dir.to_str().unwrap()
             );
-            default_dir
+            Ok(default_dir)
         }
-    };
-    let category_config = get_category_config(&category_dir)?;
+    }
+}
+
+/// Find the path template of the category directory,
+/// and the conflict policy from the argument or else the config
+fn move_setup(
+    category_dir: &Path,
+    conflict: Option<ConflictPolicy>,
+) -> Result<(Template, ConflictPolicy, TagReader)> {
+    let config = config::get_config()?;
+    let conflict = conflict.or(config.conflict_policy).unwrap_or_default();
+    let category_config = get_category_config(category_dir)?;
+
+    let template = Template::for_category(&config, &category_config)?;
+
+    let reader = TagReader::from_config(&config);
 
-    Ok((category_dir, category_config))
+    Ok((template, conflict, reader))
 }
 
 pub fn change_forbidden_chars(input: &str) -> String {
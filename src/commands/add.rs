@@ -1,41 +1,77 @@
-use std::{env::current_dir, path::PathBuf};
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
 use log::error;
 
-use crate::normalize::{self, normalize_files};
+use crate::config::ConflictPolicy;
+use crate::normalize::{group_albums, normalize_albums, Album};
 
 pub fn add(
     files: &Vec<String>,
-    category: &str,
+    category_dir: &Path,
     quiet: &bool,
     force: &bool,
     album: &bool,
+    conflict: Option<ConflictPolicy>,
+    jobs: Option<usize>,
 ) -> Result<()> {
-    for file in files {
-        let file = PathBuf::from(file);
-        if !file.is_file() {
-            return Err(anyhow!("{} is not a file", file.display()));
+    // the normalizer runs in the directory of the album
+    let paths = files
+        .iter()
+        .map(std::path::absolute)
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    if let Some(file) = paths.iter().find(|file| !file.is_file()) {
+        return Err(anyhow!("{} is not a file", file.display()));
+    }
+
+    // the files of an album are normalized together, singles on their own
+    let albums = match album {
+        true => group_albums(&paths),
+        false => paths
+            .iter()
+            .map(|file| Album {
+                dir: file.parent().unwrap_or(file).to_path_buf(),
+                files: vec![file.clone()],
+            })
+            .collect(),
+    };
+    let results = normalize_albums(&albums, quiet, force, jobs)?;
+
+    if !album {
+        for (album, result) in albums.iter().zip(&results) {
+            if let Err(err) = result {
+                error!(
+                    "Could not normalize \"{}\" because of: {err}",
+                    album.files[0].display()
+                );
+            }
         }
-        if !album {
-            match normalize::normalize(&current_dir()?, &file, quiet, force) {
-                Ok(_) => {}
-                Err(err) => {
-                    error!("Could not normalize file because of: {err}")
-                }
-            };
+        return super::move_to_category(category_dir, files, conflict);
+    }
+
+    // albums that could not be normalized stay where they are
+    let mut failed = 0;
+    let mut normalized: Vec<String> = vec![];
+    for (album, result) in albums.iter().zip(results) {
+        match result {
+            Ok(_) => normalized.extend(
+                album
+                    .files
+                    .iter()
+                    .map(|file| file.to_string_lossy().to_string()),
+            ),
+            Err(err) => {
+                error!(
+                    "Could not normalize \"{}\", it is not added: {err}",
+                    album.dir.display()
+                );
+                failed += 1;
+            }
         }
     }
-    if *album {
-        let paths: Vec<_> = files.iter().map(PathBuf::from).collect();
-        normalize_files(
-            &current_dir()?,
-            &paths.iter().map(|path| path.as_path()).collect::<Vec<_>>(),
-            quiet,
-            force,
-        )?;
-        super::move_album_to_category(category, files, true)
-    } else {
-        super::move_to_category(category, files)
+    super::move_album_to_category(category_dir, &normalized, true, conflict)?;
+    match failed {
+        0 => Ok(()),
+        _ => Err(anyhow!("Could not normalize {failed} albums")),
     }
 }
@@ -1,8 +1,4 @@
-use std::{
-    ffi::OsString,
-    fs,
-    path::{Path, PathBuf},
-};
+use std::path::Path;
 
 use colored::Colorize;
 use log::{error, info, warn};
@@ -10,10 +6,7 @@ use log::{error, info, warn};
 use anyhow::Result;
 
 use crate::{
-    category,
-    config::get_config,
-    music_tag::{get_music_tag, MusicTag},
-    read_dir, read_dir_recursive,
+    category, config::get_config, index::get_category_index, music_tag::MusicTag, planner, read_dir,
 };
 
 /// Print details about categories
@@ -33,25 +26,12 @@ pub fn category(category: &Option<String>) -> Result<()> {
 
         let category_config = category::get_category_config(&category_path)?;
 
-        let extensions = config.file_extensions;
-
-        let mut music_files: Vec<PathBuf> = vec![];
-
-        for extension in extensions {
-            music_files.append(&mut read_dir_recursive(
-                &category_path,
-                Some(&OsString::from(extension)),
-                3,
-            )?);
-        }
+        let index = get_category_index(&config, &category_path)?;
 
-        let mut music_tags: Vec<MusicTag> = vec![];
-        for file in music_files {
-            music_tags.push(match get_music_tag(&file) {
-                Ok(tag) => tag,
-                Err(_) => continue,
-            });
-        }
+        let mut music_tags: Vec<MusicTag> = index
+            .entries_in(&category_path)
+            .filter_map(|entry| entry.tag.clone())
+            .collect();
 
         let big_tags: bool = if music_tags.len() > 15 {
             music_tags.sort_by(|a, b| a.album_title.cmp(&b.album_title));
@@ -83,9 +63,27 @@ pub fn category(category: &Option<String>) -> Result<()> {
 
         for music_tag in music_tags {
             println!("{}: {}", "Artist".bold().purple(), music_tag.artist_name);
-            println!("{}: {}", "Album".bold().blue(), music_tag.album_title);
+            match music_tag.year {
+                Some(year) => println!(
+                    "{}: {} ({year})",
+                    "Album".bold().blue(),
+                    music_tag.album_title
+                ),
+                None => println!("{}: {}", "Album".bold().blue(), music_tag.album_title),
+            }
             if !big_tags {
-                println!("{}: {}", "Song".bold().green(), music_tag.song_title)
+                let seconds = music_tag.properties.duration.as_secs();
+                println!(
+                    "{}: {}{} ({}:{:02})",
+                    "Song".bold().green(),
+                    music_tag
+                        .track
+                        .map(|track| format!("{track}. "))
+                        .unwrap_or_default(),
+                    music_tag.song_title,
+                    seconds / 60,
+                    seconds % 60
+                )
             }
             println!();
         }
@@ -139,21 +137,21 @@ pub fn category(category: &Option<String>) -> Result<()> {
     }
 }
 
-pub fn mk_category(category_name: &String, category_description: &String) -> Result<()> {
+pub fn mk_category(category_name: &str, category_description: &str) -> Result<()> {
     let config = get_config()?;
     let music_dir = config.music_dir;
 
     let category_dir = music_dir.join(category_name);
     // checks if the category directory already exists, makes it if it does not
     if !category_dir.is_dir() {
-        fs::create_dir(&category_dir)?
+        planner::create_dir(&category_dir)?
     } else {
         info!("Category directory already exist");
     }
 
     let untagged_dir = category_dir.join("Untagged");
     if !untagged_dir.is_dir() {
-        fs::create_dir(&untagged_dir)?
+        planner::create_dir(&untagged_dir)?
     }
 
     category::create_category_config(
@@ -1,15 +1,28 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
 
 use glob::Pattern;
-use log::{error, info, warn};
+use log::{error, info};
 
 use anyhow::Result;
 
-use crate::commands::change_forbidden_chars;
-use crate::music_tag::get_music_tag;
+use crate::commands::get_target_path;
+use crate::config::ConflictPolicy;
+use crate::index::{get_category_index, get_index, Index};
+use crate::music_tag::{MusicTag, TagReader};
+use crate::normalize::{normalize_albums, Album};
+use crate::template::{album_dir, is_disc_dir, is_multi_disc, Template};
 use crate::{
-    category::get_category_config, commands::find_category, config, read_dir, read_pattern,
+    category::get_category_config, commands::find_category, config, move_file_to, planner,
+    read_dir, read_pattern,
 };
+pub use report::ReportFormat;
+use report::{AlbumReport, CategoryReport, CheckReport, Issue, IssueKind};
+
+pub mod report;
 
 const MEDIA_EXTENSIONS: [&str; 82] = [
     "3gp", "3g2", "aa", "aac", "aax", "act", "aiff", "alac", "tak", "amr", "ape", "au", "awb",
@@ -21,18 +34,41 @@ const MEDIA_EXTENSIONS: [&str; 82] = [
     "asf", "amv", "mxf", "roq", "nsv",
 ]; // from https://en.wikipedia.org/wiki/Audio_file_format and https://en.wikipedia.org/wiki/Video_file_format
 
+/// What was changed by fixing
+#[derive(Default)]
+struct FixSummary {
+    moved: usize,
+    normalized: usize,
+    removed_dirs: usize,
+    failed: usize,
+}
+
 /// Is going to check all the music
 /// - if in right place
 /// - if full album
 /// - if all tags
-/// -
-
-pub fn check(opt_category: &Option<String>, check_tags: &bool) -> Result<()> {
-    //TODO: add auto rectify option
+///
+/// With fix it moves misplaced files, normalizes files without replaygain tags
+/// and removes empty directories.
+///
+/// Returns the number of issues found, with fix the number of issues that are left
+pub fn check(
+    opt_category: &Option<String>,
+    check_tags: &bool,
+    fix: &bool,
+    quiet: &bool,
+    format: ReportFormat,
+    jobs: Option<usize>,
+) -> Result<usize> {
+    // fixing needs the tags to know where files should be
+    let check_tags = *check_tags || *fix;
+    let mut summary = FixSummary::default();
+    let mut report = CheckReport::default();
+    let mut remaining = 0;
 
     // get config
     let config = config::get_config()?;
-    let music_dir = config.music_dir;
+    let music_dir = &config.music_dir;
 
     // get directories
     let mut category_dirs: Vec<PathBuf> = vec![];
@@ -40,11 +76,21 @@ pub fn check(opt_category: &Option<String>, check_tags: &bool) -> Result<()> {
         category_dirs.push(find_category(category)?)
     } else {
         // get all directories
-        let mut category_type_dirs = read_dir(&music_dir, None)?;
+        let mut category_type_dirs = read_dir(music_dir, None)?;
         category_type_dirs.retain(|x| x.is_dir());
         category_dirs = category_type_dirs;
     }
 
+    // only the tags need the index
+    let index = match check_tags {
+        true => Some(match opt_category {
+            Some(_) => get_category_index(&config, &category_dirs[0])?,
+            None => get_index(&config)?,
+        }),
+        false => None,
+    };
+    let reader = TagReader::from_config(&config);
+
     // the real checking
     for category_dir in category_dirs {
         // check config
@@ -70,84 +116,165 @@ pub fn check(opt_category: &Option<String>, check_tags: &bool) -> Result<()> {
             album_patterns.extend(files)
         };
 
-        // check for right place
-        if category_config.artist_category.unwrap_or(false) {
-            let mut album_dirs = read_dir(&category_dir, None)?;
-            album_dirs.retain(|x| x.is_dir());
-
-            for album_dir in album_dirs {
-                check_album(
-                    None,
-                    &album_dir,
-                    &config.file_extensions,
-                    &album_patterns,
-                    check_tags,
-                )?;
+        let mut category_report = CategoryReport {
+            name: category_config.name.clone(),
+            path: category_dir.clone(),
+            albums: vec![],
+        };
+        let template = match Template::for_category(&config, &category_config) {
+            Ok(template) => template,
+            Err(err) => {
+                error!(
+                    "Failed to read the path template of \"{}\" because of {err}",
+                    category_dir.display()
+                );
+                continue;
             }
-        } else {
-            let mut artist_dirs = read_dir(&category_dir, None)?;
-            artist_dirs.retain(|x| x.is_dir());
-
-            for artist_dir in artist_dirs {
-                let artist_name = artist_dir.file_name().unwrap().to_string_lossy();
-                let mut album_dirs = read_dir(&artist_dir, None)?;
-                album_dirs.retain(|x| x.is_dir());
-
-                for album_dir in album_dirs {
-                    check_album(
-                        Some(&*artist_name),
-                        &album_dir,
-                        &config.file_extensions,
-                        &album_patterns,
-                        check_tags,
-                    )?;
-                }
+        };
+        let category = Category {
+            dir: &category_dir,
+            template: &template,
+            file_extensions: &config.file_extensions,
+            album_patterns: &album_patterns,
+            conflict: config.conflict_policy.unwrap_or_default(),
+            reader: &reader,
+        };
+
+        category_report.albums = check_albums(&category, index.as_ref())?;
+        let mut left = category_report.issue_count();
+
+        if *fix {
+            fix_category(&category_report, &category, quiet, jobs, &mut summary)?;
+            // check again to know what could not be fixed
+            if !planner::is_dry_run() {
+                info!("Checking {} again", category_config.name);
+                // the fixes moved files, so the albums have to be read again
+                reader.clear();
+                let index = match check_tags {
+                    true => Some(get_category_index(&config, &category_dir)?),
+                    false => None,
+                };
+                left = check_albums(&category, index.as_ref())?
+                    .iter()
+                    .map(|album| album.issues.len())
+                    .sum();
             }
         }
+        remaining += left;
+        report.categories.push(category_report);
     }
-    Ok(())
+
+    if *fix {
+        info!(
+            "{} {} files, normalized {} albums and removed {} directories, {} failed",
+            match planner::is_dry_run() {
+                true => "Would have moved",
+                false => "Moved",
+            },
+            summary.moved,
+            summary.normalized,
+            summary.removed_dirs,
+            summary.failed
+        );
+        if !planner::is_dry_run() {
+            info!("{remaining} issues are left after fixing");
+        }
+    }
+    report.print(format)?;
+    Ok(remaining)
 }
 
-/// Returns a bool whether there were errors
-///
-/// true means there were errors the location of the files
+/// Check all album directories of a category,
+/// they are as deep as the directories in the template
+fn check_albums(category: &Category, index: Option<&Index>) -> Result<Vec<AlbumReport>> {
+    let mut album_dirs = vec![category.dir.to_path_buf()];
+    for _ in 0..category.template.dir_depth() {
+        let mut sub_dirs = vec![];
+        for dir in album_dirs {
+            sub_dirs.extend(read_dir(&dir, None)?.into_iter().filter(|x| x.is_dir()));
+        }
+        album_dirs = sub_dirs;
+    }
+    album_dirs
+        .iter()
+        .map(|album_dir| check_album(album_dir, category, index))
+        .collect()
+}
+
+/// The category that is being checked
+struct Category<'a> {
+    dir: &'a Path,
+    template: &'a Template,
+    file_extensions: &'a [String],
+    /// Files to check if they are in the album directory, as a glob pattern
+    album_patterns: &'a [&'a String],
+    /// What to do when a misplaced file is moved onto an existing file
+    conflict: ConflictPolicy,
+    /// Reads the tags of files that are not in the index
+    reader: &'a TagReader,
+}
+
+/// Check an album directory, and give the issues that were found
 fn check_album(
-    artist: Option<&str>,
     album_dir: &Path,
-    file_extensions: &[String],
-    album_patterns: &[&String],
-    check_tags: &bool,
-) -> Result<()> {
-    // get files
-    let mut files = read_dir(album_dir, None)?;
-    files.retain(|x| x.is_file());
+    category: &Category,
+    index: Option<&Index>,
+) -> Result<AlbumReport> {
+    let mut report = AlbumReport::new(album_dir);
 
-    let mut error = false;
+    // get files, also from the directories of the discs
+    let mut files = vec![];
+    for path in read_dir(album_dir, None)? {
+        if path.is_file() {
+            files.push(path);
+        } else if is_disc_dir(&path) {
+            let mut disc_files = read_dir(&path, None)?;
+            disc_files.retain(|x| x.is_file());
+            files.append(&mut disc_files);
+        }
+    }
 
     if files.is_empty() {
-        warn!("    No files found at \"{}\"", album_dir.display());
-        return Ok(());
+        report.add(Issue::new(
+            IssueKind::EmptyAlbum,
+            album_dir,
+            format!("No files found at \"{}\"", album_dir.display()),
+        ));
+        return Ok(report);
     }
     let album_name = album_dir.file_name().unwrap().to_str().unwrap();
 
-    if *check_tags && check_path_and_tags(&files, file_extensions, artist, album_name) {
-        error = true
+    if let Some(index) = index {
+        let tags = check_path_and_tags(&files, category, index, &mut report);
+        // the tracks of a mixed album can not be compared
+        if !check_mixed_album(album_dir, &tags, &mut report) {
+            check_discs(album_dir, &tags, &mut report);
+            check_tracks(album_dir, &tags, &mut report);
+        }
     }
     // check files
     for file in files {
         // get extension
-        let mut extension = file.extension().unwrap().to_string_lossy().to_string();
+        let mut extension = file
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
         // check extension
         extension.make_ascii_lowercase();
-        if !file_extensions.contains(&extension) && MEDIA_EXTENSIONS.contains(&&*extension) {
-            warn!(
-                "    found media file at \"{}\" but it does not have the right extension",
-                { file.display() }
-            );
-            error = true;
+        if !category.file_extensions.contains(&extension) && MEDIA_EXTENSIONS.contains(&&*extension)
+        {
+            report.add(Issue::new(
+                IssueKind::WrongExtension,
+                &file,
+                format!(
+                    "found media file at \"{}\" but it does not have the right extension",
+                    file.display()
+                ),
+            ));
         }
     }
-    for pattern in album_patterns {
+    for pattern in category.album_patterns {
         if read_pattern(
             &format!(
                 "{}/{}",
@@ -158,67 +285,586 @@ fn check_album(
         )?
         .is_empty()
         {
-            warn!(
-                "    Could not find files for pattern: \"{pattern}\" at \"{}\"",
-                album_dir.display()
-            );
-            error = true
+            report.add(Issue::new(
+                IssueKind::MissingAlbumFile,
+                album_dir,
+                format!(
+                    "Could not find files for pattern: \"{pattern}\" at \"{}\"",
+                    album_dir.display()
+                ),
+            ));
         }
     }
-    if !error {
+    if report.issues.is_empty() {
         info!("    {album_name} is ok");
     }
-    Ok(())
+    Ok(report)
 }
 
 /// Checks path based on tags, and check the tags themselves
-fn check_path_and_tags(
-    files: &[PathBuf],
-    file_extensions: &[String],
-    artist: Option<&str>,
-    album_name: &str,
-) -> bool {
-    let mut error = false;
+fn check_path_and_tags<'a>(
+    files: &'a [PathBuf],
+    category: &Category,
+    index: &Index,
+    report: &mut AlbumReport,
+) -> Vec<(&'a Path, MusicTag)> {
+    let mut album_tags = vec![];
     for file in files {
         // get extension
-        let extension = file.extension().unwrap().to_string_lossy().to_string();
+        let extension = file
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
         // check tags if music file
-        if file_extensions.contains(&extension) {
-            let tags = match get_music_tag(file) {
+        if category.file_extensions.contains(&extension) {
+            let tags = match indexed_music_tag(file, index, category.reader) {
                 Ok(tags) => tags,
                 Err(err) => {
-                    error!(
-                        "    Could not get music tags from \"{}\", because of {}",
-                        file.display(),
-                        err
-                    );
-                    error = true;
+                    report.add(Issue::new(
+                        IssueKind::UnreadableTags,
+                        file,
+                        format!(
+                            "Could not get music tags from \"{}\", because of {}",
+                            file.display(),
+                            err
+                        ),
+                    ));
                     continue;
                 }
             };
-            // check artist
-            if let Some(artist) = artist {
-                if artist != change_forbidden_chars(&tags.album_artist) {
-                    warn!("    {} is in the wrong artist folder", file.display());
-                    error = true;
-                }
-                // check album
-                else if album_name != change_forbidden_chars(&tags.album_title) {
-                    warn!("    {} is in the wrong album folder", file.display());
-                    error = true;
-                }
+            for (field, source) in &tags.inferred {
+                report.add(Issue::new(
+                    IssueKind::InferredTag,
+                    file,
+                    format!(
+                        "{} has no {field} tag, it was inferred from the {source}",
+                        file.display()
+                    ),
+                ));
             }
-            // check album
-            else if album_name != change_forbidden_chars(&tags.album_title) {
-                warn!("    {} is in the wrong album folder", file.display());
-                error = true;
+            match category.template.render(&tags, file) {
+                Ok(path) => check_path(file, &category.dir.join(path), report),
+                Err(err) => report.add(Issue::new(
+                    IssueKind::IncompleteTags,
+                    file,
+                    format!(
+                        "{} can not be put in the right place: {err}",
+                        file.display()
+                    ),
+                )),
             }
             // check for replaygain tags
             if !tags.replaygain {
-                warn!("    found file with no replaygain tags");
-                error = true;
+                report.add(Issue::new(
+                    IssueKind::NoReplaygain,
+                    file,
+                    format!("found file with no replaygain tags: {}", file.display()),
+                ));
+            }
+            album_tags.push((file.as_path(), tags));
+        }
+    }
+    album_tags
+}
+
+/// Check if an album has all the discs the disc totals in the tags say it has
+fn check_discs(album_dir: &Path, tags: &[(&Path, MusicTag)], report: &mut AlbumReport) {
+    let tags: Vec<&MusicTag> = tags.iter().map(|(_, tag)| tag).collect();
+    let totals: BTreeSet<u32> = tags.iter().filter_map(|tag| tag.disc_total).collect();
+    let Some(&total) = totals.last() else {
+        return;
+    };
+    // music without a disc number is on the first disc
+    let discs: BTreeSet<u32> = tags.iter().map(|tag| tag.disc.unwrap_or(1)).collect();
+    let highest = discs.last().copied().unwrap_or(1);
+    let dir = album_dir.display();
+    let message = if totals.len() > 1 {
+        let totals: Vec<String> = totals.iter().map(u32::to_string).collect();
+        Some(format!(
+            "\"{dir}\" has different disc totals: {}",
+            totals.join(", ")
+        ))
+    } else if highest > total {
+        Some(format!(
+            "\"{dir}\" has disc {highest}, but a disc total of {total}"
+        ))
+    } else {
+        None
+    };
+    if let Some(message) = message {
+        report.add(Issue::new(IssueKind::WrongDiscTotal, album_dir, message));
+    }
+    let missing: Vec<String> = (1..=total)
+        .filter(|disc| !discs.contains(disc))
+        .map(|disc| disc.to_string())
+        .collect();
+    if !missing.is_empty() {
+        report.add(Issue::new(
+            IssueKind::MissingDisc,
+            album_dir,
+            format!(
+                "\"{dir}\" has {} of {total} discs, missing disc {}",
+                total as usize - missing.len(),
+                missing.join(", ")
+            ),
+        ));
+    }
+}
+
+/// Check if every disc of an album has all its tracks, from the track totals in the tags,
+/// and if no track number is used twice
+fn check_tracks(album_dir: &Path, tags: &[(&Path, MusicTag)], report: &mut AlbumReport) {
+    let mut discs: BTreeMap<u32, Vec<(&Path, &MusicTag)>> = BTreeMap::new();
+    for (file, tag) in tags {
+        discs
+            .entry(tag.disc.unwrap_or(1))
+            .or_default()
+            .push((file, tag));
+    }
+    let multi_disc = discs.len() > 1 || tags.iter().any(|(_, tag)| is_multi_disc(tag));
+    let dir = album_dir.display();
+    for (disc, tags) in discs {
+        let on_disc = match multi_disc {
+            true => format!(" on disc {disc}"),
+            false => String::new(),
+        };
+
+        let mut tracks: BTreeMap<u32, Vec<&Path>> = BTreeMap::new();
+        for (file, tag) in &tags {
+            if let Some(track) = tag.track {
+                tracks.entry(track).or_default().push(file);
             }
         }
+        for (track, files) in tracks.iter().filter(|(_, files)| files.len() > 1) {
+            let names: Vec<String> = files
+                .iter()
+                .map(|file| {
+                    file.file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect();
+            report.add(Issue::new(
+                IssueKind::DuplicateTrack,
+                album_dir,
+                format!(
+                    "\"{dir}\" has track {track}{on_disc} more than once: {}",
+                    names.join(", ")
+                ),
+            ));
+        }
+
+        let Some(total) = tags.iter().filter_map(|(_, tag)| tag.track_total).max() else {
+            continue;
+        };
+        let missing: Vec<String> = (1..=total)
+            .filter(|track| !tracks.contains_key(track))
+            .map(|track| track.to_string())
+            .collect();
+        if !missing.is_empty() {
+            report.add(Issue::new(
+                IssueKind::MissingTrack,
+                album_dir,
+                format!(
+                    "\"{dir}\" has {} of {total} tracks{on_disc}, missing track {}",
+                    total as usize - missing.len(),
+                    missing.join(", ")
+                ),
+            ));
+        }
+    }
+}
+
+/// Check if the music in an album directory is all from the same album,
+/// gives true if it is not
+fn check_mixed_album(
+    album_dir: &Path,
+    tags: &[(&Path, MusicTag)],
+    report: &mut AlbumReport,
+) -> bool {
+    let albums: BTreeSet<&str> = tags
+        .iter()
+        .map(|(_, tag)| tag.album_title.as_str())
+        .collect();
+    if albums.len() < 2 {
+        return false;
+    }
+    let albums: Vec<String> = albums.iter().map(|album| format!("\"{album}\"")).collect();
+    report.add(Issue::new(
+        IssueKind::MixedAlbum,
+        album_dir,
+        format!(
+            "\"{}\" has music of different albums: {}",
+            album_dir.display(),
+            albums.join(", ")
+        ),
+    ));
+    true
+}
+
+/// Check if a file is where the template says it should be
+fn check_path(file: &Path, expected: &Path, report: &mut AlbumReport) {
+    if file == expected {
+        return;
+    }
+    let (kind, message) = if file.parent() == expected.parent() {
+        (IssueKind::WrongFileName, "has the wrong file name")
+    } else if album_dir(file) == album_dir(expected) {
+        (IssueKind::WrongAlbumDir, "is in the wrong disc folder")
+    } else if album_dir(file).and_then(Path::parent) != album_dir(expected).and_then(Path::parent) {
+        (IssueKind::WrongArtistDir, "is in the wrong artist folder")
+    } else {
+        (IssueKind::WrongAlbumDir, "is in the wrong album folder")
+    };
+    report.add(
+        Issue::new(kind, file, format!("{} {message}", file.display()))
+            .with_location(expected.to_path_buf()),
+    );
+}
+
+/// Get the music tag from the index, or from the file if it is not in the index
+fn indexed_music_tag(file: &Path, index: &Index, reader: &TagReader) -> Result<MusicTag> {
+    match index.get(file).and_then(|entry| entry.tag.clone()) {
+        Some(tags) => Ok(tags),
+        // not in the index or missing tags, read the file to get the error
+        None => reader.read(file),
+    }
+}
+
+/// Fix the problems found in a category
+fn fix_category(
+    report: &CategoryReport,
+    category: &Category,
+    quiet: &bool,
+    jobs: Option<usize>,
+    summary: &mut FixSummary,
+) -> Result<()> {
+    let category_dir = category.dir;
+    let misplaced: Vec<&Issue> = report
+        .issues_of(IssueKind::WrongArtistDir)
+        .chain(report.issues_of(IssueKind::WrongAlbumDir))
+        .chain(report.issues_of(IssueKind::WrongFileName))
+        .collect();
+
+    // files and directories that are (or would be) gone
+    let mut gone: HashSet<PathBuf> = HashSet::new();
+    // where the moved files are now
+    let mut moved: HashMap<PathBuf, PathBuf> = HashMap::new();
+
+    // move files to the path their tags say they should be at
+    for issue in &misplaced {
+        let file = &issue.path;
+        let target = match get_target_path(file, category_dir, category.template, category.reader) {
+            Ok(target) => target,
+            Err(err) => {
+                error!("Could not find path for \"{}\": {err}", file.display());
+                summary.failed += 1;
+                continue;
+            }
+        };
+        match move_file_to(file, &target, category.conflict) {
+            Ok(Some(target)) => {
+                summary.moved += 1;
+                gone.insert(file.clone());
+                moved.insert(file.clone(), target);
+            }
+            // skipped because of a conflict
+            Ok(None) => {}
+            Err(err) => {
+                error!("Could not move \"{}\": {err}", file.display());
+                summary.failed += 1;
+            }
+        }
+    }
+
+    // normalize the albums with files without replaygain tags, where they are after moving
+    let albums: BTreeSet<PathBuf> = report
+        .issues_of(IssueKind::NoReplaygain)
+        .map(|issue| moved.get(&issue.path).unwrap_or(&issue.path))
+        .filter_map(|file| file.parent().map(Path::to_path_buf))
+        .collect();
+    let mut normalize: Vec<Album> = vec![];
+    for album_dir in albums {
+        // in a dry run the album directory might not exist yet
+        let mut files = match album_dir.is_dir() {
+            true => read_dir(&album_dir, None)?,
+            false => vec![],
+        };
+        files.retain(|file| {
+            !gone.contains(file)
+                && file.extension().is_some_and(|ext| {
+                    category
+                        .file_extensions
+                        .contains(&ext.to_string_lossy().to_string())
+                })
+        });
+        files.extend(
+            moved
+                .values()
+                .filter(|file| file.parent() == Some(&album_dir))
+                .cloned(),
+        );
+        files.sort();
+        files.dedup();
+        normalize.push(Album {
+            dir: album_dir,
+            files,
+        });
+    }
+    for (album, result) in normalize
+        .iter()
+        .zip(normalize_albums(&normalize, quiet, &false, jobs)?)
+    {
+        match result {
+            Ok(true) => summary.normalized += 1,
+            Ok(false) => {}
+            Err(err) => {
+                error!("Could not normalize \"{}\": {err}", album.dir.display());
+                summary.failed += 1;
+            }
+        }
+    }
+
+    // remove directories that are empty, and their artist directory if that is empty too
+    let mut dirs: Vec<PathBuf> = misplaced
+        .iter()
+        .filter(|issue| gone.contains(&issue.path))
+        .filter_map(|issue| issue.actual.clone())
+        .chain(
+            report
+                .issues_of(IssueKind::EmptyAlbum)
+                .map(|issue| issue.path.clone()),
+        )
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        for dir in dir.ancestors().take_while(|dir| *dir != category_dir) {
+            if dir.file_name().is_some_and(|name| name == "Untagged")
+                || gone.contains(dir)
+                || moved.values().any(|file| file.starts_with(dir))
+                || !is_empty_dir(dir, &gone)?
+            {
+                break;
+            }
+            if let Err(err) = planner::remove_dir(dir) {
+                error!("{err}");
+                summary.failed += 1;
+                break;
+            }
+            summary.removed_dirs += 1;
+            gone.insert(dir.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Whether a directory is empty, not counting the files that are gone
+fn is_empty_dir(dir: &Path, gone: &HashSet<PathBuf>) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        if !gone.contains(&entry?.path()) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::music_tag;
+
+    /// A track of an album, on the first disc if there is no disc number
+    fn track(disc: Option<u32>, track: u32) -> MusicTag {
+        MusicTag {
+            track: Some(track),
+            disc,
+            ..music_tag(&format!("Track {track}"))
+        }
+    }
+
+    /// Run one of the album checks on music with these tags
+    fn check_album(
+        tags: &[MusicTag],
+        check: impl FnOnce(&Path, &[(&Path, MusicTag)], &mut AlbumReport),
+    ) -> AlbumReport {
+        let dir = Path::new("/music/Artist/Album");
+        let files: Vec<PathBuf> = (1..=tags.len())
+            .map(|n| dir.join(format!("{n:02}.wav")))
+            .collect();
+        let tags: Vec<(&Path, MusicTag)> = files
+            .iter()
+            .map(PathBuf::as_path)
+            .zip(tags.iter().cloned())
+            .collect();
+        let mut report = AlbumReport::new(dir);
+        check(dir, &tags, &mut report);
+        report
+    }
+
+    fn messages(report: &AlbumReport, kind: IssueKind) -> Vec<&str> {
+        report
+            .issues_of(kind)
+            .map(|issue| issue.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn missing_tracks() {
+        let tags = [1, 3].map(|number| MusicTag {
+            track_total: Some(4),
+            ..track(None, number)
+        });
+        let report = check_album(&tags, check_tracks);
+        assert_eq!(
+            messages(&report, IssueKind::MissingTrack),
+            ["\"/music/Artist/Album\" has 2 of 4 tracks, missing track 2, 4"]
+        );
+        assert_eq!(report.issues.len(), 1);
+
+        // without a track total nothing is missing
+        let report = check_album(&[track(None, 1), track(None, 3)], check_tracks);
+        assert!(report.issues.is_empty());
+    }
+
+    #[test]
+    fn tracks_per_disc() {
+        let tags = [
+            MusicTag {
+                track_total: Some(2),
+                ..track(Some(1), 1)
+            },
+            MusicTag {
+                track_total: Some(2),
+                ..track(Some(1), 2)
+            },
+            MusicTag {
+                track_total: Some(3),
+                ..track(Some(2), 1)
+            },
+            MusicTag {
+                track_total: Some(3),
+                ..track(Some(2), 2)
+            },
+        ];
+        let report = check_album(&tags, check_tracks);
+        assert_eq!(
+            messages(&report, IssueKind::MissingTrack),
+            ["\"/music/Artist/Album\" has 2 of 3 tracks on disc 2, missing track 3"]
+        );
+        assert_eq!(report.issues.len(), 1);
+    }
+
+    #[test]
+    fn duplicate_tracks() {
+        let tags = [track(None, 1), track(None, 2), track(None, 2)];
+        let report = check_album(&tags, check_tracks);
+        assert_eq!(
+            messages(&report, IssueKind::DuplicateTrack),
+            ["\"/music/Artist/Album\" has track 2 more than once: 02.wav, 03.wav"]
+        );
+        assert_eq!(report.issues.len(), 1);
+
+        // the same track number on different discs is fine
+        let report = check_album(&[track(Some(1), 1), track(Some(2), 1)], check_tracks);
+        assert!(report.issues.is_empty());
+    }
+
+    #[test]
+    fn missing_discs() {
+        let tags = [
+            MusicTag {
+                disc_total: Some(3),
+                ..track(Some(1), 1)
+            },
+            MusicTag {
+                disc_total: Some(3),
+                ..track(Some(3), 1)
+            },
+        ];
+        let report = check_album(&tags, check_discs);
+        assert_eq!(
+            messages(&report, IssueKind::MissingDisc),
+            ["\"/music/Artist/Album\" has 2 of 3 discs, missing disc 2"]
+        );
+        assert_eq!(report.issues.len(), 1);
+
+        // music without a disc number is on the first disc
+        let tags = [
+            MusicTag {
+                disc_total: Some(2),
+                ..track(None, 1)
+            },
+            MusicTag {
+                disc_total: Some(2),
+                ..track(Some(2), 1)
+            },
+        ];
+        assert!(check_album(&tags, check_discs).issues.is_empty());
+
+        // without a disc total nothing is missing
+        let report = check_album(&[track(Some(2), 1)], check_discs);
+        assert!(report.issues.is_empty());
+    }
+
+    #[test]
+    fn wrong_disc_totals() {
+        let tags = [
+            MusicTag {
+                disc_total: Some(2),
+                ..track(Some(1), 1)
+            },
+            MusicTag {
+                disc_total: Some(3),
+                ..track(Some(2), 1)
+            },
+        ];
+        let report = check_album(&tags, check_discs);
+        assert_eq!(
+            messages(&report, IssueKind::WrongDiscTotal),
+            ["\"/music/Artist/Album\" has different disc totals: 2, 3"]
+        );
+
+        let tags = [
+            MusicTag {
+                disc_total: Some(1),
+                ..track(Some(1), 1)
+            },
+            MusicTag {
+                disc_total: Some(1),
+                ..track(Some(2), 1)
+            },
+        ];
+        let report = check_album(&tags, check_discs);
+        assert_eq!(
+            messages(&report, IssueKind::WrongDiscTotal),
+            ["\"/music/Artist/Album\" has disc 2, but a disc total of 1"]
+        );
+        assert_eq!(report.issues.len(), 1);
+    }
+
+    #[test]
+    fn mixed_album() {
+        let other = MusicTag {
+            album_title: String::from("Other"),
+            ..track(None, 2)
+        };
+        let mut mixed = false;
+        let report = check_album(&[track(None, 1), other], |dir, tags, report| {
+            mixed = check_mixed_album(dir, tags, report)
+        });
+        assert!(mixed);
+        assert_eq!(
+            messages(&report, IssueKind::MixedAlbum),
+            ["\"/music/Artist/Album\" has music of different albums: \"Album\", \"Other\""]
+        );
+
+        let report = check_album(&[track(None, 1), track(None, 2)], |dir, tags, report| {
+            mixed = check_mixed_album(dir, tags, report)
+        });
+        assert!(!mixed);
+        assert!(report.issues.is_empty());
     }
-    error
 }
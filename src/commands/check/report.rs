@@ -0,0 +1,382 @@
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use log::{error, warn};
+use serde::Serialize;
+
+use anyhow::{Context, Result};
+
+/// How the check report is printed
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    /// Only the log messages and a summary
+    Text,
+    Json,
+    /// JUnit XML, every category is a test suite and every album a test case
+    Junit,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// The kind of problem check found
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// The tags of a music file could not be read
+    UnreadableTags,
+    /// A music file is in a different artist directory than its album artist tag
+    WrongArtistDir,
+    /// A music file is in a different album directory than its album tag
+    WrongAlbumDir,
+    /// A music file has a different name than the path template gives
+    WrongFileName,
+    /// A music file is missing tags that the path template needs
+    IncompleteTags,
+    /// A tag was missing and its value was inferred from other tags
+    InferredTag,
+    /// A music file has no replaygain tags
+    NoReplaygain,
+    /// A media file that does not have one of the allowed file extensions
+    WrongExtension,
+    /// No files were found for one of the album_files patterns
+    MissingAlbumFile,
+    /// An album directory without any files
+    EmptyAlbum,
+    /// An album has fewer discs than the disc total in its tags
+    MissingDisc,
+    /// The disc totals of an album differ or are lower than a disc number
+    WrongDiscTotal,
+    /// A disc of an album has fewer tracks than the track total in its tags
+    MissingTrack,
+    /// A track number is used more than once on a disc of an album
+    DuplicateTrack,
+    /// An album directory with music of different albums
+    MixedAlbum,
+}
+
+impl IssueKind {
+    pub fn severity(&self) -> Severity {
+        match self {
+            IssueKind::UnreadableTags => Severity::Error,
+            _ => Severity::Warning,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IssueKind::UnreadableTags => "unreadable_tags",
+            IssueKind::WrongArtistDir => "wrong_artist_dir",
+            IssueKind::WrongAlbumDir => "wrong_album_dir",
+            IssueKind::WrongFileName => "wrong_file_name",
+            IssueKind::IncompleteTags => "incomplete_tags",
+            IssueKind::InferredTag => "inferred_tag",
+            IssueKind::NoReplaygain => "no_replaygain",
+            IssueKind::WrongExtension => "wrong_extension",
+            IssueKind::MissingAlbumFile => "missing_album_file",
+            IssueKind::EmptyAlbum => "empty_album",
+            IssueKind::MissingDisc => "missing_disc",
+            IssueKind::WrongDiscTotal => "wrong_disc_total",
+            IssueKind::MissingTrack => "missing_track",
+            IssueKind::DuplicateTrack => "duplicate_track",
+            IssueKind::MixedAlbum => "mixed_album",
+        }
+    }
+}
+
+/// A problem found by check
+#[derive(Serialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub severity: Severity,
+    /// The file or directory with the problem
+    pub path: PathBuf,
+    /// The directory the file is in now, for misplaced files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual: Option<PathBuf>,
+    /// The path the file should be at, for misplaced files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<PathBuf>,
+    pub message: String,
+}
+
+impl Issue {
+    pub fn new(kind: IssueKind, path: &Path, message: String) -> Issue {
+        Issue {
+            kind,
+            severity: kind.severity(),
+            path: path.to_path_buf(),
+            actual: None,
+            expected: None,
+            message,
+        }
+    }
+
+    /// Add the actual directory and expected path of a misplaced file
+    pub fn with_location(mut self, expected: PathBuf) -> Issue {
+        self.actual = self.path.parent().map(Path::to_path_buf);
+        self.expected = Some(expected);
+        self
+    }
+}
+
+#[derive(Serialize)]
+pub struct AlbumReport {
+    pub path: PathBuf,
+    pub issues: Vec<Issue>,
+}
+
+impl AlbumReport {
+    pub fn new(path: &Path) -> AlbumReport {
+        AlbumReport {
+            path: path.to_path_buf(),
+            issues: vec![],
+        }
+    }
+
+    /// Log the issue and add it to the report
+    pub fn add(&mut self, issue: Issue) {
+        match issue.severity {
+            Severity::Warning => warn!("    {}", issue.message),
+            Severity::Error => error!("    {}", issue.message),
+        }
+        self.issues.push(issue);
+    }
+
+    /// The issues of a certain kind
+    pub fn issues_of(&self, kind: IssueKind) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().filter(move |issue| issue.kind == kind)
+    }
+}
+
+#[derive(Serialize)]
+pub struct CategoryReport {
+    pub name: String,
+    pub path: PathBuf,
+    pub albums: Vec<AlbumReport>,
+}
+
+impl CategoryReport {
+    /// The issues of a certain kind in all albums
+    pub fn issues_of(&self, kind: IssueKind) -> impl Iterator<Item = &Issue> {
+        self.albums
+            .iter()
+            .flat_map(move |album| album.issues_of(kind))
+    }
+
+    pub fn issue_count(&self) -> usize {
+        self.albums.iter().map(|album| album.issues.len()).sum()
+    }
+}
+
+/// Everything check found
+#[derive(Serialize, Default)]
+pub struct CheckReport {
+    pub categories: Vec<CategoryReport>,
+}
+
+impl CheckReport {
+    pub fn issue_count(&self) -> usize {
+        self.categories
+            .iter()
+            .map(CategoryReport::issue_count)
+            .sum()
+    }
+
+    fn album_count(&self) -> usize {
+        self.categories
+            .iter()
+            .map(|category| category.albums.len())
+            .sum()
+    }
+
+    /// Print the report in the given format
+    pub fn print(&self, format: ReportFormat) -> Result<()> {
+        match format {
+            ReportFormat::Text => {
+                let issues = self.issue_count();
+                let summary = format!("Found {} issues in {} albums", issues, self.album_count());
+                if issues == 0 {
+                    println!("{}", summary.green().bold());
+                } else {
+                    println!("{}", summary.red().bold());
+                }
+            }
+            ReportFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(self).context("Could not serialize check report")?
+            ),
+            ReportFormat::Junit => print!("{}", self.to_junit()),
+        }
+        Ok(())
+    }
+
+    fn to_junit(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!(
+            "<testsuites name=\"music_manager check\" tests=\"{}\" failures=\"{}\">\n",
+            self.album_count(),
+            self.issue_count()
+        ));
+        for category in &self.categories {
+            xml.push_str(&format!(
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">\n",
+                escape_xml(&category.name),
+                category.albums.len(),
+                category.issue_count()
+            ));
+            for album in &category.albums {
+                let name = album
+                    .path
+                    .strip_prefix(&category.path)
+                    .unwrap_or(&album.path);
+                xml.push_str(&format!(
+                    "    <testcase classname=\"{}\" name=\"{}\"",
+                    escape_xml(&category.name),
+                    escape_xml(&name.to_string_lossy())
+                ));
+                if album.issues.is_empty() {
+                    xml.push_str("/>\n");
+                    continue;
+                }
+                xml.push_str(">\n");
+                for issue in &album.issues {
+                    let mut details = format!("path: {}", issue.path.display());
+                    if let Some(expected) = &issue.expected {
+                        details.push_str(&format!("\nexpected: {}", expected.display()));
+                    }
+                    xml.push_str(&format!(
+                        "      <failure type=\"{}\" message=\"{}\">{}</failure>\n",
+                        issue.kind.name(),
+                        escape_xml(&issue.message),
+                        escape_xml(&details)
+                    ));
+                }
+                xml.push_str("    </testcase>\n");
+            }
+            xml.push_str("  </testsuite>\n");
+        }
+        xml.push_str("</testsuites>\n");
+        xml
+    }
+}
+
+fn escape_xml(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for char in input.chars() {
+        match char {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&apos;"),
+            _ => output.push(char),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A category with an album that is ok,
+    /// and an album with a name that has to be escaped and a misplaced file
+    fn report() -> CheckReport {
+        let category = Path::new("/music/Rock & Roll");
+        let good = AlbumReport::new(&category.join("Artist/Good"));
+        let mut bad = AlbumReport::new(&category.join("Artist/<Bad> \"Album\""));
+        bad.issues.push(
+            Issue::new(
+                IssueKind::WrongAlbumDir,
+                &category.join("Artist/<Bad> \"Album\"/song.opus"),
+                String::from("song.opus is in the wrong album folder"),
+            )
+            .with_location(category.join("Artist/It's/song.opus")),
+        );
+        bad.issues.push(Issue::new(
+            IssueKind::UnreadableTags,
+            &category.join("Artist/<Bad> \"Album\"/broken.opus"),
+            String::from("Could not get music tags"),
+        ));
+        CheckReport {
+            categories: vec![CategoryReport {
+                name: String::from("Rock & Roll"),
+                path: category.to_path_buf(),
+                albums: vec![good, bad],
+            }],
+        }
+    }
+
+    #[test]
+    fn junit_report() {
+        assert_eq!(
+            report().to_junit(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="music_manager check" tests="2" failures="2">
+  <testsuite name="Rock &amp; Roll" tests="2" failures="2">
+    <testcase classname="Rock &amp; Roll" name="Artist/Good"/>
+    <testcase classname="Rock &amp; Roll" name="Artist/&lt;Bad&gt; &quot;Album&quot;">
+      <failure type="wrong_album_dir" message="song.opus is in the wrong album folder">path: /music/Rock &amp; Roll/Artist/&lt;Bad&gt; &quot;Album&quot;/song.opus
+expected: /music/Rock &amp; Roll/Artist/It&apos;s/song.opus</failure>
+      <failure type="unreadable_tags" message="Could not get music tags">path: /music/Rock &amp; Roll/Artist/&lt;Bad&gt; &quot;Album&quot;/broken.opus</failure>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+
+    #[test]
+    fn json_report() {
+        assert_eq!(
+            serde_json::to_string_pretty(&report()).unwrap(),
+            r#"{
+  "categories": [
+    {
+      "name": "Rock & Roll",
+      "path": "/music/Rock & Roll",
+      "albums": [
+        {
+          "path": "/music/Rock & Roll/Artist/Good",
+          "issues": []
+        },
+        {
+          "path": "/music/Rock & Roll/Artist/<Bad> \"Album\"",
+          "issues": [
+            {
+              "kind": "wrong_album_dir",
+              "severity": "warning",
+              "path": "/music/Rock & Roll/Artist/<Bad> \"Album\"/song.opus",
+              "actual": "/music/Rock & Roll/Artist/<Bad> \"Album\"",
+              "expected": "/music/Rock & Roll/Artist/It's/song.opus",
+              "message": "song.opus is in the wrong album folder"
+            },
+            {
+              "kind": "unreadable_tags",
+              "severity": "error",
+              "path": "/music/Rock & Roll/Artist/<Bad> \"Album\"/broken.opus",
+              "message": "Could not get music tags"
+            }
+          ]
+        }
+      ]
+    }
+  ]
+}"#
+        );
+    }
+
+    #[test]
+    fn escape_xml_characters() {
+        assert_eq!(
+            escape_xml("<a href=\"x\">Tom & Jerry's</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+        );
+        assert_eq!(escape_xml("Nothing to escape"), "Nothing to escape");
+    }
+}
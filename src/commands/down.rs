@@ -1,66 +1,383 @@
-use std::env::current_dir;
-use std::process::Command;
+use std::{
+    env::current_dir,
+    path::{Path, PathBuf},
+};
 
-use log::error;
-
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
+use log::{debug, error, info};
 
+use crate::backend::{self, Chapter, Download, DownloadOptions};
+use crate::config::{get_config, Config, InfoField};
+use crate::field::Field;
+use crate::music_tag::TagReader;
+use crate::planner::{self, Operation};
 use crate::tag::tag;
 
+use super::add::add;
 use super::find_category;
+use super::tags::{edit_file, Change};
+
+/// The tags that are filled from a download, when the config does not set them
+const DEFAULT_TAGS: [(Field, &[InfoField]); 6] = [
+    (
+        Field::Title,
+        &[InfoField::Chapter, InfoField::Track, InfoField::Title],
+    ),
+    (Field::Artist, &[InfoField::Artist, InfoField::Uploader]),
+    (Field::Album, &[InfoField::Album, InfoField::Video]),
+    (
+        Field::Year,
+        &[InfoField::ReleaseYear, InfoField::UploadYear],
+    ),
+    (
+        Field::Track,
+        &[InfoField::ChapterNumber, InfoField::TrackNumber],
+    ),
+    (Field::TrackTotal, &[InfoField::ChapterTotal]),
+];
+
+/// The videos that were downloaded to a category, next to its config
+const ARCHIVE_FILE: &str = "download-archive.txt";
+
+/// What a download did
+#[derive(Default, Clone, Copy, Debug)]
+pub struct DownloadSummary {
+    /// The videos that were downloaded
+    pub new: usize,
+    /// The videos that were downloaded before, too old or not available
+    pub skipped: usize,
+    /// The files of the new videos, with the files of their chapters
+    pub files: usize,
+}
 
 /// The download sub command
-/// this will try to download with yt-dlp and normalize with rsgain
-pub fn download(web_address: &str, category: &str, quiet: &bool) -> Result<()> {
-    // get directory
-    let category_dir = find_category(category)?.join("Untagged");
-
-    let category_dir_content = super::read_dir(&category_dir, None)?;
-
-    // download from yt with yt-dlp
-    //TODO: use --print for yt-dlp and use that
-    let downloader = Command::new("yt-dlp")
-        .args([
-            "--extract-audio",
-            "-f",
-            "bestaudio",
-            "--audio-format",
-            "opus",
-            "--split-chapters",
-            match quiet {
-                true => "-q",
-                false => "--no-quiet",
-            },
-        ])
-        .arg(web_address)
-        .current_dir(&category_dir)
-        .status()
-        .context("Could not use yt-dlp command")?;
-
-    if !downloader.success() {
-        error!("yt-dlp {}", downloader);
-        return Err(anyhow!("Failed to download with yt-dlp"));
+/// this will try to download with the downloader from the config, yt-dlp by default.
+/// Videos in the archive of the category are skipped, so a playlist or channel can be
+/// downloaded again to get only the new videos
+pub fn download(
+    web_address: &str,
+    category: &str,
+    since: &Option<String>,
+    quiet: &bool,
+) -> Result<DownloadSummary> {
+    let options = DownloadOptions {
+        since: since.clone(),
+        ..Default::default()
     };
+    download_with(
+        web_address,
+        &find_category(category)?,
+        options,
+        &true,
+        quiet,
+    )
+}
 
-    // Creates a vector with only the newly created opus files
-    let mut opus_files: Vec<String> = Vec::new();
-    let category_dir_content_after = super::read_dir(&category_dir, None)?;
-    if !category_dir_content.is_empty() {
-        for content in category_dir_content_after.iter() {
-            if !category_dir_content.contains(content)
-                && content.extension().unwrap_or_default() == "opus"
-            {
-                opus_files.push(content.to_string_lossy().to_string());
+/// Download to a category with the options, always with the archive of the category.
+/// With auto tag the files without tags are tagged with the tagger,
+/// otherwise they are left in the Untagged directory
+pub fn download_with(
+    web_address: &str,
+    category_dir: &Path,
+    mut options: DownloadOptions,
+    auto_tag: &bool,
+    quiet: &bool,
+) -> Result<DownloadSummary> {
+    let config = get_config()?;
+    let tag_fields = tag_fields(&config)?;
+    options.archive = Some(category_dir.join(ARCHIVE_FILE));
+    options.since = options.since.as_deref().map(since_date).transpose()?;
+    // get directory
+    let untagged_dir = category_dir.join("Untagged");
+
+    if planner::plan(Operation::Download {
+        url: web_address.to_string(),
+        dir: untagged_dir.clone(),
+    }) {
+        return Ok(DownloadSummary::default());
+    }
+    if !untagged_dir.is_dir() {
+        planner::create_dir(&untagged_dir)?;
+    }
+
+    // only the files the downloader gave are tagged, not others in the directory
+    let downloaded =
+        backend::downloader(&config).download(web_address, &untagged_dir, &options, quiet)?;
+    let downloads = &downloaded.downloads;
+    let mut files: Vec<String> = vec![];
+    for download in downloads {
+        debug!(
+            "Downloaded \"{}\" from {}",
+            download.title.as_deref().unwrap_or(&download.id),
+            download.webpage_url.as_deref().unwrap_or(web_address)
+        );
+        for (file, chapter) in download_files(download, &untagged_dir) {
+            // the tags are a start, files with a title, artist and album are not tagged again
+            let changes: Vec<Change> = tag_fields
+                .iter()
+                .filter_map(|(field, info)| {
+                    let value = info
+                        .iter()
+                        .find_map(|info| info_value(download, chapter, *info))?;
+                    Some(Change::Set(*field, value))
+                })
+                .collect();
+            if !changes.is_empty() {
+                if let Err(err) = edit_file(&file, &changes) {
+                    error!("{err}");
+                }
             }
+            files.push(file.to_string_lossy().to_string());
         }
-    } else {
-        for content in category_dir_content_after.iter() {
-            if content.extension().unwrap_or_default() == "opus" {
-                opus_files.push(content.to_string_lossy().to_string())
-            }
+    }
+    // a single video is not found when it is skipped
+    let skipped = match downloaded.found.is_empty() {
+        true => usize::from(downloads.is_empty()),
+        false => downloaded
+            .found
+            .iter()
+            .filter(|id| !downloads.iter().any(|download| download.id == **id))
+            .count(),
+    };
+    let summary = DownloadSummary {
+        new: downloads.len(),
+        skipped,
+        files: files.len(),
+    };
+    info!(
+        "Downloaded {} new videos with {} files, skipped {} videos",
+        summary.new, summary.files, summary.skipped
+    );
+
+    // files with tags from the download go straight to the library
+    let reader = TagReader::from_config(&config);
+    let (tagged, untagged): (Vec<String>, Vec<String>) = files
+        .into_iter()
+        .partition(|file| reader.read(file.as_ref()).is_ok());
+    if !tagged.is_empty() {
+        add(&tagged, category_dir, quiet, &false, &true, None, None)?;
+    }
+    if untagged.is_empty() {
+        return Ok(summary);
+    }
+    match auto_tag {
+        true => tag(current_dir()?, &untagged, category_dir, quiet, &false, None)?,
+        false => info!(
+            "{} files without tags are left in \"{}\"",
+            untagged.len(),
+            untagged_dir.display()
+        ),
+    }
+    Ok(summary)
+}
+
+/// A date for yt-dlp, like 2024-01-31, 20240131 or relative like today-2weeks
+fn since_date(since: &str) -> Result<String> {
+    let since = since.trim();
+    let date = since.replace('-', "");
+    if date.len() == 8 && date.chars().all(|char| char.is_ascii_digit()) {
+        return Ok(date);
+    }
+    let relative = since.strip_prefix("today").or(since.strip_prefix("now"));
+    let valid = relative.is_some_and(|offset| {
+        let Some(offset) = offset.strip_prefix(['-', '+']) else {
+            return offset.is_empty();
+        };
+        let unit = offset.trim_start_matches(|char: char| char.is_ascii_digit());
+        unit.len() < offset.len()
+            && ["day", "week", "month", "year"].contains(&unit.trim_end_matches('s'))
+    });
+    match valid {
+        true => Ok(since.to_string()),
+        false => Err(anyhow!(
+            "\"{since}\" is not a date, use a date like 2024-01-31 or today-2weeks"
+        )),
+    }
+}
+
+/// The tag fields with the information they are filled with,
+/// the defaults with the fields of the config
+fn tag_fields(config: &Config) -> Result<Vec<(Field, Vec<InfoField>)>> {
+    let mut fields: Vec<(Field, Vec<InfoField>)> = DEFAULT_TAGS
+        .iter()
+        .map(|(field, info)| (*field, info.to_vec()))
+        .collect();
+    for (name, info) in config.download_tags.iter().flatten() {
+        let field = Field::parse_tag(name)?;
+        fields.retain(|(other, _)| *other != field);
+        fields.push((field, info.clone()));
+    }
+    Ok(fields)
+}
+
+/// The chapter a file is of
+#[derive(Clone, Copy)]
+struct ChapterFile<'a> {
+    chapter: &'a Chapter,
+    number: usize,
+    /// The number of chapters with a file
+    total: usize,
+}
+
+/// The files of a download, with the chapter for the files of chapters
+fn download_files<'a>(
+    download: &'a Download,
+    dir: &Path,
+) -> Vec<(PathBuf, Option<ChapterFile<'a>>)> {
+    let chapters: Vec<&Chapter> = download
+        .chapters
+        .iter()
+        .flatten()
+        .filter(|chapter| chapter.filepath.is_some())
+        .collect();
+    let mut files = vec![(dir.join(&download.filepath), None)];
+    for (i, chapter) in chapters.iter().enumerate() {
+        if let Some(file) = &chapter.filepath {
+            let chapter = ChapterFile {
+                chapter,
+                number: i + 1,
+                total: chapters.len(),
+            };
+            files.push((dir.join(file), Some(chapter)));
         }
     }
+    files
+}
+
+/// The information of a download or the chapter of a file
+fn info_value(
+    download: &Download,
+    chapter: Option<ChapterFile>,
+    info: InfoField,
+) -> Option<String> {
+    match info {
+        InfoField::Title => download.title.clone(),
+        InfoField::Chapter => chapter.and_then(|file| file.chapter.title.clone()),
+        InfoField::ChapterNumber => chapter.map(|file| file.number.to_string()),
+        InfoField::ChapterTotal => chapter.map(|file| file.total.to_string()),
+        InfoField::Video => chapter.and(download.title.clone()),
+        InfoField::Track => download.track.clone(),
+        InfoField::TrackNumber => download.track_number.map(|number| number.to_string()),
+        InfoField::Artist => download.artist.clone(),
+        InfoField::Album => download.album.clone(),
+        InfoField::ReleaseYear => download.release_year.map(|year| year.to_string()),
+        InfoField::UploadYear => download
+            .upload_date
+            .as_ref()
+            .and_then(|date| date.get(..4))
+            .map(str::to_string),
+        InfoField::Uploader => download.uploader.clone(),
+        InfoField::Channel => download.channel.clone(),
+    }
+    .filter(|value| !value.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(download_tags: &str) -> Config {
+        toml::from_str(&format!(
+            "music_dir = \"/music\"\ndefault_dir = \"/music\"\nfile_extensions = [\"opus\"]\n{download_tags}"
+        ))
+        .unwrap()
+    }
+
+    fn download(chapters: &str) -> Download {
+        serde_json::from_str(&format!(
+            r#"{{"id": "v1", "title": "Live at the park", "uploader": "Band", "channel": "Band TV",
+            "upload_date": "20240131", "artist": null, "track": null, "album": null,
+            "filepath": "Live at the park [v1].opus", "chapters": {chapters}}}"#
+        ))
+        .unwrap()
+    }
 
-    // Tag all files
-    tag(current_dir()?, &opus_files, category, quiet, &false)
+    #[test]
+    fn default_tag_fields() {
+        let fields = tag_fields(&config("")).unwrap();
+        assert_eq!(fields.len(), DEFAULT_TAGS.len());
+        assert!(fields.contains(&(Field::Artist, vec![InfoField::Artist, InfoField::Uploader])));
+    }
+
+    #[test]
+    fn config_overrides_tag_fields() {
+        let fields = tag_fields(&config(
+            "[download_tags]\nartist = [\"channel\"]\ngenre = [\"uploader\"]\n",
+        ))
+        .unwrap();
+        assert!(fields.contains(&(Field::Artist, vec![InfoField::Channel])));
+        assert!(fields.contains(&(Field::Genre, vec![InfoField::Uploader])));
+        assert_eq!(
+            fields
+                .iter()
+                .filter(|(field, _)| *field == Field::Artist)
+                .count(),
+            1
+        );
+        assert!(tag_fields(&config("[download_tags]\next = [\"title\"]\n")).is_err());
+    }
+
+    #[test]
+    fn chapter_files_are_numbered() {
+        let download = download(
+            r#"[{"title": "Intro", "start_time": 0, "end_time": 10, "filepath": null},
+            {"title": "First", "start_time": 10, "end_time": 200, "filepath": "001 First.opus"},
+            {"title": "Second", "start_time": 200, "end_time": 400, "filepath": "002 Second.opus"}]"#,
+        );
+        let files = download_files(&download, Path::new("/untagged"));
+        assert_eq!(files.len(), 3);
+        assert_eq!(
+            files[0].0,
+            Path::new("/untagged/Live at the park [v1].opus")
+        );
+        assert!(files[0].1.is_none());
+
+        // the chapter without a file is not counted
+        let (file, chapter) = &files[2];
+        assert_eq!(file, Path::new("/untagged/002 Second.opus"));
+        let chapter = chapter.unwrap();
+        assert_eq!((chapter.number, chapter.total), (2, 2));
+        assert_eq!(
+            info_value(&download, Some(chapter), InfoField::Chapter).as_deref(),
+            Some("Second")
+        );
+        assert_eq!(
+            info_value(&download, Some(chapter), InfoField::Video).as_deref(),
+            Some("Live at the park")
+        );
+    }
+
+    #[test]
+    fn info_values() {
+        let download = download("null");
+        let value = |info| info_value(&download, None, info);
+        assert_eq!(value(InfoField::Title).as_deref(), Some("Live at the park"));
+        assert_eq!(value(InfoField::UploadYear).as_deref(), Some("2024"));
+        assert_eq!(value(InfoField::Artist), None);
+        // only the files of chapters have chapter information
+        assert_eq!(value(InfoField::Chapter), None);
+        assert_eq!(value(InfoField::Video), None);
+    }
+
+    #[test]
+    fn since_dates() {
+        assert_eq!(since_date("2024-01-31").unwrap(), "20240131");
+        assert_eq!(since_date("20240131").unwrap(), "20240131");
+        assert_eq!(since_date("today-2weeks").unwrap(), "today-2weeks");
+        assert_eq!(since_date(" now-1day ").unwrap(), "now-1day");
+        assert_eq!(since_date("today").unwrap(), "today");
+    }
+
+    #[test]
+    fn invalid_since_dates() {
+        for since in [
+            "2024-1-31",
+            "yesterday",
+            "today-weeks",
+            "today-2fortnights",
+            "today2weeks",
+        ] {
+            assert!(since_date(since).is_err(), "{since} is not a date");
+        }
+    }
 }
@@ -0,0 +1,403 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    time::Duration,
+};
+
+use colored::Colorize;
+use log::{info, warn};
+use serde::Serialize;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    config::{get_config, ConflictPolicy},
+    index::{get_index, IndexEntry},
+    move_file_to,
+    music_tag::{audio_hash, is_lossless, MusicTag},
+};
+
+use super::{category_of, create_parent_dirs, find_category};
+
+/// How duplicates are found
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DupeMatch {
+    /// The same artist and title, ignoring case, spaces and punctuation,
+    /// and about the same duration. The album is not compared,
+    /// so a song on a single and on the album is found too
+    Tags,
+    /// The same MusicBrainz recording id
+    Musicbrainz,
+    /// The same audio without the tags, only files with the same duration are compared
+    Audio,
+}
+
+/// What decides which copy of a song is kept
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeepRule {
+    /// The preferred format, or else lossless before lossy
+    Format,
+    /// The highest bitrate
+    Bitrate,
+    /// The preferred category
+    Category,
+}
+
+const DEFAULT_MATCHES: [DupeMatch; 2] = [DupeMatch::Tags, DupeMatch::Musicbrainz];
+const DEFAULT_KEEP_RULES: [KeepRule; 3] = [KeepRule::Format, KeepRule::Bitrate, KeepRule::Category];
+/// How much the durations of music with the same tags can differ,
+/// different encodings of the same song are not exactly as long
+const DURATION_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// A copy of a song that is in the library more than once
+#[derive(Serialize)]
+struct DupeFile<'a> {
+    category: String,
+    path: &'a Path,
+    title: &'a str,
+    artist: &'a str,
+    codec: &'a str,
+    /// In kbps
+    bitrate: Option<u32>,
+    /// If this is the copy that is kept
+    keep: bool,
+}
+
+/// Find music that is in the library more than once, also across categories,
+/// and keep the best copy of every song
+pub fn dupes(
+    matches: &[DupeMatch],
+    keep_rules: &[KeepRule],
+    formats: &[String],
+    categories: &[String],
+    move_to: &Option<String>,
+    json: &bool,
+) -> Result<()> {
+    let config = get_config()?;
+    let index = get_index(&config)?;
+    let matches = match matches.is_empty() {
+        true => &DEFAULT_MATCHES[..],
+        false => matches,
+    };
+    let keep_rules = match keep_rules.is_empty() {
+        true => &DEFAULT_KEEP_RULES[..],
+        false => keep_rules,
+    };
+    let formats: Vec<String> = formats.iter().map(|format| format.to_lowercase()).collect();
+    // find the categories, so short names can be used
+    let categories = categories
+        .iter()
+        .map(|category| Ok(category_of(&config.music_dir, &find_category(category)?)))
+        .collect::<Result<Vec<String>>>()?;
+
+    let entries: Vec<(&IndexEntry, &MusicTag)> = index
+        .entries()
+        .filter_map(|entry| Some((entry, entry.tag.as_ref()?)))
+        .collect();
+    let groups = find_dupes(&entries, matches);
+
+    // the best copy comes first
+    let mut dupes: Vec<Vec<DupeFile>> = groups
+        .into_iter()
+        .map(|group| {
+            let mut files: Vec<DupeFile> = group
+                .into_iter()
+                .map(|i| {
+                    let (entry, tag) = entries[i];
+                    DupeFile {
+                        category: category_of(&config.music_dir, &entry.path),
+                        path: &entry.path,
+                        title: &tag.song_title,
+                        artist: &tag.artist_name,
+                        codec: &tag.properties.codec,
+                        bitrate: tag.properties.bitrate,
+                        keep: false,
+                    }
+                })
+                .collect();
+            files.sort_by(|a, b| compare(a, b, keep_rules, &formats, &categories));
+            files[0].keep = true;
+            files
+        })
+        .collect();
+    dupes.sort_by(|a, b| (a[0].artist, a[0].title).cmp(&(b[0].artist, b[0].title)));
+
+    if *json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&dupes).context("Could not serialize duplicates")?
+        );
+    } else {
+        for files in &dupes {
+            println!("{} - {}", files[0].title.bold(), files[0].artist);
+            for file in files {
+                let quality = match file.bitrate {
+                    Some(bitrate) => format!("{}, {bitrate} kbps", file.codec),
+                    None => file.codec.to_string(),
+                };
+                println!(
+                    "  {} {} ({quality})",
+                    match file.keep {
+                        true => "keep".green(),
+                        false => "dupe".red(),
+                    },
+                    file.path.display()
+                );
+            }
+        }
+    }
+    let copies: usize = dupes.iter().map(|files| files.len() - 1).sum();
+    info!(
+        "Found {} songs with {copies} copies that are not kept",
+        dupes.len()
+    );
+
+    if let Some(move_to) = move_to {
+        let move_to = match Path::new(move_to).canonicalize() {
+            Ok(dir) => dir,
+            Err(_) => std::path::absolute(move_to)?,
+        };
+        if move_to.starts_with(&config.music_dir) {
+            return Err(anyhow!(
+                "The directory for duplicates can not be in the music directory"
+            ));
+        }
+        let mut moved = 0;
+        for file in dupes.iter().flatten().filter(|file| !file.keep) {
+            // keep the category and album directories, so it is clear where it came from
+            let target = move_to.join(file.path.strip_prefix(&config.music_dir)?);
+            create_parent_dirs(&target)?;
+            if move_file_to(file.path, &target, ConflictPolicy::Rename)?.is_some() {
+                moved += 1;
+            }
+        }
+        info!("Moved {moved} copies to \"{}\"", move_to.display());
+    }
+    Ok(())
+}
+
+/// Group the music that is the same by any of the ways to match,
+/// gives the groups with more than one file
+fn find_dupes(entries: &[(&IndexEntry, &MusicTag)], matches: &[DupeMatch]) -> Vec<Vec<usize>> {
+    // every file points to a file of its group, the first file of a group points to itself
+    let mut groups: Vec<usize> = (0..entries.len()).collect();
+    fn first(groups: &mut [usize], mut i: usize) -> usize {
+        while groups[i] != i {
+            groups[i] = groups[groups[i]];
+            i = groups[i];
+        }
+        i
+    }
+
+    for dupe_match in matches {
+        let mut same_key: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, key) in match_keys(entries, *dupe_match) {
+            same_key.entry(key).or_default().push(i);
+        }
+        for files in same_key.values() {
+            for (n, &i) in files.iter().enumerate() {
+                for &other in &files[..n] {
+                    if same_duration(entries, *dupe_match, i, other) {
+                        let (a, b) = (first(&mut groups, i), first(&mut groups, other));
+                        groups[a] = b;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut dupes: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for i in 0..entries.len() {
+        dupes.entry(first(&mut groups, i)).or_default().push(i);
+    }
+    dupes
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+/// The values files are matched on, files without a value are not matched
+fn match_keys(entries: &[(&IndexEntry, &MusicTag)], dupe_match: DupeMatch) -> Vec<(usize, String)> {
+    match dupe_match {
+        DupeMatch::Tags => entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, tag))| {
+                let artist = normalize(&tag.artist_name);
+                let title = normalize(&tag.song_title);
+                match title.is_empty() {
+                    true => None,
+                    false => Some((i, format!("{artist}\0{title}"))),
+                }
+            })
+            .collect(),
+        DupeMatch::Musicbrainz => entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, tag))| Some((i, tag.musicbrainz.recording.clone()?)))
+            .collect(),
+        DupeMatch::Audio => {
+            // the same audio has the same duration, so only those files are hashed
+            let mut durations: HashMap<Duration, Vec<usize>> = HashMap::new();
+            for (i, (_, tag)) in entries.iter().enumerate() {
+                durations
+                    .entry(tag.properties.duration)
+                    .or_default()
+                    .push(i);
+            }
+            let files: Vec<usize> = durations
+                .into_values()
+                .filter(|files| files.len() > 1)
+                .flatten()
+                .collect();
+            info!("Comparing the audio of {} files", files.len());
+            files
+                .into_iter()
+                .filter_map(|i| match audio_hash(&entries[i].0.path) {
+                    Ok(hash) => Some((i, format!("{hash:x}"))),
+                    Err(err) => {
+                        warn!(
+                            "Could not read the audio of \"{}\": {err}",
+                            entries[i].0.path.display()
+                        );
+                        None
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+/// If two files with the same key are also as long as each other,
+/// only music matched by the tags can be a different song with the same name
+fn same_duration(
+    entries: &[(&IndexEntry, &MusicTag)],
+    dupe_match: DupeMatch,
+    a: usize,
+    b: usize,
+) -> bool {
+    match dupe_match {
+        DupeMatch::Tags => {
+            let (a, b) = (
+                entries[a].1.properties.duration,
+                entries[b].1.properties.duration,
+            );
+            a.abs_diff(b) <= DURATION_TOLERANCE
+        }
+        DupeMatch::Musicbrainz | DupeMatch::Audio => true,
+    }
+}
+
+/// Lowercase and only letters and numbers, with a space between words
+fn normalize(value: &str) -> String {
+    value
+        .to_lowercase()
+        .split(|char: char| !char.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Compare two copies with the keep rules, the copy to keep comes first
+fn compare(
+    a: &DupeFile,
+    b: &DupeFile,
+    keep_rules: &[KeepRule],
+    formats: &[String],
+    categories: &[String],
+) -> Ordering {
+    // the position in the preferred list, not listed comes last
+    let rank = |list: &[String], value: &str| {
+        list.iter()
+            .position(|item| item == value)
+            .unwrap_or(list.len())
+    };
+    let format_rank = |file: &DupeFile| match formats.is_empty() {
+        true => usize::from(!is_lossless(file.codec)),
+        false => {
+            let extension = file
+                .path
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            rank(formats, file.codec).min(rank(formats, &extension))
+        }
+    };
+    keep_rules
+        .iter()
+        .map(|rule| match rule {
+            KeepRule::Format => format_rank(a).cmp(&format_rank(b)),
+            KeepRule::Bitrate => b.bitrate.cmp(&a.bitrate),
+            KeepRule::Category => rank(categories, &a.category).cmp(&rank(categories, &b.category)),
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or_else(|| a.path.cmp(b.path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::test_util::music_tag;
+
+    fn entry(album: &str, title: &str, seconds: u64) -> (IndexEntry, MusicTag) {
+        let tag = MusicTag {
+            artist_name: String::from("Radiohead"),
+            album_title: album.to_string(),
+            album_artist: String::from("Radiohead"),
+            properties: crate::music_tag::AudioProperties {
+                duration: Duration::from_secs(seconds),
+                ..Default::default()
+            },
+            ..music_tag(title)
+        };
+        let entry = IndexEntry {
+            path: PathBuf::from(format!("/music/{album}/{title}.flac")),
+            modified: SystemTime::UNIX_EPOCH,
+            size: 0,
+            tag: Some(tag.clone()),
+        };
+        (entry, tag)
+    }
+
+    fn tag_dupes(files: &[(IndexEntry, MusicTag)]) -> Vec<Vec<usize>> {
+        let entries: Vec<(&IndexEntry, &MusicTag)> =
+            files.iter().map(|(entry, tag)| (entry, tag)).collect();
+        find_dupes(&entries, &[DupeMatch::Tags])
+    }
+
+    #[test]
+    fn same_tags_and_duration() {
+        let files = [
+            entry("OK Computer", "Airbag", 284),
+            entry("OK Computer", "airbag!", 285),
+            entry("OK Computer", "Lucky", 259),
+        ];
+        assert_eq!(tag_dupes(&files), vec![vec![0, 1]]);
+    }
+
+    #[test]
+    fn single_and_album_version() {
+        let files = [
+            entry("OK Computer", "Karma Police", 264),
+            entry("Karma Police", "Karma Police", 263),
+        ];
+        assert_eq!(tag_dupes(&files), vec![vec![0, 1]]);
+    }
+
+    #[test]
+    fn different_duration_or_artist() {
+        let mut files = vec![
+            entry("OK Computer", "Airbag", 284),
+            // a live version
+            entry("OK Computer", "Airbag", 301),
+        ];
+        let mut cover = entry("OK Computer", "Airbag", 284);
+        cover.1.artist_name = String::from("Cover Band");
+        files.push(cover);
+        assert!(tag_dupes(&files).is_empty());
+    }
+}
@@ -0,0 +1,32 @@
+use log::info;
+
+use anyhow::Result;
+
+use crate::{config::get_config, index};
+
+/// Rebuild the library index from scratch
+pub fn rebuild() -> Result<()> {
+    let config = get_config()?;
+    info!("Rebuilding index of \"{}\"", config.music_dir.display());
+    let stats = index::rebuild_index(&config)?;
+    info!(
+        "Indexed {} files at \"{}\"",
+        stats.added,
+        index::index_path()?.display()
+    );
+    Ok(())
+}
+
+/// Update the library index with the files that changed
+pub fn update() -> Result<()> {
+    let config = get_config()?;
+    let (index, stats) = index::update_index(&config)?;
+    info!(
+        "{} added, {} updated, {} removed, {} files in the index",
+        stats.added,
+        stats.updated,
+        stats.removed,
+        index.entries().count()
+    );
+    Ok(())
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use log::{error, info, warn};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    category::get_category_config,
+    config::{get_config, Config},
+    music_tag::TagReader,
+    planner, read_dir_recursive,
+    template::{Template, DEFAULT_FILE_NAME_TEMPLATE},
+};
+
+use super::find_category;
+
+/// How deep rename looks for music files, category/artist/album/file
+const RENAME_DEPTH: u8 = 3;
+
+/// Rename the music files in an album directory or category from their tags
+pub fn rename(target: &str, template: &Option<String>) -> Result<()> {
+    let config = get_config()?;
+    let dir = match Path::new(target).is_dir() {
+        true => PathBuf::from(target).canonicalize()?,
+        false => find_category(target)?,
+    };
+    let template = match template {
+        Some(template) => Template::parse(template)?,
+        None => file_name_template(&config, &dir)?,
+    };
+    if template.dir_depth() > 0 {
+        return Err(anyhow!("The file name template can not have directories"));
+    }
+    if !template.keeps_extension() {
+        return Err(anyhow!(
+            "The file name template needs {{ext}}, otherwise the files lose their extension"
+        ));
+    }
+
+    let mut files = vec![];
+    for extension in &config.file_extensions {
+        files.append(&mut read_dir_recursive(
+            &dir,
+            Some(&OsString::from(extension)),
+            RENAME_DEPTH,
+        )?);
+    }
+    files.sort();
+
+    // get the new names
+    let reader = TagReader::from_config(&config);
+    let mut renames = vec![];
+    for file in files {
+        let name = match reader
+            .read(&file)
+            .and_then(|tag| template.render(&tag, &file))
+        {
+            Ok(name) => name,
+            Err(err) => {
+                warn!("Skipping \"{}\" because {err}", file.display());
+                continue;
+            }
+        };
+        let new_file = file.with_file_name(name);
+        if new_file != file {
+            renames.push((file, new_file));
+        }
+    }
+
+    let count = renames.len();
+    let conflicts = find_conflicts(&renames);
+    for (file, reason) in &conflicts {
+        error!("Can not rename \"{}\", {reason}", file.display());
+    }
+
+    if !conflicts.is_empty() {
+        for (file, new_file) in &renames {
+            let new_name = new_file.file_name().unwrap_or_default().to_string_lossy();
+            println!(
+                "{} {} {}",
+                file.display(),
+                "->".bold(),
+                match conflicts.contains_key(file) {
+                    true => new_name.red(),
+                    false => new_name.green(),
+                }
+            );
+        }
+        return Err(anyhow!(
+            "Found {} conflicts, nothing was renamed",
+            conflicts.len()
+        ));
+    }
+
+    for (file, new_file) in order_renames(renames) {
+        planner::rename(&file, &new_file)?;
+    }
+    info!(
+        "{} {count} files",
+        match planner::is_dry_run() {
+            true => "Would have renamed",
+            false => "Renamed",
+        }
+    );
+    Ok(())
+}
+
+/// The file name part of the path template of the category the directory is in
+fn file_name_template(config: &Config, dir: &Path) -> Result<Template> {
+    let template = match dir.strip_prefix(&config.music_dir) {
+        Ok(path) => match path.components().next() {
+            Some(category) => {
+                let category_config =
+                    get_category_config(&config.music_dir.join(category.as_os_str()))?;
+                Template::for_category(config, &category_config)?.file_name()
+            }
+            None => None,
+        },
+        // not in the library
+        Err(_) => None,
+    };
+    match template {
+        Some(template) => Ok(template),
+        None => Template::parse(DEFAULT_FILE_NAME_TEMPLATE),
+    }
+}
+
+/// Find the files that can not be renamed,
+/// because another file gets the same name or a file with the name already exists,
+/// a file that is renamed itself is not in the way
+fn find_conflicts(renames: &[(PathBuf, PathBuf)]) -> BTreeMap<PathBuf, String> {
+    let sources: HashSet<&Path> = renames.iter().map(|(file, _)| file.as_path()).collect();
+    let same_name = |file: &Path| {
+        format!(
+            "\"{}\" would get the same name",
+            file.file_name().unwrap_or_default().to_string_lossy()
+        )
+    };
+    let mut conflicts = BTreeMap::new();
+    let mut targets: HashMap<&Path, &Path> = HashMap::new();
+    for (file, new_file) in renames {
+        if let Some(other) = targets.insert(new_file, file) {
+            conflicts.insert(file.clone(), same_name(other));
+            conflicts.insert(other.to_path_buf(), same_name(file));
+        } else if new_file.exists() && !sources.contains(new_file.as_path()) {
+            conflicts.insert(
+                file.clone(),
+                format!("\"{}\" already exists", new_file.display()),
+            );
+        }
+    }
+    conflicts
+}
+
+/// Order the renames so no file is renamed onto a file that still has to be renamed,
+/// files that swap names are first renamed to a temporary name
+fn order_renames(mut pending: Vec<(PathBuf, PathBuf)>) -> Vec<(PathBuf, PathBuf)> {
+    let mut ordered = vec![];
+    while !pending.is_empty() {
+        let sources: HashSet<PathBuf> = pending.iter().map(|(file, _)| file.clone()).collect();
+        let (ready, blocked): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .partition(|(_, new_file)| !sources.contains(new_file));
+        pending = blocked;
+        if !ready.is_empty() {
+            ordered.extend(ready);
+            continue;
+        }
+        // only cycles are left, break one with a temporary name
+        let (file, new_file) = pending.remove(0);
+        let temporary = file.with_file_name(format!(
+            ".{}.rename",
+            file.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        ordered.push((file, temporary.clone()));
+        pending.push((temporary, new_file));
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renames(names: &[(&str, &str)]) -> Vec<(PathBuf, PathBuf)> {
+        names
+            .iter()
+            .map(|(from, to)| (PathBuf::from(from), PathBuf::from(to)))
+            .collect()
+    }
+
+    #[test]
+    fn chain_is_not_a_conflict() {
+        let chain = renames(&[("/album/a", "/album/b"), ("/album/b", "/album/c")]);
+        assert!(find_conflicts(&chain).is_empty());
+        assert_eq!(
+            order_renames(chain),
+            renames(&[("/album/b", "/album/c"), ("/album/a", "/album/b")])
+        );
+    }
+
+    #[test]
+    fn swap_uses_temporary_name() {
+        let swap = renames(&[("/album/a", "/album/b"), ("/album/b", "/album/a")]);
+        assert!(find_conflicts(&swap).is_empty());
+        assert_eq!(
+            order_renames(swap),
+            renames(&[
+                ("/album/a", "/album/.a.rename"),
+                ("/album/b", "/album/a"),
+                ("/album/.a.rename", "/album/b")
+            ])
+        );
+    }
+
+    #[test]
+    fn same_name_is_a_conflict() {
+        let conflicts = find_conflicts(&renames(&[
+            ("/album/a", "/album/c"),
+            ("/album/b", "/album/c"),
+        ]));
+        assert_eq!(conflicts.len(), 2);
+    }
+}
@@ -0,0 +1,237 @@
+use std::path::Path;
+
+use colored::Colorize;
+use log::info;
+use serde::Serialize;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{config::get_config, field::Field, index::get_index, music_tag::MusicTag};
+
+use super::category_of;
+
+/// The fields a term without a field is searched on
+const SEARCH_FIELDS: [Field; 7] = [
+    Field::Artist,
+    Field::AlbumArtist,
+    Field::Album,
+    Field::Title,
+    Field::Genre,
+    Field::Year,
+    Field::Composer,
+];
+
+/// A part of the query, with no field it matches on all fields
+#[derive(Debug, PartialEq)]
+struct Term {
+    field: Option<Field>,
+    value: String,
+}
+
+impl Term {
+    fn matches(&self, tag: &MusicTag, file: &Path) -> bool {
+        let matches = |field: &Field| {
+            field.value(tag, file, 0).is_some_and(|value| {
+                // a number should match completely
+                match field.is_number() {
+                    true => value == self.value,
+                    false => value.to_lowercase().contains(&self.value),
+                }
+            })
+        };
+        match &self.field {
+            Some(field) => matches(field),
+            None => SEARCH_FIELDS.iter().any(matches),
+        }
+    }
+}
+
+/// Split a query in terms, like `artist:radiohead album:"ok computer" creep`.
+/// The shell already split the query and removed the quotes, so every argument is one term,
+/// only a whole query in one argument is split again
+fn parse_query(query: &[String]) -> Result<Vec<Term>> {
+    let words = match query {
+        [joined] if is_joined(joined) => split_words(joined),
+        _ => query.to_vec(),
+    };
+    let mut terms = vec![];
+    for word in words {
+        let (field, value) = match word.split_once(':') {
+            Some((field, value)) => match Field::parse(field) {
+                Ok(field) => (Some(field), value),
+                // not a field, like re:zero, so it is a normal term
+                Err(_) => (None, word.as_str()),
+            },
+            None => (None, word.as_str()),
+        };
+        let value = value.trim().trim_matches('"').to_lowercase();
+        if value.is_empty() {
+            continue;
+        }
+        terms.push(Term { field, value });
+    }
+    if terms.is_empty() {
+        return Err(anyhow!("Empty search query"));
+    }
+    Ok(terms)
+}
+
+/// If an argument is a whole query and not one term,
+/// it has quotes or more than one field
+fn is_joined(argument: &str) -> bool {
+    let fields = argument
+        .split_whitespace()
+        .filter(|word| {
+            word.split_once(':')
+                .is_some_and(|(field, _)| Field::parse(field).is_ok())
+        })
+        .count();
+    argument.contains('"') || fields > 1
+}
+
+/// Split on whitespace, but keep words between quotes together
+fn split_words(input: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut quoted = false;
+    for char in input.chars() {
+        match char {
+            '"' => quoted = !quoted,
+            char if char.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            char => word.push(char),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// A track that matched the search
+#[derive(Serialize)]
+struct SearchResult<'a> {
+    category: String,
+    path: &'a Path,
+    #[serde(flatten)]
+    tag: &'a MusicTag,
+}
+
+/// Search the tags of all music in the library
+pub fn search(query: &[String], json: &bool) -> Result<()> {
+    let terms = parse_query(query)?;
+    let config = get_config()?;
+    let index = get_index(&config)?;
+
+    let mut results: Vec<SearchResult> = index
+        .entries()
+        .filter_map(|entry| Some((entry, entry.tag.as_ref()?)))
+        .filter(|(entry, tag)| terms.iter().all(|term| term.matches(tag, &entry.path)))
+        .map(|(entry, tag)| SearchResult {
+            category: category_of(&config.music_dir, &entry.path),
+            path: &entry.path,
+            tag,
+        })
+        .collect();
+    results.sort_by(|a, b| {
+        (&a.tag.album_artist, &a.tag.album_title, a.path).cmp(&(
+            &b.tag.album_artist,
+            &b.tag.album_title,
+            b.path,
+        ))
+    });
+
+    if *json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&results).context("Could not serialize results")?
+        );
+        return Ok(());
+    }
+
+    if results.is_empty() {
+        println!("{}", "No music found".red().bold());
+        return Ok(());
+    }
+    for result in &results {
+        println!("{}: {}", "Song".bold().green(), result.tag.song_title);
+        println!("{}: {}", "Artist".bold().purple(), result.tag.artist_name);
+        println!("{}: {}", "Album".bold().blue(), result.tag.album_title);
+        println!("{}: {}", "Category".bold().yellow(), result.category);
+        println!("{}: {}", "Path".bold(), result.path.display());
+        println!();
+    }
+    info!("Found {} songs", results.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(arguments: &[&str]) -> Vec<Term> {
+        let arguments: Vec<String> = arguments.iter().map(|arg| arg.to_string()).collect();
+        parse_query(&arguments).unwrap()
+    }
+
+    fn term(field: Option<Field>, value: &str) -> Term {
+        Term {
+            field,
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn argument_is_one_term() {
+        // the shell made album:"ok computer" into one argument without quotes
+        assert_eq!(
+            query(&["artist:Radiohead", "album:ok computer"]),
+            [
+                term(Some(Field::Artist), "radiohead"),
+                term(Some(Field::Album), "ok computer")
+            ]
+        );
+        assert_eq!(
+            query(&["album:ok computer"]),
+            [term(Some(Field::Album), "ok computer")]
+        );
+        assert_eq!(query(&["creep"]), [term(None, "creep")]);
+    }
+
+    #[test]
+    fn joined_query_is_split() {
+        assert_eq!(
+            query(&[r#"artist:radiohead album:"ok computer" creep"#]),
+            [
+                term(Some(Field::Artist), "radiohead"),
+                term(Some(Field::Album), "ok computer"),
+                term(None, "creep")
+            ]
+        );
+        assert_eq!(
+            query(&["artist:muse year:2003"]),
+            [
+                term(Some(Field::Artist), "muse"),
+                term(Some(Field::Year), "2003")
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_field_is_normal_term() {
+        assert_eq!(query(&["re:zero"]), [term(None, "re:zero")]);
+        assert_eq!(
+            query(&["title:re:zero"]),
+            [term(Some(Field::Title), "re:zero")]
+        );
+    }
+
+    #[test]
+    fn empty_query() {
+        assert!(parse_query(&[String::new()]).is_err());
+        assert!(parse_query(&["artist:".to_string()]).is_err());
+    }
+}
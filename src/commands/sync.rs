@@ -0,0 +1,186 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use colored::Colorize;
+use log::{error, info, warn};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    backend::DownloadOptions,
+    config::get_config,
+    planner, read_dir,
+    subscription::{
+        get_subscriptions, get_sync_states, save_sync_states, subscriptions_path, Subscription,
+        SyncState,
+    },
+};
+
+use super::{
+    category_of,
+    down::{download_with, DownloadSummary},
+    find_category,
+};
+
+/// What the subscriptions of a category gave, with their last sync before this one
+type CategoryReport = Vec<(Subscription, Option<SyncState>, Result<DownloadSummary>)>;
+
+/// Download the new videos of the subscriptions of a category, or of all categories,
+/// and print what every subscription gave
+pub fn sync(category: &Option<String>, quiet: &bool) -> Result<()> {
+    let config = get_config()?;
+    let category_dirs: Vec<PathBuf> = match category {
+        Some(category) => vec![find_category(category)?],
+        None => read_dir(&config.music_dir, None)?
+            .into_iter()
+            .filter(|dir| subscriptions_path(dir).is_file())
+            .collect(),
+    };
+
+    let mut failed = 0;
+    // categories with subscriptions that could not be read
+    let mut broken = 0;
+    let mut synced = 0;
+    // every category with what its subscriptions gave
+    let mut reports: Vec<(String, Result<CategoryReport>)> = vec![];
+    for category_dir in category_dirs {
+        let category = category_of(&config.music_dir, &category_dir);
+        // one broken category does not stop the others
+        let read = get_subscriptions(&category_dir)
+            .and_then(|subscriptions| Ok((subscriptions, get_sync_states(&category_dir)?)));
+        let (subscriptions, mut states) = match read {
+            Ok(read) => read,
+            Err(err) => {
+                error!("Could not sync {category}: {err:#}");
+                broken += 1;
+                reports.push((category, Err(err)));
+                continue;
+            }
+        };
+        if subscriptions.is_empty() {
+            warn!(
+                "\"{}\" has no subscriptions",
+                subscriptions_path(&category_dir).display()
+            );
+            continue;
+        }
+        let mut report = vec![];
+        for subscription in subscriptions {
+            info!("Syncing {}", subscription.name());
+            let options = DownloadOptions {
+                since: subscription.since.clone(),
+                format: subscription.format.clone(),
+                split_chapters: subscription.split_chapters.unwrap_or(true),
+                ..Default::default()
+            };
+            let last = states.get(&subscription.url).copied();
+            let result = download_with(
+                &subscription.url,
+                &category_dir,
+                options,
+                &subscription.auto_tag.unwrap_or(true),
+                quiet,
+            );
+            match &result {
+                Ok(summary) => {
+                    synced += 1;
+                    states.insert(
+                        subscription.url.clone(),
+                        SyncState {
+                            time: SystemTime::now(),
+                            new: summary.new,
+                        },
+                    );
+                }
+                Err(err) => {
+                    error!("Could not sync {}: {err}", subscription.name());
+                    failed += 1;
+                }
+            }
+            report.push((subscription, last, result));
+        }
+        // a dry run does not download anything
+        if !planner::is_dry_run() {
+            save_sync_states(&category_dir, &states)?;
+        }
+        reports.push((category, Ok(report)));
+    }
+
+    for (category, report) in &reports {
+        println!("{}", category.bold());
+        let report = match report {
+            Ok(report) => report,
+            Err(err) => {
+                println!("  {} {err:#}", "failed:".red());
+                continue;
+            }
+        };
+        for (subscription, last, result) in report {
+            let last = match last {
+                Some(last) => format!("last synced {}", ago(last.time)),
+                None => String::from("first sync"),
+            };
+            match result {
+                Ok(DownloadSummary {
+                    new,
+                    skipped,
+                    files,
+                }) => println!(
+                    "  {} {new} new videos ({files} files), {skipped} skipped, {last}",
+                    subscription.name().green()
+                ),
+                Err(err) => println!("  {} failed: {err}, {last}", subscription.name().red()),
+            }
+        }
+    }
+    info!("Synced {synced} subscriptions");
+    match (failed, broken) {
+        (0, 0) => Ok(()),
+        (_, 0) => Err(anyhow!("Could not sync {failed} subscriptions")),
+        (0, _) => Err(anyhow!("Could not sync {broken} categories")),
+        _ => Err(anyhow!(
+            "Could not sync {failed} subscriptions and {broken} categories"
+        )),
+    }
+}
+
+/// How long ago a time was, like "3 hours ago"
+fn ago(time: SystemTime) -> String {
+    let elapsed = time.elapsed().unwrap_or(Duration::ZERO).as_secs();
+    let (amount, unit) = match elapsed {
+        0..=59 => return String::from("just now"),
+        60..=3599 => (elapsed / 60, "minute"),
+        3600..=86399 => (elapsed / 3600, "hour"),
+        _ => (elapsed / 86400, "day"),
+    };
+    match amount {
+        1 => format!("1 {unit} ago"),
+        _ => format!("{amount} {unit}s ago"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ago_secs(secs: u64) -> String {
+        ago(SystemTime::now() - Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn times_ago() {
+        assert_eq!(ago_secs(10), "just now");
+        assert_eq!(ago_secs(60), "1 minute ago");
+        assert_eq!(ago_secs(59 * 60), "59 minutes ago");
+        assert_eq!(ago_secs(2 * 3600 + 60), "2 hours ago");
+        assert_eq!(ago_secs(86400), "1 day ago");
+        assert_eq!(ago_secs(40 * 86400), "40 days ago");
+        // a time in the future, like after the clock was changed
+        assert_eq!(
+            ago(SystemTime::now() + Duration::from_secs(600)),
+            "just now"
+        );
+    }
+}
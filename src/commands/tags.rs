@@ -0,0 +1,259 @@
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use lofty::config::WriteOptions;
+use lofty::file::TaggedFileExt;
+use lofty::read_from_path;
+use lofty::tag::{Accessor, ItemKey, Tag, TagExt};
+use log::{info, warn};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    config::get_config,
+    field::{Field, ALL_FIELDS},
+    planner::{self, Operation},
+    read_dir,
+    template::Template,
+};
+
+/// The value of a tag field
+fn get_field(field: Field, tag: &Tag) -> Option<String> {
+    match field {
+        Field::Title => tag.title().map(|value| value.to_string()),
+        Field::Artist => tag.artist().map(|value| value.to_string()),
+        Field::Album => tag.album().map(|value| value.to_string()),
+        Field::AlbumArtist => tag.get_string(&ItemKey::AlbumArtist).map(str::to_string),
+        Field::Genre => tag.genre().map(|value| value.to_string()),
+        Field::Year => tag.year().map(|value| value.to_string()),
+        Field::Date => tag.get_string(&ItemKey::RecordingDate).map(str::to_string),
+        Field::Track => tag.track().map(|value| value.to_string()),
+        Field::TrackTotal => tag.track_total().map(|value| value.to_string()),
+        Field::Disc => tag.disk().map(|value| value.to_string()),
+        Field::DiscTotal => tag.disk_total().map(|value| value.to_string()),
+        Field::Composer => tag.get_string(&ItemKey::Composer).map(str::to_string),
+        Field::Compilation => tag
+            .get_string(&ItemKey::FlagCompilation)
+            .map(str::to_string),
+        Field::Codec | Field::Ext | Field::FileName => None,
+    }
+}
+
+fn set_field(field: Field, tag: &mut Tag, value: &str) -> Result<()> {
+    let number = || {
+        value
+            .trim()
+            .parse::<u32>()
+            .with_context(|| format!("{} should be a number, not \"{value}\"", field.name()))
+    };
+    let value = value.to_string();
+    match field {
+        Field::Title => tag.set_title(value),
+        Field::Artist => tag.set_artist(value),
+        Field::Album => tag.set_album(value),
+        Field::AlbumArtist => {
+            tag.insert_text(ItemKey::AlbumArtist, value);
+        }
+        Field::Genre => tag.set_genre(value),
+        Field::Year => tag.set_year(number()?),
+        Field::Date => {
+            tag.insert_text(ItemKey::RecordingDate, value);
+        }
+        Field::Track => tag.set_track(number()?),
+        Field::TrackTotal => tag.set_track_total(number()?),
+        Field::Disc => tag.set_disk(number()?),
+        Field::DiscTotal => tag.set_disk_total(number()?),
+        Field::Composer => {
+            tag.insert_text(ItemKey::Composer, value);
+        }
+        Field::Compilation => {
+            let flag = match value.trim() {
+                "1" | "true" => "1",
+                "0" | "false" => "0",
+                _ => return Err(anyhow!("compilation should be 1 or 0, not \"{value}\"")),
+            };
+            tag.insert_text(ItemKey::FlagCompilation, flag.to_string());
+        }
+        Field::Codec | Field::Ext | Field::FileName => {
+            return Err(anyhow!("{} is not a tag field", field.name()))
+        }
+    }
+    Ok(())
+}
+
+fn remove_field(field: Field, tag: &mut Tag) {
+    match field {
+        Field::Title => tag.remove_title(),
+        Field::Artist => tag.remove_artist(),
+        Field::Album => tag.remove_album(),
+        Field::AlbumArtist => tag.remove_key(&ItemKey::AlbumArtist),
+        Field::Genre => tag.remove_genre(),
+        Field::Year => tag.remove_year(),
+        Field::Date => tag.remove_key(&ItemKey::RecordingDate),
+        Field::Track => tag.remove_track(),
+        Field::TrackTotal => tag.remove_track_total(),
+        Field::Disc => tag.remove_disk(),
+        Field::DiscTotal => tag.remove_disk_total(),
+        Field::Composer => tag.remove_key(&ItemKey::Composer),
+        Field::Compilation => tag.remove_key(&ItemKey::FlagCompilation),
+        Field::Codec | Field::Ext | Field::FileName => {}
+    }
+}
+
+/// A change to the tags of a file
+#[derive(Clone)]
+pub(crate) enum Change {
+    Set(Field, String),
+    Remove(Field),
+}
+
+impl Change {
+    fn describe(&self) -> String {
+        match self {
+            Change::Set(field, value) => format!("{}=\"{value}\"", field.name()),
+            Change::Remove(field) => format!("remove {}", field.name()),
+        }
+    }
+}
+
+/// Print the tags of music files and albums
+pub fn get(paths: &[String], fields: &[String]) -> Result<()> {
+    let fields = match fields.is_empty() {
+        true => ALL_FIELDS.into_iter().filter(Field::is_tag).collect(),
+        false => parse_fields(fields)?,
+    };
+    for file in music_files(paths)? {
+        let tagged_file = read_from_path(&file)?;
+        println!("{}", file.display().to_string().bold());
+        let Some(tag) = tagged_file.primary_tag().or(tagged_file.first_tag()) else {
+            println!("  {}", "No tags".red());
+            continue;
+        };
+        for field in &fields {
+            if let Some(value) = get_field(*field, tag) {
+                println!("  {}: {value}", field.name().bold().blue());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Set tag fields of music files and albums, from `field=value`
+/// or from the path of the files with a path template like `{album_artist}/{album}/{track:02} {title}`
+pub fn set(paths: &[String], values: &[String], from_path: &Option<String>) -> Result<()> {
+    let mut changes = vec![];
+    for value in values {
+        let (field, value) = value
+            .split_once('=')
+            .ok_or(anyhow!("\"{value}\" should be like field=value"))?;
+        changes.push(Change::Set(Field::parse_tag(field)?, value.to_string()));
+    }
+    let pattern = from_path.as_deref().map(Template::parse).transpose()?;
+    if changes.is_empty() && pattern.is_none() {
+        return Err(anyhow!("Nothing to set, use field=value or --from-path"));
+    }
+
+    let mut edited = 0;
+    for file in music_files(paths)? {
+        let mut file_changes = vec![];
+        if let Some(pattern) = &pattern {
+            match pattern.match_path(&file) {
+                Some(values) => file_changes.extend(
+                    values
+                        .into_iter()
+                        .filter(|(field, _)| field.is_tag())
+                        .map(|(field, value)| Change::Set(field, value)),
+                ),
+                None => {
+                    warn!("\"{}\" does not match the path pattern", file.display());
+                    continue;
+                }
+            }
+        }
+        // the values that are given explicitly win from the path
+        file_changes.extend(changes.iter().cloned());
+        edit_file(&file, &file_changes)?;
+        edited += 1;
+    }
+    info!("Edited the tags of {edited} files");
+    Ok(())
+}
+
+/// Remove tag fields from music files and albums
+pub fn remove(paths: &[String], fields: &[String]) -> Result<()> {
+    let changes: Vec<Change> = parse_fields(fields)?
+        .into_iter()
+        .map(Change::Remove)
+        .collect();
+    let files = music_files(paths)?;
+    for file in &files {
+        edit_file(file, &changes)?;
+    }
+    info!("Edited the tags of {} files", files.len());
+    Ok(())
+}
+
+fn parse_fields(fields: &[String]) -> Result<Vec<Field>> {
+    fields
+        .iter()
+        .flat_map(|fields| fields.split(','))
+        .map(Field::parse_tag)
+        .collect()
+}
+
+/// The music files of the paths, directories give all music files in them
+fn music_files(paths: &[String]) -> Result<Vec<PathBuf>> {
+    let config = get_config()?;
+    let mut files = vec![];
+    for path in paths {
+        let path = PathBuf::from(path);
+        if path.is_dir() {
+            let mut album: Vec<PathBuf> = read_dir(&path, None)?
+                .into_iter()
+                .filter(|file| {
+                    file.is_file()
+                        && file.extension().is_some_and(|ext| {
+                            config
+                                .file_extensions
+                                .contains(&ext.to_string_lossy().to_string())
+                        })
+                })
+                .collect();
+            album.sort();
+            files.append(&mut album);
+        } else if path.is_file() {
+            files.push(path);
+        } else {
+            return Err(anyhow!("\"{}\" is not a file or directory", path.display()));
+        }
+    }
+    Ok(files)
+}
+
+/// Apply the changes to the tags of a file and save it
+pub(crate) fn edit_file(file: &Path, changes: &[Change]) -> Result<()> {
+    if planner::plan(Operation::WriteTags {
+        file: file.to_path_buf(),
+        changes: changes.iter().map(Change::describe).collect(),
+    }) {
+        return Ok(());
+    }
+
+    let mut tagged_file =
+        read_from_path(file).with_context(|| format!("Could not read \"{}\"", file.display()))?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file.primary_tag_mut().unwrap();
+    for change in changes {
+        match change {
+            Change::Set(field, value) => set_field(*field, tag, value)?,
+            Change::Remove(field) => remove_field(*field, tag),
+        }
+    }
+    tag.save_to_path(file, WriteOptions::default())
+        .with_context(|| format!("Could not write the tags of \"{}\"", file.display()))?;
+    info!("Wrote tags to \"{}\"", file.display());
+    Ok(())
+}
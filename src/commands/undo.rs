@@ -0,0 +1,247 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
+
+use log::{info, warn};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    journal::{read_journal, write_journal, JournalEntry},
+    planner::{self, Operation},
+};
+
+/// Undo the last moves and created directories,
+/// with commands all operations of the last commands
+pub fn undo(count: &usize, commands: &bool) -> Result<()> {
+    let mut entries = read_journal()?;
+    if entries.is_empty() {
+        info!("Nothing to undo");
+        return Ok(());
+    }
+    // what could not be undone stays in the journal, also after an error
+    let undone = undo_entries(&mut entries, *count, *commands);
+    write_journal(&entries)?;
+    info!("Undid {} operations", undone?);
+    Ok(())
+}
+
+/// Undo the last entries, they are removed from the entries when they are undone.
+/// Gives the number of operations that were undone
+fn undo_entries(entries: &mut Vec<JournalEntry>, count: usize, commands: bool) -> Result<usize> {
+    // only the first entry of a command has the command
+    let names: HashMap<u128, String> = entries
+        .iter()
+        .filter_map(|entry| Some((entry.transaction, entry.command.clone()?)))
+        .collect();
+
+    // entries that can not be undone yet, they stay in the journal
+    let mut kept: Vec<JournalEntry> = vec![];
+    let mut undone = 0;
+    let mut undone_commands = 0;
+    let mut current = None;
+    let mut result = Ok(());
+    loop {
+        let done = match commands {
+            true => undone_commands,
+            false => undone,
+        };
+        if done >= count {
+            break;
+        }
+        let Some(entry) = entries.pop() else {
+            break;
+        };
+        let transaction = entry.transaction;
+        if current != Some(transaction) {
+            let name = names.get(&transaction).map_or("unknown", String::as_str);
+            info!("Undoing \"{name}\"");
+            current = Some(transaction);
+        }
+        match undo_entry(&entry) {
+            Ok(true) => undone += 1,
+            Ok(false) => kept.push(entry),
+            Err(err) => {
+                entries.push(entry);
+                result = Err(err);
+                break;
+            }
+        }
+        let command_done = entries
+            .last()
+            .is_none_or(|entry| entry.transaction != transaction);
+        if command_done {
+            undone_commands += 1;
+        }
+    }
+    entries.extend(kept.into_iter().rev());
+
+    // the first entry that is left of a command gets the command
+    let mut seen = HashSet::new();
+    for entry in entries.iter_mut() {
+        entry.command = match seen.insert(entry.transaction) {
+            true => names.get(&entry.transaction).cloned(),
+            false => None,
+        };
+    }
+    result.map(|_| undone)
+}
+
+/// Undo an entry, gives false if it can not be undone yet and should stay in the journal
+fn undo_entry(entry: &JournalEntry) -> Result<bool> {
+    match &entry.operation {
+        Operation::CreateDir(dir) => {
+            if !dir.is_dir() {
+                warn!("\"{}\" was already removed", dir.display());
+                return Ok(true);
+            }
+            // only remove directories that are empty, in a dry run the files are not moved out
+            if !planner::is_dry_run() && fs::read_dir(dir)?.next().is_some() {
+                warn!(
+                    "Not removing \"{}\" because it is not empty, it stays in the journal",
+                    dir.display()
+                );
+                return Ok(false);
+            }
+        }
+        Operation::Move { from, to } => {
+            if !to.exists() {
+                warn!(
+                    "Can not move \"{}\" back, it does not exist anymore",
+                    to.display()
+                );
+                return Ok(true);
+            }
+            if from.exists() {
+                return Err(anyhow!(
+                    "Can not move \"{}\" back, \"{}\" already exists",
+                    to.display(),
+                    from.display()
+                ));
+            }
+        }
+        // already created again
+        Operation::RemoveDir(dir) if dir.is_dir() => return Ok(true),
+        _ => {}
+    }
+    planner::undo(&entry.operation)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::SystemTime};
+
+    use super::*;
+    use crate::{
+        config::ConflictPolicy,
+        journal::{journal_path, start_transaction},
+        move_file_to,
+        test_util::{lock, test_dir},
+    };
+
+    fn entry(transaction: u128, operation: Operation) -> JournalEntry {
+        JournalEntry {
+            transaction,
+            command: Some(format!("command {transaction}")),
+            time: SystemTime::now(),
+            operation,
+        }
+    }
+
+    fn moved(from: &Path, to: &Path) -> Operation {
+        fs::rename(from, to).unwrap();
+        Operation::Move {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        }
+    }
+
+    #[test]
+    fn last_commands_are_undone_first() {
+        let _lock = lock();
+        let dir = test_dir("undo-order");
+        let album = dir.join("Album");
+        let (a, b) = (dir.join("a.opus"), dir.join("b.opus"));
+        fs::write(&a, "a").unwrap();
+        fs::write(&b, "b").unwrap();
+        fs::create_dir(&album).unwrap();
+        let mut entries = vec![
+            entry(1, Operation::CreateDir(album.clone())),
+            entry(1, moved(&a, &album.join("a.opus"))),
+            entry(2, moved(&b, &album.join("b.opus"))),
+        ];
+
+        assert_eq!(undo_entries(&mut entries, 1, true).unwrap(), 1);
+        assert!(b.is_file());
+        assert!(album.join("a.opus").is_file());
+        assert_eq!(entries.len(), 2);
+
+        // the file is moved out before its directory is removed
+        assert_eq!(undo_entries(&mut entries, 1, true).unwrap(), 2);
+        assert!(a.is_file());
+        assert!(!album.exists());
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn directory_is_only_removed_when_empty() {
+        let _lock = lock();
+        let dir = test_dir("undo-dir");
+        let album = dir.join("Album");
+        fs::create_dir(&album).unwrap();
+        fs::write(album.join("other.opus"), "other").unwrap();
+        let mut entries = vec![
+            entry(1, Operation::CreateDir(dir.join("Gone"))),
+            entry(2, Operation::CreateDir(album.clone())),
+        ];
+        entries[1].command = None;
+        entries[1].transaction = 1;
+
+        // the directory is not empty, it stays in the journal with the command
+        assert_eq!(undo_entries(&mut entries, 2, false).unwrap(), 1);
+        assert!(album.is_dir());
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, Operation::CreateDir(album.clone()));
+        assert_eq!(entries[0].command.as_deref(), Some("command 1"));
+
+        fs::remove_file(album.join("other.opus")).unwrap();
+        assert_eq!(undo_entries(&mut entries, 1, false).unwrap(), 1);
+        assert!(!album.exists());
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn existing_source_is_not_overwritten() {
+        let _lock = lock();
+        let dir = test_dir("undo-exists");
+        let (from, to) = (dir.join("song.opus"), dir.join("moved.opus"));
+        fs::write(&from, "moved").unwrap();
+        let mut entries = vec![entry(1, moved(&from, &to))];
+        fs::write(&from, "new").unwrap();
+
+        assert!(undo_entries(&mut entries, 1, false).is_err());
+        assert_eq!(entries.len(), 1, "the move stays in the journal");
+        assert_eq!(fs::read_to_string(&from).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&to).unwrap(), "moved");
+    }
+
+    #[test]
+    fn replaced_file_is_restored() {
+        let _lock = lock();
+        let _ = fs::remove_file(journal_path().unwrap());
+        let dir = test_dir("undo-replaced");
+        let (file, target) = (dir.join("new.opus"), dir.join("song.opus"));
+        fs::write(&file, "new").unwrap();
+        fs::write(&target, "old").unwrap();
+        start_transaction(String::from("music_manager add new.opus"));
+        move_file_to(&file, &target, ConflictPolicy::Overwrite).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+
+        undo(&1, &true).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old");
+        assert!(read_journal().unwrap().is_empty());
+    }
+}
@@ -1,11 +1,12 @@
 use std::{
+    collections::BTreeMap,
     fs,
     io::ErrorKind,
     path::{Path, PathBuf},
 };
 
 use directories::{BaseDirs, UserDirs};
-use log::{error, info, warn};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
 use anyhow::{anyhow, Context, Result};
@@ -22,12 +23,148 @@ pub struct Config {
     pub file_extensions: Vec<String>,
     /// Files to check if they are in the album directory, as a glob pattern
     pub album_files: Option<Vec<String>>,
+    /// What to do when a file is moved to a path that already exists, rename by default
+    pub conflict_policy: Option<ConflictPolicy>,
+    /// Where music files are put in a category, like `{album_artist}/{album}/{filename}`
+    pub path_template: Option<String>,
+    /// How to find the album artist of music without the tag, tried in order,
+    /// sort_name, various_artists and then track_artist by default
+    pub album_artist_fallback: Option<Vec<AlbumArtistFallback>>,
+    /// Where the discs of albums with more than one disc go, flat by default
+    pub disc_layout: Option<DiscLayout>,
+    /// The program that downloads music, yt-dlp by default
+    pub downloader: Option<ToolConfig<DownloaderProgram>>,
+    /// The program that writes the replaygain tags, native if it is built in, otherwise rsgain
+    pub normalizer: Option<ToolConfig<NormalizerProgram>>,
+    /// How many albums are normalized at the same time, the number of cpus by default
+    pub normalize_jobs: Option<usize>,
+    /// The program that tags music, picard by default
+    pub tagger: Option<ToolConfig<TaggerProgram>>,
+    /// The tags that are filled from the information of downloaded videos,
+    /// a tag field with the information to use, the first that is found is used
+    pub download_tags: Option<BTreeMap<String, Vec<InfoField>>>,
 }
 
-pub fn get_config() -> Result<Config> {
+/// Which external program is used for something, and how it is run
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ToolConfig<P> {
+    pub program: Option<P>,
+    /// The path of the program, if it is not in the PATH or has another name
+    pub path: Option<PathBuf>,
+    /// Extra arguments, they come before the files or url
+    pub args: Option<Vec<String>>,
+}
+
+/// What to do when a moved file would replace a file that already exists
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Leave the file where it is
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Add a number to the file name, like "song (1).opus"
+    #[default]
+    Rename,
+    /// Keep the file with the best quality, lossless before a higher bitrate
+    KeepBest,
+    /// Skip the file if the audio is the same, otherwise rename it
+    CompareAudio,
+}
+
+/// A program that downloads music
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloaderProgram {
+    YtDlp,
+}
+
+/// A program that writes replaygain tags
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NormalizerProgram {
+    Rsgain,
+    Loudgain,
+    /// Measures the loudness with ffmpeg, the tags are written by music_manager
+    Ffmpeg,
+    /// Decode and analyze the audio in music_manager, needs the native-replaygain feature
+    Native,
+}
+
+/// A program that tags music
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaggerProgram {
+    Picard,
+    /// beets, the files are imported without copying them
+    Beets,
+}
+
+/// Information of a downloaded video that can be put in a tag
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InfoField {
+    /// The title of the video
+    Title,
+    /// The title of the chapter, only for the files of chapters
+    Chapter,
+    /// The number of the chapter, only for the files of chapters
+    ChapterNumber,
+    /// The number of chapters, only for the files of chapters
+    ChapterTotal,
+    /// The title of the video the chapter is from, only for the files of chapters
+    Video,
+    Track,
+    TrackNumber,
+    Artist,
+    Album,
+    ReleaseYear,
+    /// The year of the upload date
+    UploadYear,
+    Uploader,
+    Channel,
+}
+
+/// Where the music of albums with more than one disc is put
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscLayout {
+    /// All discs together in the album directory
+    #[default]
+    Flat,
+    /// A directory for every disc in the album directory, like "Disc 1"
+    Subfolders,
+    /// The disc number before the file name, like "1-01 Airbag.opus"
+    Prefix,
+}
+
+/// A way to find the album artist when the tag is missing
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlbumArtistFallback {
+    /// From the album artist sort name, "Beatles, The" gives "The Beatles"
+    SortName,
+    /// "Various Artists" if the other music of the album has other artists
+    VariousArtists,
+    /// The artist of the track
+    TrackArtist,
+}
+
+pub const DEFAULT_ALBUM_ARTIST_FALLBACK: [AlbumArtistFallback; 3] = [
+    AlbumArtistFallback::SortName,
+    AlbumArtistFallback::VariousArtists,
+    AlbumArtistFallback::TrackArtist,
+];
+
+/// Get the directory where the config and other music_manager data is stored
+pub fn get_config_dir() -> Result<PathBuf> {
     let base_dir = BaseDirs::new().ok_or(anyhow!("Could not find directories"))?;
     let config_dir = BaseDirs::config_dir(&base_dir);
-    let config_path = config_dir.join("music_manager/config.toml");
+    Ok(config_dir.join("music_manager"))
+}
+
+pub fn get_config() -> Result<Config> {
+    let config_path = get_config_dir()?.join("config.toml");
 
     // get content or create new content
     let config: Config = match fs::read_to_string(config_path) {
@@ -48,15 +185,12 @@ pub fn get_config() -> Result<Config> {
 }
 
 fn make_config() -> Result<Config> {
-    let base_dir = BaseDirs::new().ok_or(anyhow!("Could not find directories"))?;
-    let config_dir = BaseDirs::config_dir(&base_dir);
-    if let Err(err) = fs::create_dir(config_dir.join("music_manager")) {
-        match err.kind() {
-            ErrorKind::AlreadyExists => warn!("Directory already exists"),
-            _ => {
-                return Err(anyhow!("Could not make directory\n{err}"));
-            }
-        }
+    let config_dir = get_config_dir()?;
+    if config_dir.is_dir() {
+        warn!("Directory already exists");
+    } else {
+        // like the index, the config directory is not part of the plan
+        fs::create_dir_all(&config_dir).context("Could not make directory")?;
     }
     let music_dir = Path::new(&get_dir_music()?).to_owned();
     let default_dir = music_dir.join("other");
@@ -65,10 +199,19 @@ fn make_config() -> Result<Config> {
         default_dir,
         file_extensions: vec!["opus".to_string()],
         album_files: None,
+        conflict_policy: None,
+        path_template: None,
+        album_artist_fallback: None,
+        disc_layout: None,
+        downloader: None,
+        normalizer: None,
+        normalize_jobs: None,
+        download_tags: None,
+        tagger: None,
     };
 
     let content = toml::to_string(&config).context("deserialize config")?;
-    create_file(&config_dir.join("music_manager/config.toml"), content)?;
+    create_file(&config_dir.join("config.toml"), content)?;
     Ok(config)
 }
 
@@ -88,3 +231,25 @@ fn get_dir_music() -> std::io::Result<String> {
 
     Ok(result.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalizer(lines: &str) -> Option<NormalizerProgram> {
+        let config: Config = toml::from_str(&format!(
+            "music_dir = \"/music\"\ndefault_dir = \"/music/other\"\nfile_extensions = [\"opus\"]\n{lines}"
+        ))
+        .unwrap();
+        config.normalizer.and_then(|tool| tool.program)
+    }
+
+    #[test]
+    fn normalizer_table() {
+        assert_eq!(
+            normalizer("[normalizer]\nprogram = \"loudgain\"\n"),
+            Some(NormalizerProgram::Loudgain)
+        );
+        assert_eq!(normalizer(""), None);
+    }
+}
@@ -0,0 +1,144 @@
+//! The fields of music files, the same names are used by search, path templates and tags
+
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use crate::music_tag::MusicTag;
+
+/// A value from the tags or the file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    AlbumArtist,
+    Artist,
+    Album,
+    Title,
+    Genre,
+    Year,
+    /// The full recording date
+    Date,
+    Track,
+    TrackTotal,
+    Disc,
+    DiscTotal,
+    Composer,
+    /// 1 for compilations, otherwise 0
+    Compilation,
+    /// The file type, like opus or flac
+    Codec,
+    /// The extension of the file
+    Ext,
+    /// The file name the file already has
+    FileName,
+}
+
+pub const ALL_FIELDS: [Field; 16] = [
+    Field::AlbumArtist,
+    Field::Artist,
+    Field::Album,
+    Field::Title,
+    Field::Genre,
+    Field::Year,
+    Field::Date,
+    Field::Track,
+    Field::TrackTotal,
+    Field::Disc,
+    Field::DiscTotal,
+    Field::Composer,
+    Field::Compilation,
+    Field::Codec,
+    Field::Ext,
+    Field::FileName,
+];
+
+impl Field {
+    /// Parse the name of a field, like album_artist or track_total
+    pub fn parse(name: &str) -> Result<Field> {
+        let name = name.trim().to_lowercase();
+        match name.as_str() {
+            "albumartist" => return Ok(Field::AlbumArtist),
+            "song" => return Ok(Field::Title),
+            _ => {}
+        }
+        ALL_FIELDS
+            .into_iter()
+            .find(|field| field.name() == name)
+            .ok_or(anyhow!(
+                "Unknown field \"{name}\", use {}",
+                ALL_FIELDS.map(|field| field.name()).join(", ")
+            ))
+    }
+
+    /// Parse the name of a field that is stored in the tags
+    pub fn parse_tag(name: &str) -> Result<Field> {
+        let field = Field::parse(name)?;
+        match field.is_tag() {
+            true => Ok(field),
+            false => Err(anyhow!("{} is not a tag field", field.name())),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::AlbumArtist => "album_artist",
+            Field::Artist => "artist",
+            Field::Album => "album",
+            Field::Title => "title",
+            Field::Genre => "genre",
+            Field::Year => "year",
+            Field::Date => "date",
+            Field::Track => "track",
+            Field::TrackTotal => "track_total",
+            Field::Disc => "disc",
+            Field::DiscTotal => "disc_total",
+            Field::Composer => "composer",
+            Field::Compilation => "compilation",
+            Field::Codec => "codec",
+            Field::Ext => "ext",
+            Field::FileName => "filename",
+        }
+    }
+
+    /// If the field is stored in the tags, and not a property of the file
+    pub fn is_tag(&self) -> bool {
+        !matches!(self, Field::Codec | Field::Ext | Field::FileName)
+    }
+
+    /// If the value of the field is a number
+    pub fn is_number(&self) -> bool {
+        matches!(
+            self,
+            Field::Year | Field::Track | Field::TrackTotal | Field::Disc | Field::DiscTotal
+        )
+    }
+
+    /// Get the value of this field, numbers are padded with zeros to the width
+    pub fn value(&self, tag: &MusicTag, file: &Path, width: usize) -> Option<String> {
+        let number = |number: Option<u32>| number.map(|number| format!("{number:0width$}"));
+        match self {
+            Field::AlbumArtist => Some(tag.album_artist.clone()),
+            Field::Artist => Some(tag.artist_name.clone()),
+            Field::Album => Some(tag.album_title.clone()),
+            Field::Title => Some(tag.song_title.clone()),
+            Field::Genre => tag.genre.clone(),
+            Field::Year => number(tag.year),
+            Field::Date => tag.date.clone(),
+            Field::Track => number(tag.track),
+            Field::TrackTotal => number(tag.track_total),
+            Field::Disc => number(tag.disc),
+            Field::DiscTotal => number(tag.disc_total),
+            Field::Composer => tag.composer.clone(),
+            Field::Compilation => Some(String::from(match tag.compilation {
+                true => "1",
+                false => "0",
+            })),
+            Field::Codec => Some(tag.properties.codec.clone()),
+            Field::Ext => file
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_string()),
+            Field::FileName => file
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string()),
+        }
+    }
+}
@@ -0,0 +1,442 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fs::{self, File},
+    io::{BufWriter, ErrorKind},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    config::{get_config_dir, Config},
+    music_tag::{MusicTag, TagReader},
+    planner, read_dir, read_dir_recursive,
+};
+
+/// The max depth of the files that get indexed below a category directory,
+/// artist/album/file, or artist/album/disc/file with the subfolder disc layout
+const INDEX_DEPTH: u8 = 3;
+
+/// Version of the index format, the index is rebuilt if it does not match,
+/// so it should be incremented when `MusicTag` changes
+const INDEX_VERSION: u32 = 6;
+
+/// A music file in the index
+#[derive(Deserialize, Serialize, Clone)]
+pub struct IndexEntry {
+    pub path: PathBuf,
+    pub modified: SystemTime,
+    pub size: u64,
+    /// None if the file could not be read or has missing tags
+    pub tag: Option<MusicTag>,
+}
+
+/// Index of all music files in the library,
+/// stored next to the config so the tags don't have to be read every time
+#[derive(Deserialize, Serialize)]
+pub struct Index {
+    #[serde(default)]
+    version: u32,
+    /// The music directory the index was made for
+    music_dir: PathBuf,
+    entries: BTreeMap<PathBuf, IndexEntry>,
+}
+
+/// What changed after refreshing the index
+#[derive(Default)]
+pub struct RefreshStats {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+impl RefreshStats {
+    pub fn changed(&self) -> bool {
+        self.added > 0 || self.updated > 0 || self.removed > 0
+    }
+}
+
+/// Get the path of the index file
+pub fn index_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("index.json"))
+}
+
+/// Get the index, and update it with the changes in the library
+pub fn get_index(config: &Config) -> Result<Index> {
+    Ok(update_index(config)?.0)
+}
+
+/// Get the index, and only update it with the changes in one category,
+/// the entries of the other categories can be outdated
+pub fn get_category_index(config: &Config, category_dir: &Path) -> Result<Index> {
+    let mut index = load_index(config)?;
+    let stats = index.refresh_dirs(config, &[category_dir.to_path_buf()])?;
+    if stats.changed() {
+        index.save()?;
+    }
+    Ok(index)
+}
+
+/// Update the index with the changes in the library, and save it if anything changed
+pub fn update_index(config: &Config) -> Result<(Index, RefreshStats)> {
+    let mut index = load_index(config)?;
+    let stats = index.refresh(config)?;
+    if stats.changed() {
+        debug!(
+            "Index refreshed: {} added, {} updated, {} removed",
+            stats.added, stats.updated, stats.removed
+        );
+        index.save()?;
+    }
+    Ok((index, stats))
+}
+
+/// Throw away the old index and make a new one
+pub fn rebuild_index(config: &Config) -> Result<RefreshStats> {
+    let mut index = Index::new(config);
+    let stats = index.refresh(config)?;
+    index.save()?;
+    Ok(stats)
+}
+
+/// Only the version of a stored index
+#[derive(Deserialize)]
+struct IndexVersion {
+    #[serde(default)]
+    version: u32,
+}
+
+/// Read the index from disk, gives an empty index if there is none
+fn load_index(config: &Config) -> Result<Index> {
+    let path = index_path()?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            info!("No index found, creating one at \"{}\"", path.display());
+            return Ok(Index::new(config));
+        }
+        Err(err) => return Err(anyhow!("Could not open index because of {err}")),
+    };
+
+    // check the version first, an old index can not be read as the current one
+    let version = serde_json::from_str::<IndexVersion>(&content).map(|index| index.version);
+    if version.is_ok_and(|version| version != INDEX_VERSION) {
+        info!("Index is outdated, rebuilding it");
+        return Ok(Index::new(config));
+    }
+    let index: Index = match serde_json::from_str(&content) {
+        Ok(index) => index,
+        Err(err) => {
+            warn!("Could not read index, rebuilding it: {err}");
+            return Ok(Index::new(config));
+        }
+    };
+
+    if index.music_dir != config.music_dir {
+        info!("Music directory changed, rebuilding index");
+        return Ok(Index::new(config));
+    }
+    Ok(index)
+}
+
+impl Index {
+    fn new(config: &Config) -> Index {
+        Index {
+            version: INDEX_VERSION,
+            music_dir: config.music_dir.clone(),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Write the index to disk, through a temporary file
+    /// so an interrupted write does not leave a broken index.
+    /// A dry run does not change anything on disk, also not the index
+    pub fn save(&self) -> Result<()> {
+        if planner::is_dry_run() {
+            debug!("Not saving the index in a dry run");
+            return Ok(());
+        }
+        let path = index_path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp_path = path.with_extension("json.tmp");
+        let file = File::create(&temp_path)
+            .with_context(|| format!("Could not create index at \"{}\"", temp_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self).context("Could not write index")?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()
+            .context("Could not write index")?;
+        fs::rename(&temp_path, &path)
+            .with_context(|| format!("Could not replace index at \"{}\"", path.display()))
+    }
+
+    /// Walk the library and only read the tags of files that changed since the last refresh
+    pub fn refresh(&mut self, config: &Config) -> Result<RefreshStats> {
+        let category_dirs = category_dirs(config)?;
+        self.refresh_dirs(config, &category_dirs)
+    }
+
+    /// Walk some category directories and only read the tags of files that changed,
+    /// the entries of other directories are kept as they are
+    pub fn refresh_dirs(&mut self, config: &Config, dirs: &[PathBuf]) -> Result<RefreshStats> {
+        let mut stats = RefreshStats::default();
+        let reader = TagReader::from_config(config);
+        let mut found = BTreeMap::new();
+        // directories with files that were added, changed or removed
+        let mut changed_dirs = BTreeSet::new();
+        let mut fresh = BTreeSet::new();
+        // the entries that are refreshed, the others are put back at the end
+        let (mut old, kept): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|(path, _)| dirs.iter().any(|dir| path.starts_with(dir)));
+
+        for file in library_files(config, dirs)? {
+            let metadata = match fs::metadata(&file) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    warn!("Could not read metadata of \"{}\": {err}", file.display());
+                    continue;
+                }
+            };
+            let modified = metadata.modified()?;
+            let size = metadata.len();
+
+            let entry = match old.remove(&file) {
+                Some(entry) if entry.modified == modified && entry.size == size => {
+                    stats.unchanged += 1;
+                    entry
+                }
+                old => {
+                    if let Some(dir) = file.parent() {
+                        changed_dirs.insert(dir.to_path_buf());
+                    }
+                    fresh.insert(file.clone());
+                    match old {
+                        Some(_) => stats.updated += 1,
+                        None => stats.added += 1,
+                    }
+                    IndexEntry {
+                        tag: reader.read(&file).ok(),
+                        path: file.clone(),
+                        modified,
+                        size,
+                    }
+                }
+            };
+            found.insert(file, entry);
+        }
+
+        // everything left was not found in the library anymore
+        stats.removed = old.len();
+        changed_dirs.extend(
+            old.keys()
+                .filter_map(|file| file.parent())
+                .map(Path::to_path_buf),
+        );
+
+        // inferred tags depend on the other music in the album, so they are read again
+        for entry in found
+            .values_mut()
+            .filter(|entry| !fresh.contains(&entry.path))
+        {
+            let inferred = entry
+                .tag
+                .as_ref()
+                .is_some_and(|tag| !tag.inferred.is_empty());
+            if inferred
+                && entry
+                    .path
+                    .parent()
+                    .is_some_and(|dir| changed_dirs.contains(dir))
+            {
+                entry.tag = reader.read(&entry.path).ok();
+                stats.unchanged -= 1;
+                stats.updated += 1;
+            }
+        }
+        self.entries = kept;
+        self.entries.append(&mut found);
+        Ok(stats)
+    }
+
+    /// All the entries in the index
+    pub fn entries(&self) -> impl Iterator<Item = &IndexEntry> {
+        self.entries.values()
+    }
+
+    /// All the entries in a directory, including the subdirectories
+    pub fn entries_in<'a>(&'a self, dir: &'a Path) -> impl Iterator<Item = &'a IndexEntry> {
+        self.entries
+            .range(dir.to_path_buf()..)
+            .take_while(move |(path, _)| path.starts_with(dir))
+            .map(|(_, entry)| entry)
+    }
+
+    /// Get the entry of a file
+    pub fn get(&self, file: &Path) -> Option<&IndexEntry> {
+        self.entries.get(file)
+    }
+}
+
+/// Get all category directories in the music directory
+fn category_dirs(config: &Config) -> Result<Vec<PathBuf>> {
+    let mut category_dirs = read_dir(&config.music_dir, None)?;
+    // skip hidden directories
+    category_dirs.retain(|x| {
+        x.is_dir()
+            && !x
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .starts_with('.')
+    });
+    Ok(category_dirs)
+}
+
+/// Get all music files in the category directories
+fn library_files(config: &Config, category_dirs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for category_dir in category_dirs {
+        for extension in &config.file_extensions {
+            files.append(&mut read_dir_recursive(
+                category_dir,
+                Some(&OsString::from(extension)),
+                INDEX_DEPTH,
+            )?);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::{config, lock, test_dir};
+
+    /// A library with a category and some files that are not real music,
+    /// they are indexed without tags
+    fn library() -> Config {
+        let music_dir = test_dir("index");
+        let album_dir = music_dir.join("Category/Artist/Album");
+        fs::create_dir_all(&album_dir).unwrap();
+        for name in ["01.wav", "02.wav"] {
+            fs::write(album_dir.join(name), "not music").unwrap();
+        }
+        fs::create_dir_all(music_dir.join("Other/Artist")).unwrap();
+        fs::write(music_dir.join("Other/Artist/song.wav"), "not music").unwrap();
+        config(&music_dir)
+    }
+
+    #[test]
+    fn outdated_index_is_rebuilt() {
+        let _lock = lock();
+        let config = library();
+        rebuild_index(&config).unwrap();
+        assert_eq!(load_index(&config).unwrap().entries().count(), 3);
+
+        let content = fs::read_to_string(index_path().unwrap()).unwrap();
+        let old = content.replacen(&format!("\"version\":{INDEX_VERSION}"), "\"version\":1", 1);
+        assert_ne!(content, old);
+        fs::write(index_path().unwrap(), old).unwrap();
+        assert_eq!(load_index(&config).unwrap().entries().count(), 0);
+
+        // an index of another music directory is not used either
+        rebuild_index(&config).unwrap();
+        let other = library();
+        assert_eq!(load_index(&other).unwrap().entries().count(), 0);
+    }
+
+    #[test]
+    fn refresh_reads_changed_files() {
+        let _lock = lock();
+        let config = library();
+        let mut index = Index::new(&config);
+        let stats = index.refresh(&config).unwrap();
+        assert_eq!(stats.added, 3);
+        assert!(stats.changed());
+
+        let stats = index.refresh(&config).unwrap();
+        assert_eq!(stats.unchanged, 3);
+        assert!(!stats.changed());
+
+        let file = config.music_dir.join("Category/Artist/Album/01.wav");
+        let modified = index.get(&file).unwrap().modified;
+        File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(10))
+            .unwrap();
+        let stats = index.refresh(&config).unwrap();
+        assert_eq!((stats.updated, stats.unchanged), (1, 2));
+        assert_eq!(
+            index.get(&file).unwrap().modified,
+            modified + Duration::from_secs(10)
+        );
+
+        fs::remove_file(&file).unwrap();
+        let stats = index.refresh(&config).unwrap();
+        assert_eq!((stats.removed, stats.unchanged), (1, 2));
+        assert!(index.get(&file).is_none());
+    }
+
+    #[test]
+    fn refresh_dirs_keeps_other_categories() {
+        let _lock = lock();
+        let config = library();
+        let mut index = Index::new(&config);
+        index.refresh(&config).unwrap();
+
+        fs::remove_file(config.music_dir.join("Other/Artist/song.wav")).unwrap();
+        let category_dir = config.music_dir.join("Category");
+        let stats = index.refresh_dirs(&config, &[category_dir]).unwrap();
+        assert_eq!((stats.removed, stats.unchanged), (0, 2));
+        assert_eq!(index.entries().count(), 3);
+    }
+
+    #[test]
+    fn entries_in_directory() {
+        let _lock = lock();
+        let config = library();
+        let mut index = Index::new(&config);
+        index.refresh(&config).unwrap();
+
+        let album_dir = config.music_dir.join("Category/Artist/Album");
+        let files: Vec<_> = index
+            .entries_in(&album_dir)
+            .map(|entry| entry.path.clone())
+            .collect();
+        assert_eq!(
+            files,
+            vec![album_dir.join("01.wav"), album_dir.join("02.wav")]
+        );
+        assert_eq!(index.entries_in(&config.music_dir).count(), 3);
+        // a directory that starts with the same name is not inside it
+        let prefix = config.music_dir.join("Cat");
+        assert_eq!(index.entries_in(&prefix).count(), 0);
+    }
+
+    #[test]
+    fn dry_run_does_not_save() {
+        let _lock = lock();
+        let config = library();
+        let _ = fs::remove_file(index_path().unwrap());
+        planner::set_dry_run(true);
+        let stats = rebuild_index(&config).unwrap();
+        planner::set_dry_run(false);
+        assert_eq!(stats.added, 3);
+        assert!(!index_path().unwrap().exists());
+    }
+}
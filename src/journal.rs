@@ -0,0 +1,334 @@
+//! The journal of all the moves and created directories, so they can be undone
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    config::get_config_dir,
+    planner::{self, Operation},
+};
+
+/// When the journal is bigger than this, the oldest commands are removed
+const MAX_JOURNAL_SIZE: u64 = 4 * 1024 * 1024;
+/// How big the journal is after removing the oldest commands,
+/// so it is not rotated again on every change after that
+const KEEP_JOURNAL_SIZE: u64 = MAX_JOURNAL_SIZE / 2;
+/// Commands are only kept to show what is undone, long ones are cut off
+const MAX_COMMAND_LENGTH: usize = 200;
+
+/// The command that is running, every operation is recorded with this
+static TRANSACTION: Mutex<Option<Transaction>> = Mutex::new(None);
+
+#[derive(Clone)]
+struct Transaction {
+    id: u128,
+    command: String,
+    /// If the command is in the journal already, only the first entry has it
+    recorded: bool,
+    /// If the journal was rotated in this transaction, it is only done once per command
+    rotated: bool,
+}
+
+/// An operation in the journal
+#[derive(Deserialize, Serialize)]
+pub struct JournalEntry {
+    /// All operations of one command have the same transaction
+    pub transaction: u128,
+    /// The command that made the change, only in the first entry of a transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    pub time: SystemTime,
+    pub operation: Operation,
+}
+
+/// Get the path of the journal file
+pub fn journal_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("journal.jsonl"))
+}
+
+/// Get the directory where replaced files are kept, so the move can be undone
+pub fn trash_dir() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("trash"))
+}
+
+impl Transaction {
+    fn new(command: String) -> Transaction {
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let command = match command.char_indices().nth(MAX_COMMAND_LENGTH) {
+            Some((end, _)) => format!("{}...", &command[..end]),
+            None => command,
+        };
+        Transaction {
+            id,
+            command,
+            recorded: false,
+            rotated: false,
+        }
+    }
+}
+
+/// Start a new transaction, all operations after this can be undone together
+pub fn start_transaction(command: String) {
+    *TRANSACTION.lock().unwrap() = Some(Transaction::new(command));
+}
+
+/// Add an operation to the journal,
+/// failing to do so is only a warning because the operation already happened
+pub fn record(operation: &Operation) {
+    if let Err(err) = try_record(operation) {
+        warn!(
+            "Could not write {} to the journal: {err}",
+            operation.describe()
+        );
+    }
+}
+
+fn try_record(operation: &Operation) -> Result<()> {
+    // nothing happened in a dry run
+    if planner::is_dry_run() {
+        return Ok(());
+    }
+    let mut transaction = TRANSACTION.lock().unwrap();
+    let transaction = transaction.get_or_insert_with(|| Transaction::new(String::from("unknown")));
+    let entry = JournalEntry {
+        transaction: transaction.id,
+        command: (!transaction.recorded).then(|| transaction.command.clone()),
+        time: SystemTime::now(),
+        operation: operation.clone(),
+    };
+
+    let path = journal_path()?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Could not open journal at \"{}\"", path.display()))?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+    transaction.recorded = true;
+
+    if !transaction.rotated && file.metadata()?.len() > MAX_JOURNAL_SIZE {
+        transaction.rotated = true;
+        rotate_journal(&path, KEEP_JOURNAL_SIZE)?;
+    }
+    Ok(())
+}
+
+/// Remove the oldest commands from the journal until it is at most the given size,
+/// so it does not grow forever. The files they put in the trash are removed with them
+fn rotate_journal(path: &Path, keep_size: u64) -> Result<()> {
+    let entries = read_journal_at(path)?;
+    let lines = entries
+        .iter()
+        .map(|entry| Ok(serde_json::to_string(entry)?))
+        .collect::<Result<Vec<String>>>()?;
+
+    // only remove whole commands, so a command is undone completely or not at all,
+    // the last command is always kept
+    let mut start = entries.len();
+    let mut size = 0;
+    while start > 0 {
+        let transaction = entries[start - 1].transaction;
+        let first = entries[..start]
+            .iter()
+            .rposition(|entry| entry.transaction != transaction)
+            .map_or(0, |last| last + 1);
+        let command_size: u64 = lines[first..start]
+            .iter()
+            .map(|line| line.len() as u64 + 1)
+            .sum();
+        if start < entries.len() && size + command_size > keep_size {
+            break;
+        }
+        size += command_size;
+        start = first;
+    }
+    if start == 0 {
+        return Ok(());
+    }
+    debug!("Removing the oldest {start} entries from the journal");
+
+    let trash_dir = trash_dir()?;
+    for entry in &entries[..start] {
+        if let Operation::Move { to, .. } = &entry.operation {
+            if to.starts_with(&trash_dir) && to.is_file() {
+                debug!("Removing \"{}\" from the trash", to.display());
+                fs::remove_file(to)?;
+            }
+        }
+    }
+    write_lines(path, &lines[start..])
+}
+
+/// Read all entries from the journal, the oldest first
+pub fn read_journal() -> Result<Vec<JournalEntry>> {
+    read_journal_at(&journal_path()?)
+}
+
+fn read_journal_at(path: &Path) -> Result<Vec<JournalEntry>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(anyhow!("Could not read journal because of {err}")),
+    };
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).context("Could not read journal entry"))
+        .collect()
+}
+
+/// Replace the journal with the given entries, not in a dry run
+pub fn write_journal(entries: &[JournalEntry]) -> Result<()> {
+    if planner::is_dry_run() {
+        return Ok(());
+    }
+    let lines = entries
+        .iter()
+        .map(|entry| Ok(serde_json::to_string(entry)?))
+        .collect::<Result<Vec<String>>>()?;
+    write_lines(&journal_path()?, &lines)
+}
+
+fn write_lines(path: &Path, lines: &[String]) -> Result<()> {
+    let mut content = String::new();
+    for line in lines {
+        content.push_str(line);
+        content.push('\n');
+    }
+    fs::write(path, content)
+        .with_context(|| format!("Could not write journal at \"{}\"", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{lock, test_dir};
+
+    fn entry(transaction: u128, operation: Operation) -> JournalEntry {
+        JournalEntry {
+            transaction,
+            command: Some(format!("command {transaction}")),
+            time: SystemTime::now(),
+            operation,
+        }
+    }
+
+    #[test]
+    fn command_is_recorded_once() {
+        let _lock = lock();
+        let _ = fs::remove_file(journal_path().unwrap());
+        start_transaction(format!("music_manager add {}", "song.opus ".repeat(100)));
+        record(&Operation::CreateDir(PathBuf::from("/music/Artist")));
+        record(&Operation::CreateDir(PathBuf::from("/music/Artist/Album")));
+        start_transaction(String::from("music_manager check --fix"));
+        record(&Operation::RemoveDir(PathBuf::from("/music/Empty")));
+
+        let entries = read_journal().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].transaction, entries[1].transaction);
+        assert_ne!(entries[1].transaction, entries[2].transaction);
+        let command = entries[0].command.as_deref().unwrap();
+        assert_eq!(command.chars().count(), MAX_COMMAND_LENGTH + 3);
+        assert!(command.starts_with("music_manager add song.opus"));
+        assert!(command.ends_with("..."));
+        assert!(entries[1].command.is_none());
+        assert_eq!(
+            entries[2].command.as_deref(),
+            Some("music_manager check --fix")
+        );
+        assert_eq!(
+            entries[2].operation,
+            Operation::RemoveDir(PathBuf::from("/music/Empty"))
+        );
+    }
+
+    #[test]
+    fn nothing_is_recorded_in_a_dry_run() {
+        let _lock = lock();
+        let _ = fs::remove_file(journal_path().unwrap());
+        planner::set_dry_run(true);
+        record(&Operation::CreateDir(PathBuf::from("/music/Artist")));
+        planner::set_dry_run(false);
+        assert!(read_journal().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rotation_removes_whole_commands() {
+        let _lock = lock();
+        let dir = test_dir("rotation");
+        let path = dir.join("journal.jsonl");
+        // the first command put a replaced file in the trash
+        fs::create_dir_all(trash_dir().unwrap()).unwrap();
+        let trashed = trash_dir().unwrap().join("rotation.opus");
+        fs::write(&trashed, "music").unwrap();
+        let entries = [
+            entry(
+                1,
+                Operation::Move {
+                    from: dir.join("song.opus"),
+                    to: trashed.clone(),
+                },
+            ),
+            entry(1, Operation::CreateDir(dir.join("a"))),
+            entry(2, Operation::CreateDir(dir.join("b"))),
+            entry(2, Operation::CreateDir(dir.join("c"))),
+            entry(3, Operation::CreateDir(dir.join("d"))),
+        ];
+        let lines: Vec<String> = entries
+            .iter()
+            .map(|entry| serde_json::to_string(entry).unwrap())
+            .collect();
+        write_lines(&path, &lines).unwrap();
+        let size: u64 = lines[2..].iter().map(|line| line.len() as u64 + 1).sum();
+
+        // the second command does not fit completely, so it is removed too
+        rotate_journal(&path, size - 1).unwrap();
+        let left = read_journal_at(&path).unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].transaction, 3);
+        assert!(
+            !trashed.exists(),
+            "the trash of the removed commands is emptied"
+        );
+
+        // the last command is always kept
+        rotate_journal(&path, 0).unwrap();
+        assert_eq!(read_journal_at(&path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rotation_keeps_what_fits() {
+        let _lock = lock();
+        let dir = test_dir("rotation-fits");
+        let path = dir.join("journal.jsonl");
+        let lines: Vec<String> = (1..=4)
+            .map(|transaction| {
+                let operation = Operation::CreateDir(dir.join(transaction.to_string()));
+                serde_json::to_string(&entry(transaction, operation)).unwrap()
+            })
+            .collect();
+        write_lines(&path, &lines).unwrap();
+        let size: u64 = lines[1..].iter().map(|line| line.len() as u64 + 1).sum();
+
+        rotate_journal(&path, size).unwrap();
+        let left: Vec<u128> = read_journal_at(&path)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.transaction)
+            .collect();
+        assert_eq!(left, [2, 3, 4]);
+    }
+}
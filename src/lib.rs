@@ -1,30 +1,39 @@
 use std::{
     ffi::OsStr,
-    fs::{self, File},
-    os::unix::fs::FileExt,
+    fs,
     path::{Path, PathBuf},
 };
 
 use glob::{glob_with, MatchOptions, Pattern};
 
 use anyhow::{anyhow, Context, Result};
+use log::warn;
 
+use config::ConflictPolicy;
+use music_tag::{audio_hash, get_audio_quality};
+
+pub mod backend;
 pub mod category;
 pub mod commands;
 pub mod config;
+pub mod field;
+pub mod index;
+pub mod journal;
 pub mod music_tag;
 pub mod normalize;
+pub mod planner;
+pub mod progress;
+#[cfg(feature = "native-replaygain")]
+pub mod replaygain;
+pub mod subscription;
 pub mod tag;
+pub mod template;
+#[cfg(test)]
+mod test_util;
 
 /// Create a file with the given content
 pub fn create_file(path: &Path, content: String) -> Result<()> {
-    // create a file
-    let description_file = File::create(path)?;
-
-    // write to the file
-    description_file.write_at(content.as_bytes(), 0)?;
-
-    Ok(())
+    planner::create_file(path, &content)
 }
 
 /// Gives all the files in that are in a directory,
@@ -116,9 +125,13 @@ fn search(query: &str, content: Vec<String>) -> Vec<String> {
 }
 
 /// Move files to the target directory
-pub fn move_files(target_files: &Vec<PathBuf>, target_dir: &Path) -> Result<()> {
+pub fn move_files(
+    target_files: &Vec<PathBuf>,
+    target_dir: &Path,
+    conflict: ConflictPolicy,
+) -> Result<()> {
     for file in target_files {
-        if let Err(err) = move_file(&PathBuf::from(file), target_dir) {
+        if let Err(err) = move_file(&PathBuf::from(file), target_dir, conflict) {
             return Err(anyhow!(
                 "could not move {} because of {err}",
                 file.display()
@@ -128,12 +141,237 @@ pub fn move_files(target_files: &Vec<PathBuf>, target_dir: &Path) -> Result<()>
     Ok(())
 }
 
-/// Move a file to the target directory
-pub fn move_file(target_file: &Path, target_dir: &Path) -> Result<()> {
+/// Move a file to the target directory,
+/// gives the new path or none if the file was not moved because of a conflict
+pub fn move_file(
+    target_file: &Path,
+    target_dir: &Path,
+    conflict: ConflictPolicy,
+) -> Result<Option<PathBuf>> {
     let file_name = target_file
         .file_name()
         .ok_or(anyhow!("target_file is not a valid file"))?;
+    move_file_to(target_file, &target_dir.join(file_name), conflict)
+}
+
+/// Move a file to the target path,
+/// gives the new path or none if the file was not moved because of a conflict
+pub fn move_file_to(
+    target_file: &Path,
+    target: &Path,
+    conflict: ConflictPolicy,
+) -> Result<Option<PathBuf>> {
+    let mut target = target.to_path_buf();
+    if target == target_file {
+        return Ok(Some(target));
+    }
+
+    if planner::file_exists(&target) {
+        match resolve_conflict(target_file, &target, conflict)? {
+            Some(path) => target = path,
+            None => return Ok(None),
+        }
+    }
+    // a replaced file is moved to the trash in the same command, so undo brings it back
+    if planner::file_exists(&target) {
+        planner::rename(&target, &trash_path(&target)?)?;
+    }
 
     // move the file
-    fs::rename(target_file, target_dir.join(file_name)).context("Rename failed")
+    planner::rename(target_file, &target)?;
+    Ok(Some(target))
+}
+
+/// Decide where a file goes when the target already exists,
+/// gives none if the file should not be moved
+fn resolve_conflict(
+    file: &Path,
+    target: &Path,
+    conflict: ConflictPolicy,
+) -> Result<Option<PathBuf>> {
+    match conflict {
+        ConflictPolicy::Skip => {
+            warn!(
+                "\"{}\" already exists, skipping \"{}\"",
+                target.display(),
+                file.display()
+            );
+            Ok(None)
+        }
+        ConflictPolicy::Overwrite => {
+            warn!("Overwriting \"{}\"", target.display());
+            Ok(Some(target.to_path_buf()))
+        }
+        ConflictPolicy::Rename => Ok(Some(free_path(target))),
+        ConflictPolicy::KeepBest => {
+            // a planned move has no file to compare yet
+            if !target.exists() {
+                return Ok(Some(free_path(target)));
+            }
+            let quality = get_audio_quality(file)?;
+            let existing = get_audio_quality(target)?;
+            if quality > existing {
+                warn!(
+                    "Replacing \"{}\" with the better quality \"{}\"",
+                    target.display(),
+                    file.display()
+                );
+                Ok(Some(target.to_path_buf()))
+            } else {
+                warn!(
+                    "\"{}\" already exists with the same or better quality, skipping \"{}\"",
+                    target.display(),
+                    file.display()
+                );
+                Ok(None)
+            }
+        }
+        ConflictPolicy::CompareAudio => {
+            if target.exists() && audio_hash(file)? == audio_hash(target)? {
+                warn!(
+                    "\"{}\" already exists with the same audio, skipping \"{}\"",
+                    target.display(),
+                    file.display()
+                );
+                return Ok(None);
+            }
+            Ok(Some(free_path(target)))
+        }
+    }
+}
+
+/// Where a replaced file is kept in the trash, the trash is made if it does not exist
+fn trash_path(file: &Path) -> Result<PathBuf> {
+    let trash_dir = journal::trash_dir()?;
+    // like the journal, the trash is not part of the plan
+    if !planner::is_dry_run() {
+        fs::create_dir_all(&trash_dir).context("Could not make the trash directory")?;
+    }
+    let file_name = file.file_name().ok_or(anyhow!("Not a file"))?;
+    let path = trash_dir.join(file_name);
+    match planner::file_exists(&path) {
+        true => Ok(free_path(&path)),
+        false => Ok(path),
+    }
+}
+
+/// Add a number to the file name until there is no file with that name
+fn free_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|number| path.with_file_name(format!("{stem} ({number}){extension}")))
+        .find(|path| !planner::file_exists(path))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{lock, test_dir, wav};
+
+    /// A short mp3 file, lossy so a wav file is better
+    fn mp3(path: &Path) -> PathBuf {
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x64];
+        frame.resize(417, 0);
+        fs::write(path, frame.repeat(20)).unwrap();
+        path.to_path_buf()
+    }
+
+    fn resolve(file: &Path, target: &Path, conflict: ConflictPolicy) -> Option<PathBuf> {
+        resolve_conflict(file, target, conflict).unwrap()
+    }
+
+    #[test]
+    fn skip_and_overwrite() {
+        let _lock = lock();
+        let dir = test_dir("conflict");
+        let file = wav(&dir.join("new.wav"), 1);
+        let target = wav(&dir.join("song.wav"), 2);
+
+        assert_eq!(resolve(&file, &target, ConflictPolicy::Skip), None);
+        assert_eq!(
+            resolve(&file, &target, ConflictPolicy::Overwrite),
+            Some(target)
+        );
+    }
+
+    #[test]
+    fn rename_to_free_name() {
+        let _lock = lock();
+        let dir = test_dir("conflict-rename");
+        let file = wav(&dir.join("new.wav"), 1);
+        let target = wav(&dir.join("song.wav"), 2);
+
+        assert_eq!(
+            resolve(&file, &target, ConflictPolicy::Rename),
+            Some(dir.join("song (1).wav"))
+        );
+        wav(&dir.join("song (1).wav"), 3);
+        assert_eq!(
+            resolve(&file, &target, ConflictPolicy::Rename),
+            Some(dir.join("song (2).wav"))
+        );
+        // without an extension the number is at the end
+        assert_eq!(free_path(&dir.join("cover")), dir.join("cover (1)"));
+    }
+
+    #[test]
+    fn keep_best_quality() {
+        let _lock = lock();
+        let dir = test_dir("conflict-best");
+        let lossless = wav(&dir.join("song.wav"), 1);
+        let lossy = mp3(&dir.join("song.mp3"));
+        let same = wav(&dir.join("same.wav"), 1);
+
+        assert_eq!(
+            resolve(&lossless, &lossy, ConflictPolicy::KeepBest),
+            Some(lossy.clone())
+        );
+        assert_eq!(resolve(&lossy, &lossless, ConflictPolicy::KeepBest), None);
+        // the same quality is not better
+        assert_eq!(resolve(&same, &lossless, ConflictPolicy::KeepBest), None);
+    }
+
+    #[test]
+    fn compare_audio() {
+        let _lock = lock();
+        let dir = test_dir("conflict-audio");
+        let target = wav(&dir.join("song.wav"), 1);
+        let same = wav(&dir.join("same.wav"), 1);
+        let other = wav(&dir.join("other.wav"), 2);
+
+        assert_eq!(resolve(&same, &target, ConflictPolicy::CompareAudio), None);
+        assert_eq!(
+            resolve(&other, &target, ConflictPolicy::CompareAudio),
+            Some(dir.join("song (1).wav"))
+        );
+    }
+
+    #[test]
+    fn planned_target_in_dry_run() {
+        let _lock = lock();
+        let dir = test_dir("conflict-planned");
+        let first = wav(&dir.join("first.wav"), 1);
+        let second = wav(&dir.join("second.wav"), 2);
+        let target = dir.join("song.wav");
+        planner::set_dry_run(true);
+        planner::rename(&first, &target).unwrap();
+
+        let renamed = resolve(&second, &target, ConflictPolicy::Rename);
+        // there is no file yet to compare the quality or audio of
+        let best = resolve(&second, &target, ConflictPolicy::KeepBest);
+        let audio = resolve(&second, &target, ConflictPolicy::CompareAudio);
+        let moved = move_file_to(&second, &target, ConflictPolicy::Rename).unwrap();
+        planner::set_dry_run(false);
+
+        assert_eq!(renamed, Some(dir.join("song (1).wav")));
+        assert_eq!(best, Some(dir.join("song (1).wav")));
+        assert_eq!(audio, Some(dir.join("song (1).wav")));
+        assert_eq!(moved, Some(dir.join("song (1).wav")));
+        assert!(!target.exists() && first.exists() && second.exists());
+    }
 }
@@ -1,12 +1,15 @@
-use std::env::current_dir;
+use std::{
+    env::{self, current_dir},
+    process::exit,
+};
 
 use clap::Parser;
 use log::{self, info};
 use simplelog::{LevelFilter, TermLogger};
 
-use cli::{Cli, Commands};
+use cli::{CheckFormat, Cli, Commands, Conflict, DupeMatch, IndexCommands, KeepRule, TagsCommands};
 use music_manager::commands::*;
-use music_manager::tag;
+use music_manager::{config::ConflictPolicy, journal, planner, progress, tag};
 
 use anyhow::Result;
 
@@ -16,33 +19,50 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
     let mut log_config = simplelog::ConfigBuilder::new();
     let mut quiet = false;
-    TermLogger::init(
-        if cli.quiet {
-            quiet = true;
-            LevelFilter::Off
-        } else {
-            match cli.loglevel {
-                0 => {
-                    quiet = true;
-                    LevelFilter::Off
-                }
-                1 => LevelFilter::Error,
-                2 => LevelFilter::Warn,
-                3 => LevelFilter::Info,
-                4 => LevelFilter::Debug,
-                _ => LevelFilter::Trace,
+    let level = if cli.quiet {
+        quiet = true;
+        LevelFilter::Off
+    } else {
+        match cli.loglevel {
+            0 => {
+                quiet = true;
+                LevelFilter::Off
             }
-        },
-        log_config.set_time_level(LevelFilter::Off).build(),
-        simplelog::TerminalMode::Stdout,
-        simplelog::ColorChoice::Auto,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            4 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    };
+    progress::init_logger(
+        TermLogger::new(
+            level,
+            log_config.set_time_level(LevelFilter::Off).build(),
+            // keep stdout clean for output that is read by other programs
+            if machine_output(&cli.command) {
+                simplelog::TerminalMode::Stderr
+            } else {
+                simplelog::TerminalMode::Stdout
+            },
+            simplelog::ColorChoice::Auto,
+        ),
+        level,
     )
     .unwrap();
 
-    match &cli.command {
+    planner::set_dry_run(cli.dry_run);
+    journal::start_transaction(env::args().collect::<Vec<String>>().join(" "));
+    let mut exit_code = 0;
+
+    let result = match &cli.command {
         // download YouTube music and move in a category directory
-        Commands::Download { url, category } => down::download(url, category, &quiet)
-        ,
+        Commands::Download {
+            url,
+            category,
+            since,
+        } => down::download(url, category, since, &quiet).map(|_| ()),
+        Commands::Sync { category } => sync::sync(category, &quiet),
         // print all categories with a description
         Commands::Categories { category } => cat::category(category),
 
@@ -56,6 +76,8 @@ fn main() -> Result<()> {
             category,
             force,
             singles,
+            conflict,
+            jobs,
         } => {
             // check if we get files
             if files.is_empty() {
@@ -63,16 +85,130 @@ fn main() -> Result<()> {
                 return Ok(());
             }
 
-            add::add(files, category, &quiet, force, &!singles)
+            find_category_or_default(category).and_then(|category_dir| {
+                add::add(
+                    files,
+                    &category_dir,
+                    &quiet,
+                    force,
+                    &!singles,
+                    conflict.map(conflict_policy),
+                    *jobs,
+                )
+            })
         }
         Commands::Check {
             category,
             tags_path,
-        } => check::check(category, tags_path),
+            fix,
+            format,
+            jobs,
+        } => {
+            let format = match format {
+                CheckFormat::Text => check::ReportFormat::Text,
+                CheckFormat::Json => check::ReportFormat::Json,
+                CheckFormat::Junit => check::ReportFormat::Junit,
+            };
+            check::check(category, tags_path, fix, &quiet, format, *jobs).map(|issues| {
+                // 1 is for errors
+                if issues > 0 {
+                    exit_code = 2;
+                }
+            })
+        }
         Commands::Tag {
             category,
             files,
             force,
-        } => tag::tag(current_dir().unwrap(), files, category, &quiet, force),
+            conflict,
+        } => find_category_or_default(category).and_then(|category_dir| {
+            tag::tag(
+                current_dir().unwrap(),
+                files,
+                &category_dir,
+                &quiet,
+                force,
+                conflict.map(conflict_policy),
+            )
+        }),
+        Commands::Search { query, json } => search::search(query, json),
+        Commands::Dupes {
+            by,
+            keep,
+            formats,
+            categories,
+            move_to,
+            json,
+        } => dupes::dupes(
+            &by.iter().copied().map(dupe_match).collect::<Vec<_>>(),
+            &keep.iter().copied().map(keep_rule).collect::<Vec<_>>(),
+            formats,
+            categories,
+            move_to,
+            json,
+        ),
+        Commands::Rename { target, template } => rename::rename(target, template),
+        Commands::Tags { command } => match command {
+            TagsCommands::Get { paths, field } => tags::get(paths, field),
+            TagsCommands::Set {
+                paths,
+                value,
+                from_path,
+            } => tags::set(paths, value, from_path),
+            TagsCommands::Remove { paths, field } => tags::remove(paths, field),
+        },
+        Commands::Undo { count, commands } => undo::undo(count, commands),
+        Commands::Index { command } => match command {
+            IndexCommands::Rebuild => index::rebuild(),
+            IndexCommands::Update => index::update(),
+        },
+    };
+
+    if cli.dry_run && !machine_output(&cli.command) {
+        planner::print_plan();
+    }
+    result?;
+    if exit_code != 0 {
+        exit(exit_code);
+    }
+    Ok(())
+}
+
+/// If the command prints output meant to be read by other programs
+fn machine_output(command: &Commands) -> bool {
+    matches!(
+        command,
+        Commands::Search { json: true, .. }
+            | Commands::Dupes { json: true, .. }
+            | Commands::Check {
+                format: CheckFormat::Json | CheckFormat::Junit,
+                ..
+            }
+    )
+}
+
+fn dupe_match(dupe_match: DupeMatch) -> dupes::DupeMatch {
+    match dupe_match {
+        DupeMatch::Tags => dupes::DupeMatch::Tags,
+        DupeMatch::Musicbrainz => dupes::DupeMatch::Musicbrainz,
+        DupeMatch::Audio => dupes::DupeMatch::Audio,
+    }
+}
+
+fn keep_rule(keep_rule: KeepRule) -> dupes::KeepRule {
+    match keep_rule {
+        KeepRule::Format => dupes::KeepRule::Format,
+        KeepRule::Bitrate => dupes::KeepRule::Bitrate,
+        KeepRule::Category => dupes::KeepRule::Category,
+    }
+}
+
+fn conflict_policy(conflict: Conflict) -> ConflictPolicy {
+    match conflict {
+        Conflict::Skip => ConflictPolicy::Skip,
+        Conflict::Overwrite => ConflictPolicy::Overwrite,
+        Conflict::Rename => ConflictPolicy::Rename,
+        Conflict::KeepBest => ConflictPolicy::KeepBest,
+        Conflict::CompareAudio => ConflictPolicy::CompareAudio,
     }
 }
@@ -1,82 +1,987 @@
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 
-use lofty::file::TaggedFileExt;
+use lofty::config::WriteOptions;
+use lofty::file::{AudioFile, FileType, TaggedFile, TaggedFileExt};
+use lofty::probe::Probe;
 use lofty::read_from_path;
-use lofty::tag::{Accessor, ItemKey, Tag, TagExt, TagType};
+use lofty::tag::{Accessor, ItemKey, ItemValue, Tag, TagExt, TagItem, TagType};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{AlbumArtistFallback, Config, DEFAULT_ALBUM_ARTIST_FALLBACK};
+use crate::planner::{read_chunks, Fnv};
+use crate::read_dir;
+use crate::template::{album_dir, is_disc_dir};
+
+/// The album artist of albums with music of different artists
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+/// The loudness replaygain 2.0 gains are relative to, in LUFS
+const REPLAYGAIN_REFERENCE: f64 = -18.0;
+/// The loudness the R128 gains of opus files are relative to, in LUFS
+const R128_REFERENCE: f64 = -23.0;
+
+/// Reads music tags, a missing album artist is inferred with the fallback rules.
+/// It remembers the artists of the albums it read, so it should only be used
+/// while the files don't change, like for one command or one refresh of the index
+pub struct TagReader {
+    fallback: Vec<AlbumArtistFallback>,
+    /// The music of an album, by album directory and album title
+    albums: Mutex<BTreeMap<(PathBuf, String), AlbumMusic>>,
+}
+
+/// What is in the tags of the music of an album
+#[derive(Clone, Default)]
+struct AlbumMusic {
+    artists: BTreeSet<String>,
+    /// None for music without an album artist tag
+    album_artists: BTreeSet<Option<String>>,
+    /// The highest disc number, 0 if no music has one
+    highest_disc: u32,
+}
+
+impl AlbumMusic {
+    /// The album artist of the music that has the tag, if they all have the same one
+    fn album_artist(&self) -> Option<&str> {
+        let mut album_artists = self.album_artists.iter().flatten();
+        let album_artist = album_artists.next()?;
+        match album_artists.next() {
+            Some(_) => None,
+            None => Some(album_artist),
+        }
+    }
+
+    /// Music of different artists without one album artist,
+    /// music without an album artist tag does not make it a compilation
+    fn is_compilation(&self) -> bool {
+        self.artists.len() > 1 && self.album_artist().is_none()
+    }
+}
 
 /// Type to store music albums and songs
-#[derive(Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Eq, Ord, PartialEq, PartialOrd, Clone, Debug, Default, Deserialize, Serialize)]
 pub struct MusicTag {
     pub song_title: String,
     pub artist_name: String,
     pub album_title: String,
     pub album_artist: String,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+    /// The full recording date, like 1997-05-21
+    pub date: Option<String>,
+    pub track: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc: Option<u32>,
+    pub disc_total: Option<u32>,
+    pub composer: Option<String>,
+    pub musicbrainz: MusicBrainzIds,
     pub replaygain: bool,
+    /// If there is a picture in the tags, like the album cover
+    pub embedded_art: bool,
+    /// If the album has music of different artists
+    pub compilation: bool,
+    /// If other music of the album is on a disc after the first,
+    /// for albums without disc totals
+    #[serde(default)]
+    pub multi_disc: bool,
+    pub properties: AudioProperties,
+    /// The fields that were not in the tags, and how they were found
+    pub inferred: BTreeMap<String, String>,
 }
 
-/// Get a music tag form a file
-pub fn get_music_tag(music_file: &Path) -> Result<MusicTag> {
-    let tag = get_tag(music_file)?;
+/// The MusicBrainz identifiers in the tags
+#[derive(Eq, Ord, PartialEq, PartialOrd, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MusicBrainzIds {
+    pub recording: Option<String>,
+    pub track: Option<String>,
+    pub release: Option<String>,
+    pub release_group: Option<String>,
+    pub artist: Option<String>,
+    pub release_artist: Option<String>,
+}
 
-    // Song title
-    let Some(title) = tag.title() else {
-        return Err(anyhow!("could not find title tag"));
-    };
-    // artist name
-    let Some(artist) = tag.artist() else {
-        return Err(anyhow!("could not find artist tag"));
-    };
-    // album title
-    let Some(album) = tag.album() else {
-        return Err(anyhow!("could not find album tag"));
-    };
-    // album artist
-    let Some(album_artist) = tag.get(&ItemKey::AlbumArtist) else {
-        return Err(anyhow!("could not find album artist tag"));
+/// The properties of the audio itself
+#[derive(Eq, Ord, PartialEq, PartialOrd, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AudioProperties {
+    pub duration: Duration,
+    /// In kbps
+    pub bitrate: Option<u32>,
+    /// In Hz
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u8>,
+    pub channels: Option<u8>,
+    /// The file type, like opus or flac
+    pub codec: String,
+}
+
+impl TagReader {
+    pub fn new(fallback: Vec<AlbumArtistFallback>) -> TagReader {
+        TagReader {
+            fallback,
+            albums: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// A reader with the fallback rules of the config
+    pub fn from_config(config: &Config) -> TagReader {
+        TagReader::new(
+            config
+                .album_artist_fallback
+                .clone()
+                .unwrap_or(DEFAULT_ALBUM_ARTIST_FALLBACK.to_vec()),
+        )
+    }
+
+    /// Forget the music of the albums, for when the files were changed
+    pub fn clear(&self) {
+        self.albums.lock().unwrap().clear();
+    }
+
+    /// Get a music tag from a file
+    pub fn read(&self, music_file: &Path) -> Result<MusicTag> {
+        let tagged_file = read_from_path(music_file)?;
+        let tag = primary_tag(&tagged_file, music_file)?;
+
+        // Song title
+        let Some(title) = tag.title() else {
+            return Err(anyhow!("could not find title tag"));
+        };
+        // artist name
+        let Some(artist) = tag.artist() else {
+            return Err(anyhow!("could not find artist tag"));
+        };
+        // album title
+        let Some(album) = tag.album() else {
+            return Err(anyhow!("could not find album tag"));
+        };
+        // album artist
+        let mut inferred = BTreeMap::new();
+        let album_artist = match tag.get_string(&ItemKey::AlbumArtist) {
+            Some(album_artist) => album_artist.to_string(),
+            None => {
+                let (album_artist, source) = self
+                    .infer_album_artist(music_file, tag, &album, &artist)
+                    .ok_or(anyhow!("could not find album artist tag"))?;
+                inferred.insert(String::from("album_artist"), source.to_string());
+                album_artist
+            }
+        };
+        let text = |key: ItemKey| tag.get_string(&key).map(|value| value.to_string());
+
+        // compilation flag, or different artists without one album artist
+        let compilation = match tag.get_string(&ItemKey::FlagCompilation) {
+            Some(flag) => matches!(flag.trim(), "1" | "true"),
+            None => {
+                let compilation = self.album_music(music_file, &album).is_compilation();
+                if compilation {
+                    inferred.insert(
+                        String::from("compilation"),
+                        String::from("different artists in the album"),
+                    );
+                }
+                compilation
+            }
+        };
+        // the first disc of an album only knows it has more discs from the other discs
+        let multi_disc = tag.disk_total().is_none()
+            && tag.disk() == Some(1)
+            && self.album_music(music_file, &album).highest_disc > 1;
+        let properties = tagged_file.properties();
+        Ok(MusicTag {
+            song_title: title.to_string(),
+            album_artist,
+            album_title: album.to_string(),
+            artist_name: artist.to_string(),
+            genre: tag.genre().map(|genre| genre.to_string()),
+            year: tag.year(),
+            date: text(ItemKey::RecordingDate),
+            track: tag.track(),
+            track_total: tag.track_total(),
+            disc: tag.disk(),
+            disc_total: tag.disk_total(),
+            composer: text(ItemKey::Composer),
+            musicbrainz: MusicBrainzIds {
+                recording: text(ItemKey::MusicBrainzRecordingId),
+                track: text(ItemKey::MusicBrainzTrackId),
+                release: text(ItemKey::MusicBrainzReleaseId),
+                release_group: text(ItemKey::MusicBrainzReleaseGroupId),
+                artist: text(ItemKey::MusicBrainzArtistId),
+                release_artist: text(ItemKey::MusicBrainzReleaseArtistId),
+            },
+            replaygain: tag_has_replaygain_tags(tag, tagged_file.file_type()),
+            embedded_art: tagged_file.tags().iter().any(|tag| tag.picture_count() > 0),
+            compilation,
+            multi_disc,
+            properties: AudioProperties {
+                duration: properties.duration(),
+                bitrate: properties.audio_bitrate().or(properties.overall_bitrate()),
+                sample_rate: properties.sample_rate(),
+                bit_depth: properties.bit_depth(),
+                channels: properties.channels(),
+                codec: codec_name(tagged_file.file_type()),
+            },
+            inferred,
+        })
+    }
+
+    /// Find the album artist with the fallback rules,
+    /// gives the album artist and how it was found
+    fn infer_album_artist(
+        &self,
+        music_file: &Path,
+        tag: &Tag,
+        album: &str,
+        artist: &str,
+    ) -> Option<(String, &'static str)> {
+        self.fallback.iter().find_map(|rule| match rule {
+            AlbumArtistFallback::SortName => tag
+                .get_string(&ItemKey::AlbumArtistSortOrder)
+                .map(|sort_name| (unsort_name(sort_name), "album artist sort name")),
+            AlbumArtistFallback::VariousArtists => {
+                let album = self.album_music(music_file, album);
+                // music without the tag in an album with one album artist is part of it
+                match album.album_artist() {
+                    Some(album_artist) => Some((
+                        album_artist.to_string(),
+                        "album artist of the other music in the album",
+                    )),
+                    None => (album.artists.len() > 1).then(|| {
+                        (
+                            VARIOUS_ARTISTS.to_string(),
+                            "different artists in the album",
+                        )
+                    }),
+                }
+            }
+            AlbumArtistFallback::TrackArtist => Some((artist.to_string(), "track artist")),
+        })
+    }
+
+    /// The music with the album title in the album directory of a file,
+    /// with the music in the directories of the discs
+    fn album_music(&self, music_file: &Path, album: &str) -> AlbumMusic {
+        let Some(dir) = album_dir(music_file) else {
+            return AlbumMusic::default();
+        };
+        let key = (dir.to_path_buf(), album.to_string());
+        if let Some(music) = self.albums.lock().unwrap().get(&key) {
+            return music.clone();
+        }
+        let mut files = read_dir(dir, None).unwrap_or_default();
+        let disc_dirs: Vec<PathBuf> = files.extract_if(.., |file| is_disc_dir(file)).collect();
+        for disc_dir in disc_dirs {
+            files.extend(read_dir(&disc_dir, None).unwrap_or_default());
+        }
+        let mut music = AlbumMusic::default();
+        for file in files {
+            let Ok(tagged_file) = read_from_path(&file) else {
+                continue;
+            };
+            let Ok(tag) = primary_tag(&tagged_file, &file) else {
+                continue;
+            };
+            if tag.album().as_deref() == Some(album) {
+                if let Some(artist) = tag.artist() {
+                    music.artists.insert(artist.to_string());
+                }
+                music
+                    .album_artists
+                    .insert(tag.get_string(&ItemKey::AlbumArtist).map(str::to_string));
+                music.highest_disc = music.highest_disc.max(tag.disk().unwrap_or(0));
+            }
+        }
+        self.albums.lock().unwrap().insert(key, music.clone());
+        music
+    }
+}
+
+/// Turn a sort name back into a name, "Beatles, The" gives "The Beatles"
+fn unsort_name(sort_name: &str) -> String {
+    match sort_name.split_once(", ") {
+        Some((last, first)) if !first.contains(", ") => format!("{first} {last}"),
+        _ => sort_name.to_string(),
+    }
+}
+
+/// Check if a music file has the track and album gain tags of its format
+pub fn file_has_replaygain_tags(music_file: &Path) -> Result<bool> {
+    let tagged_file = read_from_path(music_file)?;
+    Ok(tag_has_replaygain_tags(
+        primary_tag(&tagged_file, music_file)?,
+        tagged_file.file_type(),
+    ))
+}
+
+/// Opus files need the R128 gains, other formats the replaygain gains
+pub fn tag_has_replaygain_tags(tag: &Tag, file_type: FileType) -> bool {
+    let keys = match file_type {
+        FileType::Opus => [
+            ItemKey::from_key(TagType::VorbisComments, "R128_TRACK_GAIN"),
+            ItemKey::from_key(TagType::VorbisComments, "R128_ALBUM_GAIN"),
+        ],
+        _ => [ItemKey::ReplayGainTrackGain, ItemKey::ReplayGainAlbumGain],
     };
-    Ok(MusicTag {
-        song_title: title.to_string(),
-        album_artist: album_artist
-            .clone()
-            .into_value()
-            .into_string()
+    keys.iter().all(|key| tag.contains(key))
+}
+
+/// The loudness and peak of some audio
+#[derive(Clone, Copy)]
+pub struct Gain {
+    /// In LUFS
+    pub loudness: f64,
+    /// 1.0 is full scale
+    pub peak: f64,
+}
+
+/// Write the replaygain tags, or the R128 tags for opus files
+pub fn write_replaygain_tags(file: &Path, track: &Gain, album: &Gain) -> Result<()> {
+    let mut tagged_file =
+        read_from_path(file).with_context(|| format!("Could not read \"{}\"", file.display()))?;
+    let file_type = tagged_file.file_type();
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file.primary_tag_mut().unwrap();
+    match file_type {
+        // opus players use the R128 gains, in 1/256 dB
+        FileType::Opus => {
+            let r128 = |gain: &Gain| {
+                (((R128_REFERENCE - gain.loudness) * 256.0).round() as i16).to_string()
+            };
+            // lofty has no item keys for them, insert_text would leave them out
+            let item = |key: &str, value| {
+                TagItem::new(
+                    ItemKey::from_key(TagType::VorbisComments, key),
+                    ItemValue::Text(value),
+                )
+            };
+            tag.insert_unchecked(item("R128_TRACK_GAIN", r128(track)));
+            tag.insert_unchecked(item("R128_ALBUM_GAIN", r128(album)));
+        }
+        _ => {
+            let gain = |gain: &Gain| format!("{:.2} dB", REPLAYGAIN_REFERENCE - gain.loudness);
+            let peak = |gain: &Gain| format!("{:.6}", gain.peak);
+            tag.insert_text(ItemKey::ReplayGainTrackGain, gain(track));
+            tag.insert_text(ItemKey::ReplayGainTrackPeak, peak(track));
+            tag.insert_text(ItemKey::ReplayGainAlbumGain, gain(album));
+            tag.insert_text(ItemKey::ReplayGainAlbumPeak, peak(album));
+        }
+    }
+    tag.save_to_path(file, WriteOptions::default())
+        .with_context(|| format!("Could not write the tags of \"{}\"", file.display()))?;
+    info!("Wrote replaygain tags to \"{}\"", file.display());
+    Ok(())
+}
+
+/// Only the album title in the tags of a file, without reading the other tags
+pub fn get_album_title(music_file: &Path) -> Option<String> {
+    let tagged_file = read_from_path(music_file).ok()?;
+    let album = primary_tag(&tagged_file, music_file).ok()?.album()?;
+    Some(album.to_string())
+}
+
+fn primary_tag<'a>(tagged_file: &'a TaggedFile, music_file: &Path) -> Result<&'a Tag> {
+    match tagged_file.primary_tag() {
+        Some(tag) => Ok(tag),
+        None => tagged_file.first_tag().ok_or(anyhow!(
+            "No tag found in file : \"{}\"",
+            music_file.display()
+        )),
+    }
+}
+
+/// The name of a file type, like opus or flac
+fn codec_name(file_type: FileType) -> String {
+    match file_type {
+        FileType::Custom(name) => name.to_lowercase(),
+        file_type => format!("{file_type:?}").to_lowercase(),
+    }
+}
+
+/// If a codec keeps all of the audio, the codec is the name from the audio properties
+pub fn is_lossless(codec: &str) -> bool {
+    matches!(codec, "flac" | "wav" | "aiff" | "ape" | "wavpack")
+}
+
+/// How good the audio of a file is, lossless is always better than lossy
+#[derive(Eq, Ord, PartialEq, PartialOrd, Clone, Copy, Debug)]
+pub struct AudioQuality {
+    pub lossless: bool,
+    /// In kbps
+    pub bitrate: u32,
+}
+
+/// Get the audio quality of a music file
+pub fn get_audio_quality(music_file: &Path) -> Result<AudioQuality> {
+    let tagged_file = read_from_path(music_file)?;
+    let lossless = is_lossless(&codec_name(tagged_file.file_type()));
+    let properties = tagged_file.properties();
+    Ok(AudioQuality {
+        lossless,
+        bitrate: properties
+            .audio_bitrate()
+            .or(properties.overall_bitrate())
             .unwrap_or_default(),
-        album_title: album.to_string(),
-        artist_name: artist.to_string(),
-        replaygain: tag_has_replaygain_tags(tag),
     })
 }
 
-/// Check if a music file has replaygain tags
-pub fn file_has_replaygain_tags(music_file: &Path) -> Result<bool> {
-    let tag = get_tag(music_file)?;
-    Ok(tag_has_replaygain_tags(tag))
+/// Hash the audio of a music file without its tags,
+/// so files with the same audio but different tags have the same hash.
+/// Only the parts of the file with the audio are read, in chunks
+pub fn audio_hash(music_file: &Path) -> Result<u64> {
+    let file_type = Probe::open(music_file)?
+        .guess_file_type()?
+        .file_type()
+        .ok_or(anyhow!("Unknown file type of \"{}\"", music_file.display()))?;
+    let mut file = BufReader::new(File::open(music_file)?);
+    let mut hasher = Fnv::default();
+    match file_type {
+        FileType::Wav => hash_riff_chunks(&mut file, false, &[b"fmt ", b"data"], &mut hasher)?,
+        FileType::Aiff => hash_riff_chunks(&mut file, true, &[b"COMM", b"SSND"], &mut hasher)?,
+        FileType::Mp4 => hash_mp4_data(&mut file, &mut hasher)?,
+        FileType::Opus | FileType::Vorbis | FileType::Speex => {
+            hash_ogg_audio(&mut file, &mut hasher)?
+        }
+        // the tags are before and after the audio
+        _ => {
+            let (start, end) = audio_range(&mut file, file_type)?;
+            hash_range(&mut file, start, end.saturating_sub(start), &mut hasher)?;
+        }
+    }
+    Ok(hasher.finish())
 }
 
-pub fn tag_has_replaygain_tags(tag: Tag) -> bool {
-    let result = tag.contains(&ItemKey::ReplayGainTrackGain)
-        || tag.contains(&ItemKey::from_key(
-            TagType::VorbisComments,
-            "R128_TRACK_GAIN", // for opus and ogg types
-        ));
-    result
+/// Hash a part of a file
+fn hash_range(file: &mut BufReader<File>, start: u64, length: u64, hasher: &mut Fnv) -> Result<()> {
+    file.seek(SeekFrom::Start(start))?;
+    read_chunks(&mut file.take(length), |chunk| {
+        hasher.write(chunk);
+        Ok(())
+    })
 }
 
-fn get_tag(music_file: &Path) -> Result<Tag> {
-    let tagged_file = read_from_path(music_file)?;
-    let tag = match tagged_file.primary_tag() {
-        Some(tag) => tag,
-        None => match tagged_file.first_tag() {
-            Some(tag) => tag,
-            None => {
-                return Err(anyhow!(
-                    "No tag found in file : \"{}\"",
-                    music_file.display()
-                ))
+/// Read some bytes, none at the end of the file
+fn read_header<const N: usize>(file: &mut BufReader<File>) -> Result<Option<[u8; N]>> {
+    let mut header = [0; N];
+    match file.read_exact(&mut header) {
+        Ok(()) => Ok(Some(header)),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Hash the chunks with the audio of a wav or aiff file, the tags are in other chunks
+fn hash_riff_chunks(
+    file: &mut BufReader<File>,
+    big_endian: bool,
+    audio_chunks: &[&[u8; 4]],
+    hasher: &mut Fnv,
+) -> Result<()> {
+    // the header, with the size of the file that changes with the tags
+    let mut position = 12;
+    file.seek(SeekFrom::Start(position))?;
+    while let Some(header) = read_header::<8>(file)? {
+        let size = [header[4], header[5], header[6], header[7]];
+        let size = u64::from(match big_endian {
+            true => u32::from_be_bytes(size),
+            false => u32::from_le_bytes(size),
+        });
+        if audio_chunks.iter().any(|id| header[..4] == id[..]) {
+            hasher.write(&header[..4]);
+            hash_range(file, position + 8, size, hasher)?;
+        }
+        // chunks are padded to an even size
+        position += 8 + size + size % 2;
+        file.seek(SeekFrom::Start(position))?;
+    }
+    Ok(())
+}
+
+/// Hash the media data boxes of an mp4 file, the tags are in the movie box
+fn hash_mp4_data(file: &mut BufReader<File>, hasher: &mut Fnv) -> Result<()> {
+    let length = file.get_ref().metadata()?.len();
+    let mut position = 0;
+    while let Some(header) = read_header::<8>(file)? {
+        let (start, size) = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            // the box goes on until the end of the file
+            0 => (8, length - position),
+            // the size is in 64 bits after the type
+            1 => match read_header::<8>(file)? {
+                Some(size) => (16, u64::from_be_bytes(size)),
+                None => break,
+            },
+            size => (8, u64::from(size)),
+        };
+        if size < start {
+            return Err(anyhow!("Invalid mp4 box at {position}"));
+        }
+        if &header[4..] == b"mdat" {
+            hash_range(file, position + start, size - start, hasher)?;
+        }
+        position += size;
+        file.seek(SeekFrom::Start(position))?;
+    }
+    Ok(())
+}
+
+/// Hash the pages with the audio of an ogg file, the tags are in the header pages before it.
+/// The header pages have no granule position,
+/// only the content of the pages is hashed because their numbers change with the tags
+fn hash_ogg_audio(file: &mut BufReader<File>, hasher: &mut Fnv) -> Result<()> {
+    let mut audio = false;
+    let mut content = vec![];
+    while let Some(header) = read_header::<27>(file)? {
+        if &header[..4] != b"OggS" {
+            return Err(anyhow!("Invalid ogg page"));
+        }
+        let granule = u64::from_le_bytes(header[6..14].try_into()?);
+        let mut segments = vec![0; usize::from(header[26])];
+        file.read_exact(&mut segments)?;
+        let size = segments.iter().map(|segment| usize::from(*segment)).sum();
+        content.resize(size, 0);
+        file.read_exact(&mut content)?;
+        // the first page that ends an audio packet, or the pages after it
+        audio = audio || (granule != 0 && granule != u64::MAX);
+        if audio {
+            hasher.write(&header[6..14]);
+            hasher.write(&content);
+        }
+    }
+    Ok(())
+}
+
+/// Where the audio is in a file with id3v2 tags or flac metadata before it,
+/// and id3v1 or ape tags after it
+fn audio_range(file: &mut BufReader<File>, file_type: FileType) -> Result<(u64, u64)> {
+    let mut start = 0;
+    file.seek(SeekFrom::Start(0))?;
+    while let Some(header) = read_header::<10>(file)? {
+        if &header[..3] != b"ID3" {
+            break;
+        }
+        let size = header[6..]
+            .iter()
+            .fold(0, |size, byte| size << 7 | u64::from(byte & 0x7f));
+        // with a footer of 10 bytes
+        let footer = match header[5] & 0x10 {
+            0 => 0,
+            _ => 10,
+        };
+        start += 10 + size + footer;
+        file.seek(SeekFrom::Start(start))?;
+    }
+    if file_type == FileType::Flac {
+        file.seek(SeekFrom::Start(start + 4))?;
+        start += 4;
+        while let Some(header) = read_header::<4>(file)? {
+            start += 4 + u64::from(u32::from_be_bytes([0, header[1], header[2], header[3]]));
+            file.seek(SeekFrom::Start(start))?;
+            if header[0] & 0x80 != 0 {
+                break;
             }
-        },
-    };
-    Ok(tag.clone())
+        }
+    }
+
+    let mut end = file.get_ref().metadata()?.len();
+    if end >= start + 128 {
+        file.seek(SeekFrom::Start(end - 128))?;
+        if read_header::<3>(file)?.is_some_and(|header| &header == b"TAG") {
+            end -= 128;
+        }
+    }
+    if end >= start + 32 {
+        file.seek(SeekFrom::Start(end - 32))?;
+        if let Some(footer) = read_header::<32>(file)? {
+            if &footer[..8] == b"APETAGEX" {
+                let size = u64::from(u32::from_le_bytes(footer[12..16].try_into()?));
+                let has_header = footer[23] & 0x80 != 0;
+                end = end.saturating_sub(size + if has_header { 32 } else { 0 });
+            }
+        }
+    }
+    Ok((start, end.max(start)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::{test_dir, wav};
+
+    /// A wav file with id3 tags
+    fn song(dir: &Path, name: &str, tags: &[(ItemKey, String)]) -> PathBuf {
+        fs::create_dir_all(dir).unwrap();
+        let file = wav(&dir.join(name), 0);
+        let mut tag = Tag::new(TagType::Id3v2);
+        for (key, value) in tags {
+            tag.insert_text(key.clone(), value.clone());
+        }
+        tag.save_to_path(&file, WriteOptions::default()).unwrap();
+        file
+    }
+
+    /// The tags of a song of an album without an album artist
+    fn track(artist: &str, title: &str) -> Vec<(ItemKey, String)> {
+        vec![
+            (ItemKey::TrackTitle, title.to_string()),
+            (ItemKey::TrackArtist, artist.to_string()),
+            (ItemKey::AlbumTitle, String::from("Album")),
+        ]
+    }
+
+    fn with(mut tags: Vec<(ItemKey, String)>, key: ItemKey, value: &str) -> Vec<(ItemKey, String)> {
+        tags.retain(|(other, _)| *other != key);
+        tags.push((key, value.to_string()));
+        tags
+    }
+
+    fn reader() -> TagReader {
+        TagReader::new(DEFAULT_ALBUM_ARTIST_FALLBACK.to_vec())
+    }
+
+    #[test]
+    fn album_artist_tag_is_not_inferred() {
+        let dir = test_dir("fallback-tag");
+        let file = song(
+            &dir,
+            "a.wav",
+            &with(track("Artist", "A"), ItemKey::AlbumArtist, "Band"),
+        );
+        let tag = reader().read(&file).unwrap();
+        assert_eq!(tag.album_artist, "Band");
+        assert!(tag.inferred.is_empty());
+    }
+
+    #[test]
+    fn album_artist_from_sort_name() {
+        let dir = test_dir("fallback-sort");
+        let file = song(
+            &dir,
+            "a.wav",
+            &with(
+                track("John Lennon", "A"),
+                ItemKey::AlbumArtistSortOrder,
+                "Beatles, The",
+            ),
+        );
+        let tag = reader().read(&file).unwrap();
+        assert_eq!(tag.album_artist, "The Beatles");
+        assert_eq!(tag.inferred["album_artist"], "album artist sort name");
+    }
+
+    #[test]
+    fn album_artist_of_different_artists() {
+        let dir = test_dir("fallback-various");
+        let file = song(&dir, "a.wav", &track("First", "A"));
+        song(&dir, "b.wav", &track("Second", "B"));
+        // music of another album in the directory does not count
+        song(
+            &dir,
+            "c.wav",
+            &with(track("Third", "C"), ItemKey::AlbumTitle, "Other"),
+        );
+        let tag = reader().read(&file).unwrap();
+        assert_eq!(tag.album_artist, VARIOUS_ARTISTS);
+        assert_eq!(
+            tag.inferred["album_artist"],
+            "different artists in the album"
+        );
+
+        // the rules are tried in order
+        let reader = TagReader::new(vec![
+            AlbumArtistFallback::TrackArtist,
+            AlbumArtistFallback::VariousArtists,
+        ]);
+        let tag = reader.read(&file).unwrap();
+        assert_eq!(tag.album_artist, "First");
+        assert_eq!(tag.inferred["album_artist"], "track artist");
+    }
+
+    #[test]
+    fn album_artist_from_track_artist() {
+        let dir = test_dir("fallback-track");
+        let file = song(&dir, "a.wav", &track("Artist", "A"));
+        song(&dir, "b.wav", &track("Artist", "B"));
+        let tag = reader().read(&file).unwrap();
+        assert_eq!(tag.album_artist, "Artist");
+        assert_eq!(tag.inferred["album_artist"], "track artist");
+    }
+
+    #[test]
+    fn no_fallback() {
+        let dir = test_dir("fallback-none");
+        let file = song(&dir, "a.wav", &track("Artist", "A"));
+        let err = TagReader::new(vec![]).read(&file).unwrap_err();
+        assert_eq!(err.to_string(), "could not find album artist tag");
+    }
+
+    #[test]
+    fn album_artist_of_the_other_music() {
+        let dir = test_dir("fallback-other");
+        song(
+            &dir,
+            "a.wav",
+            &with(track("Singer", "A"), ItemKey::AlbumArtist, "Band"),
+        );
+        let file = song(&dir, "b.wav", &track("Guest", "B"));
+        let tag = reader().read(&file).unwrap();
+        assert_eq!(tag.album_artist, "Band");
+        assert_eq!(
+            tag.inferred["album_artist"],
+            "album artist of the other music in the album"
+        );
+        assert!(!tag.compilation);
+    }
+
+    #[test]
+    fn compilation_flag() {
+        let dir = test_dir("compilation-flag");
+        let file = song(
+            &dir,
+            "a.wav",
+            &with(track("Artist", "A"), ItemKey::FlagCompilation, "1"),
+        );
+        song(&dir, "b.wav", &track("Other", "B"));
+        let tag = reader().read(&file).unwrap();
+        assert!(tag.compilation);
+        assert!(!tag.inferred.contains_key("compilation"));
+
+        let dir = test_dir("compilation-no-flag");
+        let file = song(
+            &dir,
+            "a.wav",
+            &with(track("Artist", "A"), ItemKey::FlagCompilation, "0"),
+        );
+        song(&dir, "b.wav", &track("Other", "B"));
+        assert!(!reader().read(&file).unwrap().compilation);
+    }
+
+    #[test]
+    fn compilation_of_different_artists() {
+        let dir = test_dir("compilation-artists");
+        let file = song(&dir, "a.wav", &track("First", "A"));
+        song(&dir, "b.wav", &track("Second", "B"));
+        let tag = reader().read(&file).unwrap();
+        assert!(tag.compilation);
+        assert_eq!(
+            tag.inferred["compilation"],
+            "different artists in the album"
+        );
+
+        // with different album artists as well
+        let dir = test_dir("compilation-album-artists");
+        let file = song(
+            &dir,
+            "a.wav",
+            &with(track("First", "A"), ItemKey::AlbumArtist, "First"),
+        );
+        song(
+            &dir,
+            "b.wav",
+            &with(track("Second", "B"), ItemKey::AlbumArtist, "Second"),
+        );
+        assert!(reader().read(&file).unwrap().compilation);
+    }
+
+    #[test]
+    fn no_compilation_with_one_album_artist() {
+        let dir = test_dir("compilation-album-artist");
+        let file = song(
+            &dir,
+            "a.wav",
+            &with(track("Singer", "A"), ItemKey::AlbumArtist, "Band"),
+        );
+        song(
+            &dir,
+            "b.wav",
+            &with(track("Guest", "B"), ItemKey::AlbumArtist, "Band"),
+        );
+        let tag = reader().read(&file).unwrap();
+        assert!(!tag.compilation);
+        assert!(!tag.inferred.contains_key("compilation"));
+
+        // music without the tag does not make it a compilation
+        let dir = test_dir("compilation-some-album-artist");
+        let file = song(
+            &dir,
+            "a.wav",
+            &with(track("Singer", "A"), ItemKey::AlbumArtist, "Band"),
+        );
+        song(&dir, "b.wav", &track("Guest", "B"));
+        assert!(!reader().read(&file).unwrap().compilation);
+    }
+
+    #[test]
+    fn compilation_over_discs() {
+        let dir = test_dir("compilation-discs");
+        let file = song(&dir.join("Disc 1"), "a.wav", &track("First", "A"));
+        song(&dir.join("Disc 2"), "b.wav", &track("Second", "B"));
+        let tag = reader().read(&file).unwrap();
+        assert!(tag.compilation);
+        assert_eq!(tag.album_artist, VARIOUS_ARTISTS);
+    }
+
+    #[test]
+    fn unsort_names() {
+        assert_eq!(unsort_name("Beatles, The"), "The Beatles");
+        assert_eq!(unsort_name("Bowie, David"), "David Bowie");
+        assert_eq!(unsort_name("Radiohead"), "Radiohead");
+        // more than one comma is not a sort name
+        assert_eq!(unsort_name("Crosby, Stills, Nash"), "Crosby, Stills, Nash");
+    }
+
+    fn write(dir: &Path, name: &str, parts: &[&[u8]]) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, parts.concat()).unwrap();
+        path
+    }
+
+    fn hash(file: &Path) -> u64 {
+        audio_hash(file).unwrap()
+    }
+
+    #[test]
+    fn wav_tags_are_not_hashed() {
+        let dir = test_dir("hash-wav");
+        let plain = wav(&dir.join("plain.wav"), 100);
+        let tagged = wav(&dir.join("tagged.wav"), 100);
+        let mut tag = Tag::new(TagType::RiffInfo);
+        tag.set_title(String::from("Song"));
+        tag.save_to_path(&tagged, WriteOptions::default()).unwrap();
+        let mut tag = Tag::new(TagType::Id3v2);
+        tag.set_artist(String::from("Artist"));
+        tag.save_to_path(&tagged, WriteOptions::default()).unwrap();
+        assert_ne!(fs::read(&plain).unwrap(), fs::read(&tagged).unwrap());
+        let other = wav(&dir.join("other.wav"), 200);
+
+        assert_eq!(hash(&plain), hash(&tagged));
+        assert_ne!(hash(&plain), hash(&other));
+    }
+
+    #[test]
+    fn flac_metadata_is_not_hashed() {
+        let dir = test_dir("hash-flac");
+        let stream_info = [&[0x00, 0x00, 0x00, 34][..], &[7; 34]].concat();
+        let last_stream_info = [&[0x80, 0x00, 0x00, 34][..], &[7; 34]].concat();
+        let comment = [&[0x84, 0x00, 0x00, 5][..], b"title"].concat();
+        let frames: &[u8] = &[0xFF, 0xF8, 0x69, 0x08, 1, 2, 3, 4];
+        let plain = write(&dir, "plain.flac", &[b"fLaC", &last_stream_info, frames]);
+        let tagged = write(
+            &dir,
+            "tagged.flac",
+            &[b"fLaC", &stream_info, &comment, frames],
+        );
+        let other = write(
+            &dir,
+            "other.flac",
+            &[b"fLaC", &last_stream_info, frames, &[5]],
+        );
+
+        assert_eq!(hash(&plain), hash(&tagged));
+        assert_ne!(hash(&plain), hash(&other));
+    }
+
+    #[test]
+    fn id3_and_ape_tags_are_not_hashed() {
+        let dir = test_dir("hash-mp3");
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x64];
+        frame.resize(417, 1);
+        let frames = frame.repeat(3);
+        let id3v2 = [&b"ID3\x04\x00\x00\x00\x00\x00\x05"[..], b"tags!"].concat();
+        let mut ape_footer = b"APETAGEX".to_vec();
+        ape_footer.extend(2000u32.to_le_bytes());
+        ape_footer.extend(40u32.to_le_bytes()); // size with the footer
+        ape_footer.extend(0u32.to_le_bytes());
+        ape_footer.extend(0u32.to_le_bytes());
+        ape_footer.extend([0; 8]);
+        let ape = [&[3; 8][..], &ape_footer].concat();
+        let id3v1 = [&b"TAG"[..], &[0; 125]].concat();
+        let plain = write(&dir, "plain.mp3", &[&frames]);
+        let tagged = write(&dir, "tagged.mp3", &[&id3v2, &frames, &ape, &id3v1]);
+        let other = write(&dir, "other.mp3", &[&id3v2, &frame, &id3v1]);
+
+        assert_eq!(hash(&plain), hash(&tagged));
+        assert_ne!(hash(&plain), hash(&other));
+    }
+
+    /// An ogg page with one packet, the checksum is not checked
+    fn ogg_page(granule: u64, sequence: u32, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00\x00".to_vec();
+        page.extend(granule.to_le_bytes());
+        page.extend(1u32.to_le_bytes());
+        page.extend(sequence.to_le_bytes());
+        page.extend(0u32.to_le_bytes());
+        let mut segments = vec![255; packet.len() / 255];
+        segments.push((packet.len() % 255) as u8);
+        page.push(segments.len() as u8);
+        page.extend(segments);
+        page.extend(packet);
+        page
+    }
+
+    #[test]
+    fn ogg_header_pages_are_not_hashed() {
+        let dir = test_dir("hash-ogg");
+        let head = ogg_page(
+            0,
+            0,
+            b"OpusHead\x01\x01\x38\x01\x80\xbb\x00\x00\x00\x00\x00",
+        );
+        let opus = |tags: &[u8], sequence: u32, audio: &[u8]| {
+            let mut tag_pages = vec![];
+            // big tags take more pages, so the audio pages get other numbers
+            let pages = tags.len().div_ceil(255 * 4);
+            for (page, part) in tags.chunks(255 * 4).enumerate() {
+                // only the last page ends the packet
+                let granule = match page + 1 == pages {
+                    true => 0,
+                    false => u64::MAX,
+                };
+                tag_pages.extend(ogg_page(granule, 1 + page as u32, part));
+            }
+            let audio_page = ogg_page(1272, sequence, audio);
+            [head.clone(), tag_pages, audio_page].concat()
+        };
+        let plain = write(&dir, "plain.opus", &[&opus(b"OpusTags", 2, b"audio")]);
+        let tags = [&b"OpusTags"[..], &[b'x'; 3000]].concat();
+        let tagged = write(&dir, "tagged.opus", &[&opus(&tags, 5, b"audio")]);
+        let other = write(&dir, "other.opus", &[&opus(b"OpusTags", 2, b"other")]);
+
+        assert_eq!(hash(&plain), hash(&tagged));
+        assert_ne!(hash(&plain), hash(&other));
+    }
+
+    #[test]
+    fn mp4_movie_box_is_not_hashed() {
+        let dir = test_dir("hash-mp4");
+        let mp4_box = |kind: &[u8], content: &[u8]| {
+            let size = (content.len() as u32 + 8).to_be_bytes();
+            [&size[..], kind, content].concat()
+        };
+        let ftyp = mp4_box(b"ftyp", b"M4A \x00\x00\x00\x00M4A mp42isom");
+        let mdat = mp4_box(b"mdat", b"audio");
+        let plain = write(&dir, "plain.m4a", &[&ftyp, &mp4_box(b"moov", b""), &mdat]);
+        let tagged = write(
+            &dir,
+            "tagged.m4a",
+            &[&ftyp, &mp4_box(b"moov", &mp4_box(b"udta", b"tags")), &mdat],
+        );
+        let other = write(
+            &dir,
+            "other.m4a",
+            &[&ftyp, &mp4_box(b"moov", b""), &mp4_box(b"mdat", b"other")],
+        );
+
+        assert_eq!(hash(&plain), hash(&tagged));
+        assert_ne!(hash(&plain), hash(&other));
+    }
 }
@@ -1,76 +1,203 @@
-use std::process::Stdio;
-use std::{path::Path, process::Command};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
-use anyhow::{anyhow, Context, Result};
-use log::{error, info};
+use anyhow::{anyhow, Result};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{debug, info};
 
-use crate::music_tag::file_has_replaygain_tags;
+use crate::backend::{self, Normalizer};
+use crate::config::get_config;
+use crate::music_tag::{file_has_replaygain_tags, get_album_title};
+use crate::planner::{self, Operation};
+use crate::progress;
+use crate::template::is_disc_dir;
 
-pub fn normalize(dir: &Path, file: &Path, quiet: &bool, force: &bool) -> Result<()> {
+/// The files of an album, they are normalized together in the directory
+pub struct Album {
+    pub dir: PathBuf,
+    pub files: Vec<PathBuf>,
+}
+
+impl Album {
+    fn files(&self) -> Vec<&Path> {
+        self.files.iter().map(|file| file.as_path()).collect()
+    }
+}
+
+/// Normalize a file as an album of its own
+pub fn normalize(dir: &Path, file: &Path, quiet: &bool, force: &bool) -> Result<bool> {
     normalize_files(dir, &[file], quiet, force)
 }
 
-pub fn normalize_files(dir: &Path, files: &[&Path], quiet: &bool, force: &bool) -> Result<()> {
-    if !force
-        && !files
-            .iter()
-            .any(|f| file_has_replaygain_tags(f).unwrap_or(false))
-    {
-        info!(
-            "{} already has replaygain tags, skipping normalizing",
-            files
-                .iter()
-                .map(|file| format!("\"{}\"", file.display()))
-                .collect::<Vec<String>>()
-                .join(", ")
-        );
-        return Ok(());
+/// Normalize the files of an album together, if any of them is missing the replaygain tags
+/// of its format, the album gain is only right if every file is measured again.
+/// Gives if the album was normalized
+pub fn normalize_files(dir: &Path, files: &[&Path], quiet: &bool, force: &bool) -> Result<bool> {
+    let normalizer = backend::normalizer(&get_config()?);
+    let normalized = normalize_album(normalizer.as_ref(), dir, files, quiet, force)?;
+    match normalized {
+        true => info!("Normalized: {}", list(files)),
+        false => info!(
+            "{} already have replaygain tags, skipping normalizing",
+            list(files)
+        ),
     }
+    Ok(normalized)
+}
 
-    let normalizer = Command::new("rsgain")
-        .current_dir(dir)
-        .stdout(if *quiet {
-            Stdio::null()
-        } else {
-            Stdio::inherit()
-        })
-        .stderr(if *quiet {
-            Stdio::null()
-        } else {
-            Stdio::inherit()
-        })
-        .arg("custom")
-        .arg(match quiet {
-            true => "-aq",
-            false => "-a",
-        }) // album mode, and quiet if needed
-        .arg(match force {
-            true => "",
-            false => "-S",
-        }) // Skip existing tags
-        .args(["-s", "i"]) // output mode i =  write replaygain2.0 tags plus extra tags
-        .args(files)
-        .status()
-        .context("Could not execute rsgain")?;
+/// Group files in albums by the directory they are in and their album tag,
+/// the discs of an album are one album
+pub fn group_albums(files: &[PathBuf]) -> Vec<Album> {
+    let mut albums: BTreeMap<(PathBuf, Option<String>), Vec<PathBuf>> = BTreeMap::new();
+    for file in files {
+        let dir = match file.parent() {
+            Some(dir) if is_disc_dir(dir) => dir.parent().unwrap_or(dir),
+            Some(dir) => dir,
+            None => Path::new(""),
+        };
+        let album = get_album_title(file);
+        albums
+            .entry((dir.to_path_buf(), album))
+            .or_default()
+            .push(file.clone());
+    }
+    albums
+        .into_iter()
+        .map(|((dir, _), files)| Album { dir, files })
+        .collect()
+}
+
+/// Normalize albums at the same time, with jobs from normalize_jobs in the config
+/// or the number of cpus, the output of the normalizer is replaced by progress bars.
+/// Gives the result of every album in order, an album that fails does not stop the others
+pub fn normalize_albums(
+    albums: &[Album],
+    quiet: &bool,
+    force: &bool,
+    jobs: Option<usize>,
+) -> Result<Vec<Result<bool>>> {
+    let config = get_config()?;
+    let jobs = jobs
+        .or(config.normalize_jobs)
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |jobs| jobs.get()))
+        .clamp(1, albums.len().max(1));
 
-    if !normalizer.success() {
-        error!(
-            "rsgain {}\nFailed to normalize audio with rsgain",
-            normalizer
-        );
-        return Err(anyhow!(
-            "Rsgain exited with unsuccessfully with code {}",
-            normalizer
-        ));
-    };
-    info!(
-        "Normalized: {}",
-        files
+    // one at a time the normalizer can show its own output, a dry run only prints
+    if jobs == 1 || planner::is_dry_run() {
+        return Ok(albums
             .iter()
-            .map(|file| format!("\"{}\"", file.display()))
-            .collect::<Vec<String>>()
-            .join(", ")
+            .map(|album| normalize_files(&album.dir, &album.files(), quiet, force))
+            .collect());
+    }
+
+    let progress = MultiProgress::with_draw_target(match quiet {
+        true => ProgressDrawTarget::hidden(),
+        false => ProgressDrawTarget::stderr(),
+    });
+    let total = progress.add(
+        ProgressBar::new(albums.len() as u64).with_style(
+            ProgressStyle::with_template("Normalizing [{bar:40}] {pos}/{len} albums ({eta})")?
+                .progress_chars("=> "),
+        ),
     );
+    let spinner_style = ProgressStyle::with_template("{spinner} {msg}")?;
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<bool>>>> =
+        Mutex::new(albums.iter().map(|_| None).collect());
+    // what the normalizers log is printed above the progress bars
+    let shown = progress::show(&progress);
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            let spinner = progress.insert_before(
+                &total,
+                ProgressBar::new_spinner().with_style(spinner_style.clone()),
+            );
+            spinner.enable_steady_tick(Duration::from_millis(100));
+            let (config, total, next, results) = (&config, &total, &next, &results);
+            scope.spawn(move || {
+                let normalizer = backend::normalizer(config);
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(album) = albums.get(index) else {
+                        break;
+                    };
+                    spinner.set_message(album.dir.display().to_string());
+                    let files = album.files();
+                    let result =
+                        normalize_album(normalizer.as_ref(), &album.dir, &files, &true, force);
+                    // errors are left to the caller
+                    if let Ok(true) = result {
+                        info!("Normalized: {}", list(&files));
+                    }
+                    results.lock().unwrap()[index] = Some(result);
+                    total.inc(1);
+                }
+                spinner.finish_and_clear();
+            });
+        }
+    });
+    total.finish_and_clear();
+    drop(shown);
+
+    Ok(results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.unwrap_or_else(|| Err(anyhow!("The album was not normalized"))))
+        .collect())
+}
+
+/// Normalize an album unless every file has the replaygain tags of its format
+fn normalize_album(
+    normalizer: &dyn Normalizer,
+    dir: &Path,
+    files: &[&Path],
+    quiet: &bool,
+    force: &bool,
+) -> Result<bool> {
+    if files.is_empty() {
+        return Ok(false);
+    }
+    let missing: Vec<&Path> = files
+        .iter()
+        .copied()
+        .filter(|file| match file_has_replaygain_tags(file) {
+            Ok(has_tags) => !has_tags,
+            Err(err) => {
+                debug!("Could not read the tags of \"{}\": {err}", file.display());
+                true
+            }
+        })
+        .collect();
+    if !force && missing.is_empty() {
+        return Ok(false);
+    }
+    if !missing.is_empty() {
+        debug!("Missing replaygain tags: {}", list(&missing));
+    }
+
+    if planner::plan(Operation::Normalize(
+        files.iter().map(|file| file.to_path_buf()).collect(),
+    )) {
+        return Ok(true);
+    }
+    normalizer.normalize(dir, files, quiet)?;
+    Ok(true)
+}
 
-    Ok(())
+fn list(files: &[&Path]) -> String {
+    files
+        .iter()
+        .map(|file| format!("\"{}\"", file.display()))
+        .collect::<Vec<String>>()
+        .join(", ")
 }
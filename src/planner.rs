@@ -0,0 +1,406 @@
+//! Every change to the filesystem goes through here,
+//! so a dry run can print what would be changed instead of changing it,
+//! and moves can be undone with the journal
+
+use std::{
+    ffi::OsString,
+    fs::{self, File, Metadata},
+    hash::Hasher,
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use colored::Colorize;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::journal;
+
+/// Files from this size get a progress bar when they are copied
+const PROGRESS_SIZE: u64 = 64 * 1024 * 1024;
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+static PLAN: Mutex<Vec<Operation>> = Mutex::new(vec![]);
+
+/// A change to the filesystem
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    CreateDir(PathBuf),
+    CreateFile(PathBuf),
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    RemoveDir(PathBuf),
+    /// Writing replaygain tags to the files
+    Normalize(Vec<PathBuf>),
+    /// Tagging the files with an external tagger
+    Tag(Vec<PathBuf>),
+    /// Changing tag fields of a file
+    WriteTags {
+        file: PathBuf,
+        changes: Vec<String>,
+    },
+    /// Downloading music to a directory
+    Download {
+        url: String,
+        dir: PathBuf,
+    },
+}
+
+impl Operation {
+    pub fn describe(&self) -> String {
+        match self {
+            Operation::CreateDir(dir) => format!("create directory \"{}\"", dir.display()),
+            Operation::CreateFile(file) => format!("create file \"{}\"", file.display()),
+            Operation::Move { from, to } => {
+                format!("move \"{}\" to \"{}\"", from.display(), to.display())
+            }
+            Operation::RemoveDir(dir) => format!("remove directory \"{}\"", dir.display()),
+            Operation::Normalize(files) => format!("normalize {}", list_files(files)),
+            Operation::Tag(files) => format!("tag {}", list_files(files)),
+            Operation::WriteTags { file, changes } => {
+                format!("write {} to \"{}\"", changes.join(", "), file.display())
+            }
+            Operation::Download { url, dir } => {
+                format!("download \"{url}\" to \"{}\"", dir.display())
+            }
+        }
+    }
+
+    /// The operation that reverses this one, if it can be reversed
+    pub fn reverse(&self) -> Option<Operation> {
+        match self {
+            Operation::CreateDir(dir) => Some(Operation::RemoveDir(dir.clone())),
+            Operation::Move { from, to } => Some(Operation::Move {
+                from: to.clone(),
+                to: from.clone(),
+            }),
+            Operation::RemoveDir(dir) => Some(Operation::CreateDir(dir.clone())),
+            _ => None,
+        }
+    }
+}
+
+fn list_files(files: &[PathBuf]) -> String {
+    files
+        .iter()
+        .map(|file| format!("\"{}\"", file.display()))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Only plan changes from now on, instead of making them
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed)
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Add an operation to the plan in a dry run,
+/// returns true if it should not be executed
+pub fn plan(operation: Operation) -> bool {
+    if !is_dry_run() {
+        return false;
+    }
+    info!("Would {}", operation.describe());
+    PLAN.lock().unwrap().push(operation);
+    true
+}
+
+/// Create a directory
+pub fn create_dir(dir: &Path) -> Result<()> {
+    apply(Operation::CreateDir(dir.to_path_buf()))
+}
+
+/// Create a file with the given content
+pub fn create_file(path: &Path, content: &str) -> Result<()> {
+    if plan(Operation::CreateFile(path.to_path_buf())) {
+        return Ok(());
+    }
+    fs::write(path, content).with_context(|| format!("Could not write \"{}\"", path.display()))
+}
+
+/// Move a file to a new path
+pub fn rename(from: &Path, to: &Path) -> Result<()> {
+    apply(Operation::Move {
+        from: from.to_path_buf(),
+        to: to.to_path_buf(),
+    })
+}
+
+/// Remove an empty directory
+pub fn remove_dir(dir: &Path) -> Result<()> {
+    apply(Operation::RemoveDir(dir.to_path_buf()))
+}
+
+/// Reverse an operation from the journal, this is not written to the journal itself
+pub fn undo(operation: &Operation) -> Result<()> {
+    let reverse = operation
+        .reverse()
+        .ok_or(anyhow!("Can not {}", operation.describe()))?;
+    if plan(reverse.clone()) {
+        return Ok(());
+    }
+    execute(&reverse)
+}
+
+/// Plan or execute an operation, and write it to the journal
+fn apply(operation: Operation) -> Result<()> {
+    if plan(operation.clone()) {
+        return Ok(());
+    }
+    execute(&operation)?;
+    journal::record(&operation);
+    Ok(())
+}
+
+fn execute(operation: &Operation) -> Result<()> {
+    match operation {
+        Operation::CreateDir(dir) => {
+            fs::create_dir(dir)
+                .with_context(|| format!("Could not create \"{}\"", dir.display()))?;
+            info!("Created \"{}\" directory", dir.display());
+        }
+        Operation::Move { from, to } => {
+            match fs::rename(from, to) {
+                Ok(_) => {}
+                // rename only works on the same filesystem
+                Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+                    debug!("{err}, copying \"{}\" instead", from.display());
+                    copy_and_remove(from, to)
+                        .with_context(|| format!("Could not move \"{}\"", from.display()))?;
+                }
+                Err(err) => return Err(err).context("Rename failed"),
+            }
+            info!("Moved \"{}\" to \"{}\"", from.display(), to.display());
+        }
+        Operation::RemoveDir(dir) => {
+            fs::remove_dir(dir)
+                .with_context(|| format!("Could not remove \"{}\"", dir.display()))?;
+            info!("Removed \"{}\" directory", dir.display());
+        }
+        _ => return Err(anyhow!("Can not {} here", operation.describe())),
+    }
+    Ok(())
+}
+
+/// Move a file to another filesystem, by copying it and only removing it
+/// after the copy is synced to disk and has the same length and hash as what was read.
+/// The copy is made next to the target and only replaces it when it is checked,
+/// so an existing target is kept when the copy fails
+fn copy_and_remove(from: &Path, to: &Path) -> Result<()> {
+    let metadata = fs::metadata(from)?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(to.file_name().unwrap_or_default());
+    temp_name.push(".part");
+    let temp = to.with_file_name(temp_name);
+    let copied = copy_verified(from, &temp, &metadata).and_then(|_| {
+        fs::rename(&temp, to)
+            .with_context(|| format!("Could not replace \"{}\" with the copy", to.display()))
+    });
+    if let Err(err) = copied {
+        // don't leave half copied files behind
+        let _ = fs::remove_file(&temp);
+        return Err(err);
+    }
+    fs::remove_file(from)
+        .with_context(|| format!("Copied but could not remove \"{}\"", from.display()))
+}
+
+/// Copy a file with its modification time and permissions, and check the copy
+fn copy_verified(from: &Path, to: &Path, metadata: &Metadata) -> Result<()> {
+    let hash = copy_file(from, to, metadata)?;
+    if fs::metadata(to)?.len() != metadata.len() || hash_file(to)? != hash {
+        return Err(anyhow!(
+            "The copy at \"{}\" is not the same as the original",
+            to.display()
+        ));
+    }
+    // the last step, a read-only copy can not be changed anymore
+    fs::set_permissions(to, metadata.permissions())?;
+    Ok(())
+}
+
+/// Copy a file with its modification time and give the hash of the content,
+/// shows a progress bar for big files
+fn copy_file(from: &Path, to: &Path, metadata: &Metadata) -> Result<u64> {
+    let size = metadata.len();
+    let mut source = File::open(from)?;
+    let mut target = File::create(to)?;
+    let progress = match size >= PROGRESS_SIZE {
+        true => ProgressBar::new(size).with_style(
+            ProgressStyle::with_template("{msg} [{bar:40}] {bytes}/{total_bytes} ({eta})")?
+                .progress_chars("=> "),
+        ),
+        false => ProgressBar::hidden(),
+    };
+    progress.set_message(
+        from.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+    );
+
+    let mut hasher = Fnv::default();
+    read_chunks(&mut source, |chunk| {
+        target.write_all(chunk)?;
+        hasher.write(chunk);
+        progress.inc(chunk.len() as u64);
+        Ok(())
+    })?;
+    target.set_modified(metadata.modified()?)?;
+    target.sync_all()?;
+    progress.finish_and_clear();
+    Ok(hasher.finish())
+}
+
+fn hash_file(file: &Path) -> Result<u64> {
+    let mut hasher = Fnv::default();
+    read_chunks(&mut File::open(file)?, |chunk| {
+        hasher.write(chunk);
+        Ok(())
+    })?;
+    Ok(hasher.finish())
+}
+
+/// The FNV-1a hash, it is the same on every platform and version
+pub(crate) struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Read a file, or a part of it, in chunks until the end
+pub(crate) fn read_chunks(
+    reader: &mut impl Read,
+    mut f: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => f(&buffer[..read])?,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// If a directory exists, or would exist when the plan is executed
+pub fn dir_exists(dir: &Path) -> bool {
+    if dir.is_dir() {
+        return true;
+    }
+    is_dry_run()
+        && PLAN
+            .lock()
+            .unwrap()
+            .contains(&Operation::CreateDir(dir.to_path_buf()))
+}
+
+/// If a file exists, or would exist when the plan is executed
+pub fn file_exists(file: &Path) -> bool {
+    if file.exists() {
+        return true;
+    }
+    is_dry_run()
+        && PLAN
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|operation| matches!(operation, Operation::Move { to, .. } if to == file))
+}
+
+/// Print a summary of the planned changes
+pub fn print_plan() {
+    let plan = PLAN.lock().unwrap();
+    let summary = format!(
+        "Dry run, nothing was changed, {} changes would be made",
+        plan.len()
+    );
+    println!("{}", summary.yellow().bold());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        journal::journal_path,
+        test_util::{lock, test_dir},
+    };
+
+    #[test]
+    fn dry_run_only_plans() {
+        let _lock = lock();
+        let dir = test_dir("dry-run");
+        let file = dir.join("song.opus");
+        fs::write(&file, "music").unwrap();
+        let journal = fs::read_to_string(journal_path().unwrap()).unwrap_or_default();
+        PLAN.lock().unwrap().clear();
+        set_dry_run(true);
+
+        let new_dir = dir.join("Album");
+        let moved = new_dir.join("song.opus");
+        create_dir(&new_dir).unwrap();
+        rename(&file, &moved).unwrap();
+        create_file(&dir.join("config.toml"), "name = \"Test\"").unwrap();
+        remove_dir(&dir).unwrap();
+
+        // the planned changes are seen as if they were made
+        assert!(dir_exists(&new_dir));
+        assert!(file_exists(&moved));
+        assert!(!dir_exists(&dir.join("Other")));
+        assert!(!file_exists(&new_dir.join("other.opus")));
+        set_dry_run(false);
+        assert!(!dir_exists(&new_dir));
+        assert!(!file_exists(&moved));
+
+        assert_eq!(
+            *PLAN.lock().unwrap(),
+            vec![
+                Operation::CreateDir(new_dir.clone()),
+                Operation::Move {
+                    from: file.clone(),
+                    to: moved,
+                },
+                Operation::CreateFile(dir.join("config.toml")),
+                Operation::RemoveDir(dir.clone()),
+            ]
+        );
+        // nothing changed on disk, also not the journal
+        assert!(file.is_file());
+        assert!(!new_dir.exists());
+        assert!(!dir.join("config.toml").exists());
+        assert_eq!(
+            fs::read_to_string(journal_path().unwrap()).unwrap_or_default(),
+            journal
+        );
+    }
+}
@@ -0,0 +1,57 @@
+//! Progress bars and logging, messages are printed above the progress bars
+//! instead of through them
+
+use std::sync::Mutex;
+
+use indicatif::MultiProgress;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+/// The progress bars on the terminal, if any
+static PROGRESS: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// Passes the messages to another logger, with the progress bars out of the way
+struct ProgressLogger(Box<dyn Log>);
+
+impl Log for ProgressLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.0.enabled(record.metadata()) {
+            return;
+        }
+        // the lock is not held while printing, so other threads can log
+        let progress = PROGRESS.lock().unwrap().clone();
+        match progress {
+            Some(progress) => progress.suspend(|| self.0.log(record)),
+            None => self.0.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// Use a logger that keeps out of the way of the progress bars
+pub fn init_logger(logger: Box<dyn Log>, level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(ProgressLogger(logger)))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Show the progress bars until the guard is dropped, messages are logged above them
+pub fn show(progress: &MultiProgress) -> ProgressGuard {
+    *PROGRESS.lock().unwrap() = Some(progress.clone());
+    ProgressGuard
+}
+
+/// Logging goes back to normal when this is dropped
+pub struct ProgressGuard;
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) {
+        *PROGRESS.lock().unwrap() = None;
+    }
+}
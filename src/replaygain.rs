@@ -0,0 +1,417 @@
+//! Replaygain without rsgain, the audio is decoded with symphonia
+//! and the loudness is measured like EBU R128
+
+use std::{f64::consts::PI, fs::File, io::ErrorKind, path::Path};
+
+use log::{debug, info};
+
+use anyhow::{anyhow, Context, Result};
+use symphonia::core::{
+    audio::{Channels, SampleBuffer, SignalSpec},
+    codecs::DecoderOptions,
+    errors::Error as DecodeError,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use crate::music_tag::{write_replaygain_tags, Gain};
+
+/// Blocks below this loudness are silence, in LUFS
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Blocks this much below the loudness of the other blocks are left out, in LU
+const RELATIVE_GATE: f64 = 10.0;
+/// A block is 400ms, a new block starts every 100ms
+const SUB_BLOCKS: usize = 4;
+/// The true peak is measured at this many points for every sample
+const OVERSAMPLING: usize = 4;
+/// The samples used to calculate every oversampled point
+const PHASE_TAPS: usize = 12;
+
+/// The measured blocks of a track
+struct TrackAnalysis {
+    /// The mean square of the filtered audio of every block, weighted over the channels
+    blocks: Vec<f64>,
+    peak: f64,
+}
+
+/// Calculate the replaygain of the files of an album and write the tags
+pub fn normalize_album(files: &[&Path]) -> Result<()> {
+    let tracks = files
+        .iter()
+        .map(|file| {
+            analyze_file(file).with_context(|| format!("Could not analyze \"{}\"", file.display()))
+        })
+        .collect::<Result<Vec<TrackAnalysis>>>()?;
+
+    let album_blocks: Vec<f64> = tracks
+        .iter()
+        .flat_map(|track| track.blocks.iter().copied())
+        .collect();
+    let album = Gain {
+        loudness: integrated_loudness(&album_blocks)
+            .ok_or(anyhow!("The album is silent or too short to measure"))?,
+        peak: tracks.iter().map(|track| track.peak).fold(0.0, f64::max),
+    };
+    for (file, track) in files.iter().zip(&tracks) {
+        let track = Gain {
+            loudness: integrated_loudness(&track.blocks).ok_or(anyhow!(
+                "\"{}\" is silent or too short to measure",
+                file.display()
+            ))?,
+            peak: track.peak,
+        };
+        debug!(
+            "\"{}\" has a loudness of {:.2} LUFS and a peak of {:.6}",
+            file.display(),
+            track.loudness,
+            track.peak
+        );
+        write_replaygain_tags(file, &track, &album)?;
+    }
+    info!(
+        "The album has a loudness of {:.2} LUFS and a peak of {:.6}",
+        album.loudness, album.peak
+    );
+    Ok(())
+}
+
+/// Decode a file and measure every block
+fn analyze_file(file: &Path) -> Result<TrackAnalysis> {
+    let source = MediaSourceStream::new(Box::new(File::open(file)?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = file.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+    let track = format
+        .default_track()
+        .ok_or(anyhow!("No audio track found"))?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut meter: Option<Meter> = None;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // a broken packet is skipped, like players do
+            Err(DecodeError::DecodeError(err)) => {
+                debug!("Skipping a packet of \"{}\": {err}", file.display());
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let spec = *decoded.spec();
+        let buffer = match &mut samples {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() * spec.channels.count() => {
+                buffer
+            }
+            _ => samples.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+        meter
+            .get_or_insert_with(|| Meter::new(spec))
+            .add(buffer.samples());
+    }
+
+    let meter = meter.ok_or(anyhow!("No audio found"))?;
+    Ok(TrackAnalysis {
+        blocks: meter.blocks,
+        peak: meter.peaks.iter().map(|peak| peak.peak).fold(0.0, f64::max),
+    })
+}
+
+/// The loudness of the blocks without silence and quiet parts, none if every block is silent
+fn integrated_loudness(blocks: &[f64]) -> Option<f64> {
+    let loudness = |energy: f64| -0.691 + 10.0 * energy.log10();
+    // the mean of the blocks louder than the gate
+    let mean = |gate: f64| {
+        let gated: Vec<f64> = blocks
+            .iter()
+            .copied()
+            .filter(|block| loudness(*block) > gate)
+            .collect();
+        match gated.is_empty() {
+            true => None,
+            false => Some(gated.iter().sum::<f64>() / gated.len() as f64),
+        }
+    };
+    let relative_gate = loudness(mean(ABSOLUTE_GATE)?) - RELATIVE_GATE;
+    mean(relative_gate.max(ABSOLUTE_GATE)).map(loudness)
+}
+
+/// Measures the loudness of audio in blocks, and the true peak
+struct Meter {
+    channels: usize,
+    /// The K-weighting filters of every channel
+    filters: Vec<[Biquad; 2]>,
+    /// Surround channels count more, LFE does not count
+    weights: Vec<f64>,
+    /// The samples of one channel in 100ms
+    sub_block_length: usize,
+    /// The samples of one channel in the current sub block
+    position: usize,
+    /// The sum of squares of every channel in the current sub block
+    sums: Vec<f64>,
+    /// The weighted sums of the last sub blocks
+    sub_blocks: Vec<f64>,
+    blocks: Vec<f64>,
+    peaks: Vec<TruePeak>,
+    interpolation: [[f64; PHASE_TAPS]; OVERSAMPLING],
+}
+
+impl Meter {
+    fn new(spec: SignalSpec) -> Meter {
+        let channels = spec.channels.count();
+        let rate = spec.rate as f64;
+        let weights = spec
+            .channels
+            .iter()
+            .map(|channel| {
+                if channel == Channels::LFE1 {
+                    0.0
+                } else if (Channels::SIDE_LEFT
+                    | Channels::SIDE_RIGHT
+                    | Channels::REAR_LEFT
+                    | Channels::REAR_RIGHT)
+                    .contains(channel)
+                {
+                    1.41
+                } else {
+                    1.0
+                }
+            })
+            .collect();
+        Meter {
+            channels,
+            filters: vec![k_weighting(rate); channels],
+            weights,
+            sub_block_length: (spec.rate as usize / 10).max(1),
+            position: 0,
+            sums: vec![0.0; channels],
+            sub_blocks: vec![],
+            blocks: vec![],
+            peaks: vec![TruePeak::default(); channels],
+            interpolation: interpolation_filter(),
+        }
+    }
+
+    /// Add interleaved samples
+    fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample = *sample as f64;
+                self.peaks[channel].add(sample, &self.interpolation);
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let filtered = high_pass.process(shelf.process(sample));
+                self.sums[channel] += filtered * filtered;
+            }
+            self.position += 1;
+            if self.position == self.sub_block_length {
+                self.end_sub_block();
+            }
+        }
+    }
+
+    fn end_sub_block(&mut self) {
+        let sum = self
+            .sums
+            .iter()
+            .zip(&self.weights)
+            .map(|(sum, weight)| sum * weight)
+            .sum();
+        self.sub_blocks.push(sum);
+        if self.sub_blocks.len() > SUB_BLOCKS {
+            self.sub_blocks.remove(0);
+        }
+        if self.sub_blocks.len() == SUB_BLOCKS {
+            let length = (SUB_BLOCKS * self.sub_block_length) as f64;
+            self.blocks
+                .push(self.sub_blocks.iter().sum::<f64>() / length);
+        }
+        self.sums.fill(0.0);
+        self.position = 0;
+    }
+}
+
+/// A second order filter
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    /// The last inputs
+    x: [f64; 2],
+    /// The last outputs
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Biquad {
+        Biquad {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0]
+            - self.a[2] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The K-weighting of ITU-R BS.1770 for a sample rate, a high shelf and a high pass filter
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let frequency = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * frequency / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let frequency = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * frequency / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+    [shelf, high_pass]
+}
+
+/// The peak of one channel, also between the samples
+#[derive(Clone, Default)]
+struct TruePeak {
+    /// The last samples, from the oldest at `position`
+    history: [f64; PHASE_TAPS],
+    position: usize,
+    peak: f64,
+}
+
+impl TruePeak {
+    fn add(&mut self, sample: f64, interpolation: &[[f64; PHASE_TAPS]; OVERSAMPLING]) {
+        self.history[self.position] = sample;
+        self.position = (self.position + 1) % PHASE_TAPS;
+        self.peak = self.peak.max(sample.abs());
+        for taps in interpolation {
+            let value: f64 = taps
+                .iter()
+                .enumerate()
+                .map(|(tap, coefficient)| {
+                    // the newest sample goes with the first tap
+                    let index = (self.position + PHASE_TAPS - 1 - tap) % PHASE_TAPS;
+                    coefficient * self.history[index]
+                })
+                .sum();
+            self.peak = self.peak.max(value.abs());
+        }
+    }
+}
+
+/// A windowed sinc filter to find the points between the samples, split in its phases
+fn interpolation_filter() -> [[f64; PHASE_TAPS]; OVERSAMPLING] {
+    let length = OVERSAMPLING * PHASE_TAPS;
+    let center = (length - 1) as f64 / 2.0;
+    let mut phases = [[0.0; PHASE_TAPS]; OVERSAMPLING];
+    for n in 0..length {
+        let x = (n as f64 - center) / OVERSAMPLING as f64;
+        let sinc = match x == 0.0 {
+            true => 1.0,
+            false => (PI * x).sin() / (PI * x),
+        };
+        let window = 0.5 - 0.5 * (2.0 * PI * n as f64 / (length - 1) as f64).cos();
+        phases[n % OVERSAMPLING][n / OVERSAMPLING] = sinc * window;
+    }
+    phases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// Measure mono audio, gives the loudness and the true peak
+    fn measure(samples: &[f32]) -> (Option<f64>, f64) {
+        let mut meter = Meter::new(SignalSpec::new(RATE, Channels::FRONT_LEFT));
+        meter.add(samples);
+        (integrated_loudness(&meter.blocks), meter.peaks[0].peak)
+    }
+
+    /// A sine of some seconds, the amplitude in dBFS
+    fn sine(frequency: f64, dbfs: f64, phase: f64, seconds: usize) -> Vec<f32> {
+        let amplitude = 10f64.powf(dbfs / 20.0);
+        (0..RATE as usize * seconds)
+            .map(|n| {
+                (amplitude * (2.0 * PI * frequency * n as f64 / RATE as f64 + phase).sin()) as f32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sine_loudness() {
+        let (loudness, _) = measure(&sine(997.0, -20.0, 0.0, 10));
+        let loudness = loudness.unwrap();
+        assert!((loudness + 23.0).abs() < 0.1, "measured {loudness} LUFS");
+    }
+
+    #[test]
+    fn silence_is_gated() {
+        assert_eq!(measure(&vec![0.0; RATE as usize * 5]).0, None);
+        // silence between the music does not make it quieter,
+        // only the few blocks with both count a bit
+        let mut samples = sine(997.0, -20.0, 0.0, 20);
+        samples.extend(vec![0.0; RATE as usize * 10]);
+        samples.extend(sine(997.0, -20.0, 0.0, 20));
+        let loudness = measure(&samples).0.unwrap();
+        assert!((loudness + 23.0).abs() < 0.1, "measured {loudness} LUFS");
+    }
+
+    #[test]
+    fn true_peak_between_samples() {
+        // a quarter of the sample rate, the samples miss the top of the wave
+        let samples = sine(RATE as f64 / 4.0, -6.0, PI / 4.0, 1);
+        let sample_peak = samples
+            .iter()
+            .fold(0.0, |peak: f64, sample| peak.max(sample.abs() as f64));
+        let (_, true_peak) = measure(&samples);
+        assert!(true_peak >= sample_peak);
+        assert!(
+            true_peak > sample_peak * 1.2,
+            "true peak {true_peak}, sample peak {sample_peak}"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Playlists and channels of a category that are downloaded again with sync
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Context, Result};
+
+/// The subscriptions of a category, next to its config
+const SUBSCRIPTIONS_FILE: &str = "subscriptions.toml";
+/// When the subscriptions of a category were synced
+const SYNC_FILE: &str = "sync.json";
+
+/// A playlist or channel, only the videos that were not downloaded before are downloaded
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Subscription {
+    pub url: String,
+    /// The name in the report, the url by default
+    pub name: Option<String>,
+    /// The audio format the videos are converted to, opus by default
+    pub format: Option<String>,
+    /// If videos are split in their chapters, true by default
+    pub split_chapters: Option<bool>,
+    /// If files that have no tags after downloading are tagged with the tagger,
+    /// otherwise they are left in Untagged, true by default
+    pub auto_tag: Option<bool>,
+    /// Only videos uploaded on or after this date, like 2024-01-31 or today-2weeks
+    pub since: Option<String>,
+}
+
+impl Subscription {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.url)
+    }
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct Subscriptions {
+    #[serde(default, rename = "subscription")]
+    subscriptions: Vec<Subscription>,
+}
+
+/// The last sync of a subscription
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct SyncState {
+    pub time: SystemTime,
+    /// The videos that were downloaded
+    pub new: usize,
+}
+
+/// The last sync of every subscription of a category, by url
+pub type SyncStates = BTreeMap<String, SyncState>;
+
+/// The path of the subscriptions file of a category
+pub fn subscriptions_path(category_dir: &Path) -> PathBuf {
+    category_dir.join(SUBSCRIPTIONS_FILE)
+}
+
+/// Get the subscriptions of a category, none if it has no subscriptions file
+pub fn get_subscriptions(category_dir: &Path) -> Result<Vec<Subscription>> {
+    let path = subscriptions_path(category_dir);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Could not read \"{}\"", path.display()))
+        }
+    };
+    let subscriptions: Subscriptions = toml::from_str(&contents)
+        .with_context(|| format!("Could not read subscriptions \"{}\"", path.display()))?;
+    Ok(subscriptions.subscriptions)
+}
+
+/// Get when the subscriptions of a category were synced, empty if they never were
+pub fn get_sync_states(category_dir: &Path) -> Result<SyncStates> {
+    let path = category_dir.join(SYNC_FILE);
+    match fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("Could not read \"{}\"", path.display())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(SyncStates::new()),
+        Err(err) => Err(err).with_context(|| format!("Could not read \"{}\"", path.display())),
+    }
+}
+
+/// Write when the subscriptions of a category were synced
+pub fn save_sync_states(category_dir: &Path, states: &SyncStates) -> Result<()> {
+    let path = category_dir.join(SYNC_FILE);
+    fs::write(&path, serde_json::to_string_pretty(states)?)
+        .with_context(|| format!("Could not write \"{}\"", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn read_subscriptions() {
+        let dir = test_dir("subscriptions");
+        fs::write(
+            subscriptions_path(&dir),
+            "[[subscription]]\nurl = \"https://example.com/playlist\"\nname = \"Playlist\"\n\
+            since = \"today-2weeks\"\n\n[[subscription]]\nurl = \"https://example.com/channel\"\n\
+            split_chapters = false\nauto_tag = false\n",
+        )
+        .unwrap();
+
+        let subscriptions = get_subscriptions(&dir).unwrap();
+        assert_eq!(subscriptions.len(), 2);
+        assert_eq!(subscriptions[0].name(), "Playlist");
+        assert_eq!(subscriptions[0].since.as_deref(), Some("today-2weeks"));
+        assert_eq!(subscriptions[0].split_chapters, None);
+        // the url is the name if there is no name
+        assert_eq!(subscriptions[1].name(), "https://example.com/channel");
+        assert_eq!(subscriptions[1].split_chapters, Some(false));
+        assert_eq!(subscriptions[1].auto_tag, Some(false));
+    }
+
+    #[test]
+    fn missing_and_malformed_subscriptions() {
+        let dir = test_dir("malformed");
+        assert!(get_subscriptions(&dir).unwrap().is_empty());
+
+        fs::write(
+            subscriptions_path(&dir),
+            "[[subscription]]\nname = \"No url\"\n",
+        )
+        .unwrap();
+        assert!(get_subscriptions(&dir).is_err());
+    }
+
+    #[test]
+    fn sync_states_round_trip() {
+        let dir = test_dir("sync_states");
+        assert!(get_sync_states(&dir).unwrap().is_empty());
+
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_706_659_200);
+        let states = SyncStates::from([(
+            String::from("https://example.com/playlist"),
+            SyncState { time, new: 3 },
+        )]);
+        save_sync_states(&dir, &states).unwrap();
+
+        let read = get_sync_states(&dir).unwrap();
+        let state = read["https://example.com/playlist"];
+        assert_eq!(read.len(), 1);
+        assert_eq!((state.time, state.new), (time, 3));
+    }
+}
@@ -1,25 +1,30 @@
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
 
 use log::warn;
 
+use crate::backend;
 use crate::commands::add::add;
-use crate::music_tag::get_music_tag;
+use crate::config::{get_config, ConflictPolicy};
+use crate::music_tag::TagReader;
+use crate::planner::{self, Operation};
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 
 pub fn tag(
     dir: PathBuf,
     files: &[String],
-    category: &str,
+    category_dir: &Path,
     quiet: &bool,
     force: &bool,
+    conflict: Option<ConflictPolicy>,
 ) -> Result<()> {
+    let config = get_config()?;
+    let reader = TagReader::from_config(&config);
     let mut tagged = vec![];
     let mut files = files.to_owned();
     // Retain files without tags
     files.retain(|file| {
-        if !force && get_music_tag(file.as_ref()).is_ok() {
+        if !force && reader.read(file.as_ref()).is_ok() {
             warn!("\"{}\" already has music tags, skipping tagging", file);
             tagged.push(file.clone());
             false
@@ -28,25 +33,23 @@ pub fn tag(
         }
     });
     if !files.is_empty() {
-        Command::new("picard")
-            .current_dir(dir)
-            .stdout(if *quiet {
-                Stdio::null()
-            } else {
-                Stdio::inherit()
-            })
-            .stderr(if *quiet {
-                Stdio::null()
-            } else {
-                Stdio::inherit()
-            })
-            .arg("-s") // standalone instance of Picard
-            .args(&files)
-            .status()
-            .context("Could not execute picard")?;
+        if planner::plan(Operation::Tag(files.iter().map(PathBuf::from).collect())) {
+            return Ok(());
+        }
+        backend::tagger(&config).tag(&dir, &files, quiet)?;
+        // the tagger changed the albums
+        reader.clear();
         let mut tagged_files: Vec<String> = files.clone();
-        tagged_files.retain(|file| get_music_tag(file.as_ref()).is_ok());
-        add(&tagged_files, category, quiet, force, &true)?;
+        tagged_files.retain(|file| reader.read(file.as_ref()).is_ok());
+        add(
+            &tagged_files,
+            category_dir,
+            quiet,
+            force,
+            &true,
+            conflict,
+            None,
+        )?;
     } else {
         // In main the empty files is checked
         warn!("All files where tagged")
@@ -0,0 +1,532 @@
+//! Templates for the path of music files in a category,
+//! like `{album_artist}/[{year} - ]{album}/{disc}-{track:02} {title}.{ext}`
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    category::CategoryConfig,
+    commands::change_forbidden_chars,
+    config::{Config, DiscLayout},
+    field::Field,
+    music_tag::{MusicTag, VARIOUS_ARTISTS},
+};
+
+/// The path of the music files in a category if there is no template
+pub const DEFAULT_TEMPLATE: &str = "{album_artist}/{album}/{filename}";
+/// The path of the music files in a category dedicated to one artist
+pub const ARTIST_CATEGORY_TEMPLATE: &str = "{album}/{filename}";
+/// The file names rename uses if the path template keeps the file names
+pub const DEFAULT_FILE_NAME_TEMPLATE: &str = "[{track:02} ]{title}.{ext}";
+/// The index and check only look this deep into a category
+const MAX_DIR_DEPTH: usize = 3;
+/// The start of the name of the directories of the discs of an album
+const DISC_DIR_PREFIX: &str = "Disc ";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Field {
+        field: Field,
+        width: usize,
+    },
+    /// Left out completely if one of the fields in it has no value
+    Optional(Vec<Part>),
+}
+
+/// A parsed path template, the last part of the path is the file name
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+    /// Used as the album artist of compilations
+    compilations_dir: String,
+    /// Where the discs of albums with more than one disc go
+    disc_layout: DiscLayout,
+}
+
+impl Template {
+    /// Parse a template, fields are between `{}` with an optional zero padded width
+    /// like `{track:02}`, and parts between `[]` are left out if a field in it is missing
+    pub fn parse(template: &str) -> Result<Template> {
+        let mut chars = template.chars();
+        let parts = parse_parts(&mut chars, false)
+            .map_err(|err| anyhow!("Invalid path template \"{template}\": {err}"))?;
+        let template = Template {
+            parts,
+            compilations_dir: VARIOUS_ARTISTS.to_string(),
+            disc_layout: DiscLayout::Flat,
+        };
+        if template.dir_depth() > MAX_DIR_DEPTH {
+            return Err(anyhow!(
+                "Path template has more than {MAX_DIR_DEPTH} directories"
+            ));
+        }
+        Ok(template)
+    }
+
+    /// The template of a category, the template of the category comes first,
+    /// then the default for artist categories, then the template in the config.
+    /// The disc layout of the category comes before the one in the config
+    pub fn for_category(config: &Config, category_config: &CategoryConfig) -> Result<Template> {
+        let template = match &category_config.path_template {
+            Some(template) => template,
+            None if category_config.artist_category.unwrap_or(false) => ARTIST_CATEGORY_TEMPLATE,
+            None => config.path_template.as_deref().unwrap_or(DEFAULT_TEMPLATE),
+        };
+        let mut template = Template::parse(template)?;
+        if let Some(dir) = &category_config.compilations_dir {
+            template.compilations_dir = dir.clone();
+        }
+        template.disc_layout = category_config
+            .disc_layout
+            .or(config.disc_layout)
+            .unwrap_or_default();
+        if template.disc_layout == DiscLayout::Subfolders && template.dir_depth() >= MAX_DIR_DEPTH {
+            return Err(anyhow!(
+                "Path template can have at most {} directories with disc subfolders",
+                MAX_DIR_DEPTH - 1
+            ));
+        }
+        Ok(template)
+    }
+
+    /// The number of directories in the template before the file name
+    pub fn dir_depth(&self) -> usize {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.matches('/').count(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Only the file name part of the template,
+    /// none if the template keeps the file name the file already has.
+    /// Disc subfolders are left out, because they are not part of the file name
+    pub fn file_name(&self) -> Option<Template> {
+        let mut parts = vec![];
+        for part in self.parts.iter().rev() {
+            match part {
+                Part::Text(text) if text.contains('/') => {
+                    let (_, name) = text.rsplit_once('/').unwrap_or_default();
+                    if !name.is_empty() {
+                        parts.push(Part::Text(name.to_string()));
+                    }
+                    break;
+                }
+                part => parts.push(part.clone()),
+            }
+        }
+        parts.reverse();
+        let keeps_name = parts
+            == [Part::Field {
+                field: Field::FileName,
+                width: 0,
+            }];
+        let disc_layout = match self.disc_layout {
+            DiscLayout::Subfolders => DiscLayout::Flat,
+            layout => layout,
+        };
+        match keeps_name && disc_layout == DiscLayout::Flat {
+            true => None,
+            false => Some(Template {
+                parts,
+                compilations_dir: self.compilations_dir.clone(),
+                disc_layout,
+            }),
+        }
+    }
+
+    /// If the file name keeps the extension of the file, with `{ext}` or `{filename}`
+    /// that are not in an optional part
+    pub fn keeps_extension(&self) -> bool {
+        let name_start = self
+            .parts
+            .iter()
+            .rposition(|part| matches!(part, Part::Text(text) if text.contains('/')))
+            .map_or(0, |last| last + 1);
+        self.parts[name_start..].iter().any(|part| {
+            matches!(
+                part,
+                Part::Field {
+                    field: Field::Ext | Field::FileName,
+                    ..
+                }
+            )
+        })
+    }
+
+    /// The fields the template needs that the tag does not have
+    pub fn missing_fields(&self, tag: &MusicTag, file: &Path) -> Vec<&'static str> {
+        self.parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Field { field, width } if field.value(tag, file, *width).is_none() => {
+                    Some(field.name())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The path of a music file relative to the category directory
+    pub fn render(&self, tag: &MusicTag, file: &Path) -> Result<PathBuf> {
+        // compilations are kept together instead of split over the album artists
+        let compilation;
+        let tag = match tag.compilation {
+            true => {
+                compilation = MusicTag {
+                    album_artist: self.compilations_dir.clone(),
+                    ..tag.clone()
+                };
+                &compilation
+            }
+            false => tag,
+        };
+        let missing = self.missing_fields(tag, file);
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "Missing {} for the path template",
+                missing.join(", ")
+            ));
+        }
+        let mut path: PathBuf = render_parts(&self.parts, tag, file)
+            .unwrap_or_default()
+            .split('/')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        let Some(name) = path.file_name().map(|name| name.to_os_string()) else {
+            return Err(anyhow!("Template gave an empty path"));
+        };
+        // the discs of an album are kept apart unless the template already does that
+        if let Some(disc) = tag.disc.filter(|_| is_multi_disc(tag) && !self.has_disc()) {
+            match self.disc_layout {
+                DiscLayout::Flat => {}
+                DiscLayout::Subfolders => {
+                    path.set_file_name(disc_dir(disc));
+                    path.push(name);
+                }
+                DiscLayout::Prefix => {
+                    // a file name from the file name can already have the prefix
+                    let name = name.to_string_lossy();
+                    if !has_disc_prefix(&name, disc, tag.track) {
+                        path.set_file_name(format!("{disc}-{name}"));
+                    }
+                }
+            }
+        }
+        Ok(path)
+    }
+
+    /// The values of the fields from the end of the path of a file,
+    /// the extension is left out when the template has no `{ext}`.
+    /// Gives none if the path does not match
+    pub fn match_path(&self, file: &Path) -> Option<Vec<(Field, String)>> {
+        fn has_ext(parts: &[Part]) -> bool {
+            parts.iter().any(|part| match part {
+                Part::Field { field, .. } => *field == Field::Ext,
+                Part::Optional(parts) => has_ext(parts),
+                Part::Text(_) => false,
+            })
+        }
+        let path = match has_ext(&self.parts) {
+            true => file.to_path_buf(),
+            false => file.with_extension(""),
+        };
+        let names: Vec<String> = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect();
+        let names = names.get(names.len().checked_sub(self.dir_depth() + 1)?..)?;
+        let parts: Vec<&Part> = self.parts.iter().collect();
+        match_parts(&parts, &names.join("/"))
+    }
+
+    /// If the template has the disc number in it
+    fn has_disc(&self) -> bool {
+        fn has_disc(parts: &[Part]) -> bool {
+            parts.iter().any(|part| match part {
+                Part::Field { field, .. } => *field == Field::Disc,
+                Part::Optional(parts) => has_disc(parts),
+                Part::Text(_) => false,
+            })
+        }
+        has_disc(&self.parts)
+    }
+}
+
+/// The name of the directory of a disc in an album directory
+pub fn disc_dir(disc: u32) -> String {
+    format!("{DISC_DIR_PREFIX}{disc}")
+}
+
+/// If a directory in an album directory is the directory of a disc, like "Disc 1"
+pub fn is_disc_dir(dir: &Path) -> bool {
+    dir.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix(DISC_DIR_PREFIX))
+        .is_some_and(|disc| disc.parse::<u32>().is_ok())
+}
+
+/// The album directory of a music file, the directory of its disc is skipped
+pub fn album_dir(file: &Path) -> Option<&Path> {
+    match file.parent() {
+        Some(dir) if is_disc_dir(dir) => dir.parent(),
+        dir => dir,
+    }
+}
+
+/// If the music is part of an album with more than one disc
+pub fn is_multi_disc(tag: &MusicTag) -> bool {
+    tag.multi_disc
+        || tag.disc_total.is_some_and(|total| total > 1)
+        || tag.disc.is_some_and(|disc| disc > 1)
+}
+
+/// If a file name starts with the disc prefix, like "2-05 Title.flac" for track 5 on disc 2.
+/// The track number has to follow the prefix, so "1-800 Number.flac" on disc 1 has no prefix
+fn has_disc_prefix(name: &str, disc: u32, track: Option<u32>) -> bool {
+    let Some(rest) = name.strip_prefix(&format!("{disc}-")) else {
+        return false;
+    };
+    let rest = match track {
+        Some(track) => match rest.strip_prefix(&format!("{track:02}")) {
+            Some(rest) => rest,
+            None => return false,
+        },
+        None => rest,
+    };
+    !rest.starts_with(|char: char| char.is_ascii_digit())
+}
+
+fn parse_parts(chars: &mut std::str::Chars, optional: bool) -> Result<Vec<Part>> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    while let Some(char) = chars.next() {
+        match char {
+            '{' | '[' | ']' if !text.is_empty() => {
+                parts.push(Part::Text(std::mem::take(&mut text)))
+            }
+            _ => {}
+        }
+        match char {
+            '{' => {
+                let mut field = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(char) => field.push(char),
+                        None => return Err(anyhow!("\"{{\" without \"}}\"")),
+                    }
+                }
+                let (name, width) = match field.split_once(':') {
+                    Some((name, width)) if width.starts_with('0') => (
+                        name,
+                        width
+                            .parse()
+                            .map_err(|_| anyhow!("invalid width \"{width}\""))?,
+                    ),
+                    Some((_, width)) => return Err(anyhow!("invalid width \"{width}\"")),
+                    None => (field.as_str(), 0),
+                };
+                parts.push(Part::Field {
+                    field: Field::parse(name)?,
+                    width,
+                });
+            }
+            '}' => return Err(anyhow!("\"}}\" without \"{{\"")),
+            '[' if optional => return Err(anyhow!("\"[\" in \"[]\"")),
+            '[' => parts.push(Part::Optional(parse_parts(chars, true)?)),
+            ']' if optional => return Ok(parts),
+            ']' => return Err(anyhow!("\"]\" without \"[\"")),
+            '/' if optional => return Err(anyhow!("\"/\" in \"[]\"")),
+            char => text.push(char),
+        }
+    }
+    if optional {
+        return Err(anyhow!("\"[\" without \"]\""));
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    Ok(parts)
+}
+
+/// Fill in the fields, gives none if an optional part has a field without a value
+fn render_parts(parts: &[Part], tag: &MusicTag, file: &Path) -> Option<String> {
+    let mut output = String::new();
+    for part in parts {
+        match part {
+            Part::Text(text) => output.push_str(text),
+            Part::Field { field, width } => match field.value(tag, file, *width) {
+                Some(value) => output.push_str(&change_forbidden_chars(&value)),
+                None => return None,
+            },
+            Part::Optional(parts) => {
+                if let Some(text) = render_parts(parts, tag, file) {
+                    output.push_str(&text);
+                }
+            }
+        }
+    }
+    Some(output)
+}
+
+/// Match text to the parts, fields match as little as possible but not nothing,
+/// and parts between `[]` are tried with and without them
+fn match_parts(parts: &[&Part], text: &str) -> Option<Vec<(Field, String)>> {
+    let Some((first, rest)) = parts.split_first() else {
+        return text.is_empty().then(Vec::new);
+    };
+    match first {
+        Part::Text(literal) => match_parts(rest, text.strip_prefix(literal.as_str())?),
+        Part::Optional(optional) => {
+            let with: Vec<&Part> = optional.iter().chain(rest.iter().copied()).collect();
+            match_parts(&with, text).or_else(|| match_parts(rest, text))
+        }
+        Part::Field { field, .. } => text
+            .char_indices()
+            .skip(1)
+            .map(|(index, _)| index)
+            .chain([text.len()])
+            .filter(|index| *index > 0)
+            .take_while(|index| !text[..*index].contains('/'))
+            .find_map(|index| {
+                let mut values = match_parts(rest, &text[index..])?;
+                values.insert(0, (*field, text[..index].to_string()));
+                Some(values)
+            }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::music_tag;
+
+    fn match_path(template: &str, file: &str) -> Option<Vec<(Field, String)>> {
+        Template::parse(template)
+            .unwrap()
+            .match_path(Path::new(file))
+    }
+
+    fn values(values: &[(Field, &str)]) -> Option<Vec<(Field, String)>> {
+        Some(
+            values
+                .iter()
+                .map(|(field, value)| (*field, value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn match_directories_and_file_name() {
+        assert_eq!(
+            match_path(
+                "{album_artist}/{album}/{track:02} - {title}",
+                "/music/rock/Radiohead/OK Computer/02 - Paranoid Android.opus"
+            ),
+            values(&[
+                (Field::AlbumArtist, "Radiohead"),
+                (Field::Album, "OK Computer"),
+                (Field::Track, "02"),
+                (Field::Title, "Paranoid Android"),
+            ])
+        );
+        assert_eq!(
+            match_path("{track} {title}.{ext}", "/music/01 Airbag.flac"),
+            values(&[
+                (Field::Track, "01"),
+                (Field::Title, "Airbag"),
+                (Field::Ext, "flac"),
+            ])
+        );
+    }
+
+    #[test]
+    fn match_optional_part() {
+        let template = "[{track:02} ]{title}";
+        assert_eq!(
+            match_path(template, "/album/03 Subterranean.opus"),
+            values(&[(Field::Track, "03"), (Field::Title, "Subterranean")])
+        );
+        assert_eq!(
+            match_path(template, "/album/Subterranean.opus"),
+            values(&[(Field::Title, "Subterranean")])
+        );
+    }
+
+    #[test]
+    fn no_match() {
+        assert_eq!(match_path("{track} - {title}", "/album/Airbag.opus"), None);
+        assert_eq!(match_path("{artist}/{album}/{title}", "Airbag.opus"), None);
+    }
+
+    #[test]
+    fn keeps_extension() {
+        let keeps = |template: &str| Template::parse(template).unwrap().keeps_extension();
+        assert!(keeps("{track:02} {title}.{ext}"));
+        assert!(keeps("{album}/{filename}"));
+        assert!(keeps(DEFAULT_FILE_NAME_TEMPLATE));
+        assert!(!keeps("{track:02} {title}"));
+        assert!(!keeps("{ext}/{title}"));
+        assert!(!keeps("{title}[.{ext}]"));
+    }
+
+    fn disc_tag(track: u32, disc: u32, disc_total: Option<u32>, multi_disc: bool) -> MusicTag {
+        MusicTag {
+            track: Some(track),
+            disc: Some(disc),
+            disc_total,
+            multi_disc,
+            ..music_tag("Title")
+        }
+    }
+
+    fn render(layout: DiscLayout, tag: &MusicTag, file: &str) -> PathBuf {
+        let mut template = Template::parse("{album}/{filename}").unwrap();
+        template.disc_layout = layout;
+        template.render(tag, Path::new(file)).unwrap()
+    }
+
+    #[test]
+    fn first_disc_of_multi_disc_album() {
+        let file = "/new/01 Title.flac";
+        // the other discs of the album are known
+        assert_eq!(
+            render(DiscLayout::Subfolders, &disc_tag(1, 1, None, true), file),
+            PathBuf::from("Album/Disc 1/01 Title.flac")
+        );
+        assert_eq!(
+            render(DiscLayout::Subfolders, &disc_tag(1, 2, None, false), file),
+            PathBuf::from("Album/Disc 2/01 Title.flac")
+        );
+        // an album with only one disc
+        assert_eq!(
+            render(DiscLayout::Subfolders, &disc_tag(1, 1, None, false), file),
+            PathBuf::from("Album/01 Title.flac")
+        );
+    }
+
+    #[test]
+    fn disc_prefix() {
+        let tag = disc_tag(5, 1, Some(2), false);
+        assert_eq!(
+            render(DiscLayout::Prefix, &tag, "/new/05 Title.flac"),
+            PathBuf::from("Album/1-05 Title.flac")
+        );
+        // already has the prefix
+        assert_eq!(
+            render(DiscLayout::Prefix, &tag, "/new/1-05 Title.flac"),
+            PathBuf::from("Album/1-05 Title.flac")
+        );
+        // starts like a prefix, but is not one
+        assert_eq!(
+            render(DiscLayout::Prefix, &tag, "/new/1-800 Number.flac"),
+            PathBuf::from("Album/1-1-800 Number.flac")
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! Helpers for the unit tests
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard, OnceLock,
+    },
+};
+
+use crate::{config::Config, music_tag::MusicTag, planner};
+
+/// The directory of this test run, with the config directory in it,
+/// so the tests never touch the journal or index of the user
+fn root() -> &'static Path {
+    static ROOT: OnceLock<PathBuf> = OnceLock::new();
+    ROOT.get_or_init(|| {
+        let root = env::temp_dir().join(format!("music_manager-unit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("config")).unwrap();
+        env::set_var("XDG_CONFIG_HOME", root.join("config"));
+        root
+    })
+}
+
+/// A new empty directory for a test, every call gives another one
+pub fn test_dir(name: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = root().join(format!("{name}-{}", COUNT.fetch_add(1, Ordering::Relaxed)));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The dry run, the journal and the index are global,
+/// so tests that use them hold this lock. A dry run is off when it is taken
+pub fn lock() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    let guard = LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    root();
+    planner::set_dry_run(false);
+    guard
+}
+
+/// A config with a music directory, that allows wav files
+pub fn config(music_dir: &Path) -> Config {
+    toml::from_str(&format!(
+        "music_dir = {:?}\ndefault_dir = {:?}\nfile_extensions = [\"wav\"]\n",
+        music_dir,
+        music_dir.join("other")
+    ))
+    .unwrap()
+}
+
+/// A short mono wav file, every sample has the given value
+pub fn wav(path: &Path, sample: i16) -> PathBuf {
+    let samples: u32 = 4410;
+    let mut data = vec![];
+    data.extend(b"RIFF");
+    data.extend((36 + samples * 2).to_le_bytes());
+    data.extend(b"WAVEfmt ");
+    data.extend(16u32.to_le_bytes());
+    data.extend(1u16.to_le_bytes()); // pcm
+    data.extend(1u16.to_le_bytes()); // mono
+    data.extend(44100u32.to_le_bytes());
+    data.extend((44100u32 * 2).to_le_bytes());
+    data.extend(2u16.to_le_bytes());
+    data.extend(16u16.to_le_bytes());
+    data.extend(b"data");
+    data.extend((samples * 2).to_le_bytes());
+    for _ in 0..samples {
+        data.extend(sample.to_le_bytes());
+    }
+    fs::write(path, data).unwrap();
+    path.to_path_buf()
+}
+
+/// The tag of a song on an album of one artist, the other fields can be set with
+/// `MusicTag { track: Some(1), ..music_tag("Title") }`
+pub fn music_tag(title: &str) -> MusicTag {
+    MusicTag {
+        song_title: title.to_string(),
+        artist_name: String::from("Artist"),
+        album_title: String::from("Album"),
+        album_artist: String::from("Artist"),
+        ..Default::default()
+    }
+}
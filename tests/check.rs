@@ -0,0 +1,107 @@
+//! Checking a category, and fixing what is wrong in it
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, path::Path};
+
+use lofty::tag::ItemKey;
+use music_manager::commands::check::{check, ReportFormat};
+
+use common::{lock, music_dir, replaygain_tags, rsgain_runs, wav, write_tags};
+
+/// A category in the music directory of the config
+fn category(name: &str) -> std::path::PathBuf {
+    let dir = music_dir().join(name);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("config.toml"),
+        format!("name = \"{name}\"\ndescription = \"\"\n"),
+    )
+    .unwrap();
+    dir
+}
+
+/// A wav file with the tags of a song of an album
+fn song(dir: &Path, name: &str, title: &str, replaygain: bool) {
+    fs::create_dir_all(dir).unwrap();
+    let file = wav(dir, name);
+    let mut tags = vec![
+        (ItemKey::TrackTitle, title),
+        (ItemKey::TrackArtist, "Artist"),
+        (ItemKey::AlbumArtist, "Artist"),
+        (ItemKey::AlbumTitle, "Album"),
+    ];
+    if replaygain {
+        tags.extend(replaygain_tags());
+    }
+    write_tags(&file, &tags);
+}
+
+#[test]
+fn fix_moves_normalizes_and_removes_empty_dirs() {
+    let _lock = lock();
+    let dir = category("Fixing");
+    let album_dir = dir.join("Artist/Album");
+    song(&album_dir, "a.wav", "A", true);
+    song(&dir.join("Wrong/Place"), "b.wav", "B", false);
+    fs::create_dir_all(dir.join("Empty/Nothing")).unwrap();
+
+    let issues = check(
+        &Some("Fixing".to_string()),
+        &true,
+        &false,
+        &true,
+        ReportFormat::Text,
+        None,
+    )
+    .unwrap();
+    // b.wav is in the wrong place and has no replaygain tags, and an album is empty
+    assert_eq!(issues, 3);
+
+    let left = check(
+        &Some("Fixing".to_string()),
+        &true,
+        &true,
+        &true,
+        ReportFormat::Text,
+        Some(1),
+    )
+    .unwrap();
+    assert!(
+        album_dir.join("b.wav").is_file(),
+        "the misplaced file is moved"
+    );
+    assert!(
+        !dir.join("Wrong").exists(),
+        "the emptied directories are removed"
+    );
+    assert!(!dir.join("Empty").exists(), "the empty album is removed");
+    // the whole album is normalized where b.wav is now
+    let runs = rsgain_runs(&album_dir);
+    assert_eq!(runs.len(), 1);
+    assert!(runs[0].iter().any(|arg| arg.ends_with("a.wav")));
+    assert!(runs[0].iter().any(|arg| arg.ends_with("b.wav")));
+    // the fake rsgain does not write tags, that is left after checking again
+    assert_eq!(left, 1);
+}
+
+#[test]
+fn file_without_extension() {
+    let _lock = lock();
+    let dir = category("Extensionless");
+    let album_dir = dir.join("Artist/Album");
+    song(&album_dir, "a.wav", "A", true);
+    fs::write(album_dir.join("cover"), "not music").unwrap();
+
+    let issues = check(
+        &Some("Extensionless".to_string()),
+        &true,
+        &false,
+        &true,
+        ReportFormat::Text,
+        None,
+    )
+    .unwrap();
+    assert_eq!(issues, 0);
+}
@@ -0,0 +1,143 @@
+//! What the tests share: a config directory of their own, a fake rsgain and music files
+#![allow(dead_code)]
+
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard, OnceLock,
+    },
+};
+
+use lofty::{
+    config::WriteOptions,
+    file::TaggedFileExt,
+    read_from_path,
+    tag::{ItemKey, ItemValue, Tag, TagExt, TagItem},
+};
+
+/// Logs the arguments of every run to rsgain.log in the directory it runs in,
+/// every argument in brackets so empty arguments show up.
+/// It fails for files with "broken" in the name
+const FAKE_RSGAIN: &str = "#!/bin/sh\nprintf '[%s]' \"$@\" >> rsgain.log\necho >> rsgain.log\n\
+    case \"$*\" in *broken*) exit 1 ;; esac\n";
+
+/// The directory of this test run, made once before the first test uses it.
+/// The config in it uses the fake rsgain, and the music directory in it
+fn root() -> &'static Path {
+    static ROOT: OnceLock<PathBuf> = OnceLock::new();
+    ROOT.get_or_init(|| {
+        let root = env::temp_dir().join(format!("music_manager-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let bin = root.join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        let rsgain = bin.join("rsgain");
+        fs::write(&rsgain, FAKE_RSGAIN).unwrap();
+        fs::set_permissions(&rsgain, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config_dir = root.join("config/music_manager");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::create_dir_all(root.join("music")).unwrap();
+        fs::write(
+            config_dir.join("config.toml"),
+            format!(
+                "music_dir = \"{0}\"\ndefault_dir = \"{0}\"\nfile_extensions = [\"wav\", \"opus\"]\n\
+                \n[normalizer]\nprogram = \"rsgain\"\n",
+                root.join("music").display()
+            ),
+        )
+        .unwrap();
+
+        // only set here, before any test runs the code that reads them
+        let path = env::var("PATH").unwrap_or_default();
+        env::set_var("PATH", format!("{}:{path}", bin.display()));
+        env::set_var("XDG_CONFIG_HOME", root.join("config"));
+        root
+    })
+}
+
+/// A new empty directory for a test, every call gives another one
+pub fn test_dir(name: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = root().join(format!("{name}-{}", COUNT.fetch_add(1, Ordering::Relaxed)));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The music directory of the config
+pub fn music_dir() -> PathBuf {
+    root().join("music")
+}
+
+/// The journal and the index in the config directory are shared,
+/// tests that change them hold this lock
+pub fn lock() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    let guard = LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    root();
+    guard
+}
+
+/// A short silent wav file
+pub fn wav(dir: &Path, name: &str) -> PathBuf {
+    let samples: u32 = 4410;
+    let mut data = vec![];
+    data.extend(b"RIFF");
+    data.extend((36 + samples * 2).to_le_bytes());
+    data.extend(b"WAVEfmt ");
+    data.extend(16u32.to_le_bytes());
+    data.extend(1u16.to_le_bytes()); // pcm
+    data.extend(1u16.to_le_bytes()); // mono
+    data.extend(44100u32.to_le_bytes());
+    data.extend((44100u32 * 2).to_le_bytes());
+    data.extend(2u16.to_le_bytes());
+    data.extend(16u16.to_le_bytes());
+    data.extend(b"data");
+    data.extend((samples * 2).to_le_bytes());
+    data.resize(data.len() + samples as usize * 2, 0);
+    let path = dir.join(name);
+    fs::write(&path, data).unwrap();
+    path
+}
+
+/// Write text tags, also the unknown keys
+pub fn write_tags(file: &Path, tags: &[(ItemKey, &str)]) {
+    let mut tagged_file = read_from_path(file).unwrap();
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file.primary_tag_mut().unwrap();
+    for (key, value) in tags {
+        tag.insert_unchecked(TagItem::new(
+            key.clone(),
+            ItemValue::Text(value.to_string()),
+        ));
+    }
+    tag.save_to_path(file, WriteOptions::default()).unwrap();
+}
+
+pub fn replaygain_tags() -> Vec<(ItemKey, &'static str)> {
+    vec![
+        (ItemKey::ReplayGainTrackGain, "-1.00 dB"),
+        (ItemKey::ReplayGainTrackPeak, "0.500000"),
+        (ItemKey::ReplayGainAlbumGain, "-1.00 dB"),
+        (ItemKey::ReplayGainAlbumPeak, "0.500000"),
+    ]
+}
+
+/// The runs of rsgain in the directory, every run with its arguments
+pub fn rsgain_runs(dir: &Path) -> Vec<Vec<String>> {
+    fs::read_to_string(dir.join("rsgain.log"))
+        .unwrap_or_default()
+        .lines()
+        .map(|line| {
+            line.strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+                .map(|line| line.split("][").map(String::from).collect())
+                .unwrap_or_default()
+        })
+        .collect()
+}
@@ -0,0 +1,137 @@
+//! Moving files to another filesystem, where rename does not work
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    env, fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use music_manager::planner;
+
+/// A directory on another filesystem than the temporary directory,
+/// none if there is no such directory, then the tests can not run
+fn other_filesystem() -> Option<PathBuf> {
+    let temp_dev = fs::metadata(env::temp_dir()).ok()?.dev();
+    ["/dev/shm", "/run/user"]
+        .iter()
+        .map(PathBuf::from)
+        .find(|dir| {
+            fs::metadata(dir).is_ok_and(|metadata| metadata.dev() != temp_dev)
+                && fs::write(dir.join(".music_manager-test"), "").is_ok()
+        })
+}
+
+/// A directory on another filesystem to move from, and one for the test to move to
+fn test_dirs(name: &str) -> Option<(PathBuf, PathBuf)> {
+    let Some(other) = other_filesystem() else {
+        eprintln!("No other filesystem to move to, skipping");
+        return None;
+    };
+    let _ = fs::remove_file(other.join(".music_manager-test"));
+    let to = common::test_dir(name);
+    let from = other.join(format!(
+        "music_manager-move-{}-{}",
+        std::process::id(),
+        to.file_name().unwrap().to_string_lossy()
+    ));
+    let _ = fs::remove_dir_all(&from);
+    fs::create_dir_all(&from).unwrap();
+    Some((from, to))
+}
+
+fn clean_up(dirs: &[&Path]) {
+    for dir in dirs {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+#[test]
+fn move_read_only_file() {
+    let Some((from_dir, to_dir)) = test_dirs("read-only") else {
+        return;
+    };
+    let from = from_dir.join("song.opus");
+    let to = to_dir.join("song.opus");
+    let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&from, &content).unwrap();
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    fs::File::options()
+        .write(true)
+        .open(&from)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    fs::set_permissions(&from, fs::Permissions::from_mode(0o444)).unwrap();
+
+    planner::rename(&from, &to).unwrap();
+
+    assert!(!from.exists(), "the original is removed");
+    assert_eq!(fs::read(&to).unwrap(), content);
+    let metadata = fs::metadata(&to).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o444);
+    assert_eq!(metadata.modified().unwrap(), modified);
+    clean_up(&[&from_dir, &to_dir]);
+}
+
+#[test]
+fn failed_move_keeps_original() {
+    let Some((from_dir, to_dir)) = test_dirs("failed") else {
+        return;
+    };
+    let from = from_dir.join("song.opus");
+    fs::write(&from, "music").unwrap();
+    let to = to_dir.join("missing/song.opus");
+
+    assert!(planner::rename(&from, &to).is_err());
+    assert!(from.exists(), "the original is kept");
+    assert!(!to.exists());
+    clean_up(&[&from_dir, &to_dir]);
+}
+
+#[test]
+fn move_over_existing_file() {
+    let Some((from_dir, to_dir)) = test_dirs("existing") else {
+        return;
+    };
+    let from = from_dir.join("song.opus");
+    let to = to_dir.join("song.opus");
+    fs::write(&from, "new").unwrap();
+    fs::write(&to, "old").unwrap();
+
+    planner::rename(&from, &to).unwrap();
+
+    assert!(!from.exists());
+    assert_eq!(fs::read_to_string(&to).unwrap(), "new");
+    let files: Vec<_> = fs::read_dir(&to_dir).unwrap().collect();
+    assert_eq!(files.len(), 1, "the copy was renamed to the target");
+    clean_up(&[&from_dir, &to_dir]);
+}
+
+#[test]
+fn failed_copy_keeps_existing_target() {
+    // the length of the status is unknown before reading, so the copy does not match
+    let from = Path::new("/proc/self/status");
+    if !from.exists()
+        || fs::metadata(from).unwrap().dev() == fs::metadata(env::temp_dir()).unwrap().dev()
+    {
+        eprintln!("No file that changes length on another filesystem, skipping");
+        return;
+    }
+    let to_dir = common::test_dir("failed-copy");
+    let to = to_dir.join("status");
+    fs::write(&to, "old").unwrap();
+
+    assert!(planner::rename(from, &to).is_err());
+    assert_eq!(
+        fs::read_to_string(&to).unwrap(),
+        "old",
+        "the target is kept"
+    );
+    let files: Vec<_> = fs::read_dir(&to_dir).unwrap().collect();
+    assert_eq!(files.len(), 1, "the copy is removed");
+    clean_up(&[&to_dir]);
+}
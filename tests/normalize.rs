@@ -0,0 +1,253 @@
+//! Which albums get normalized, with a stand-in for rsgain that logs its arguments
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use lofty::tag::{ItemKey, TagType};
+use music_manager::normalize::{normalize_albums, normalize_files, Album};
+
+use common::{replaygain_tags, rsgain_runs, test_dir, wav, write_tags};
+
+/// A short opus file with one silent frame
+fn opus(dir: &Path, name: &str) -> PathBuf {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(1); // mono
+    head.extend(312u16.to_le_bytes()); // pre-skip
+    head.extend(48000u32.to_le_bytes());
+    head.extend(0u16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend(4u32.to_le_bytes());
+    tags.extend(b"test");
+    tags.extend(0u32.to_le_bytes());
+    // a 20ms celt frame of silence
+    let frame = [0xF8, 0xFF, 0xFE];
+
+    let mut data = ogg_page(0x02, 0, 0, &head);
+    data.extend(ogg_page(0x00, 0, 1, &tags));
+    data.extend(ogg_page(0x04, 312 + 960, 2, &frame));
+    let path = dir.join(name);
+    fs::write(&path, data).unwrap();
+    path
+}
+
+/// An ogg page with one packet
+fn ogg_page(header_type: u8, granule: u64, sequence: u32, packet: &[u8]) -> Vec<u8> {
+    let mut page = b"OggS".to_vec();
+    page.push(0); // version
+    page.push(header_type);
+    page.extend(granule.to_le_bytes());
+    page.extend(1u32.to_le_bytes()); // serial
+    page.extend(sequence.to_le_bytes());
+    page.extend(0u32.to_le_bytes()); // checksum
+    let mut segments = vec![255; packet.len() / 255];
+    segments.push((packet.len() % 255) as u8);
+    page.push(segments.len() as u8);
+    page.extend(segments);
+    page.extend(packet);
+    let checksum = ogg_crc(&page);
+    page[22..26].copy_from_slice(&checksum.to_le_bytes());
+    page
+}
+
+fn ogg_crc(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = match crc & 0x8000_0000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x04C1_1DB7,
+            };
+        }
+    }
+    crc
+}
+
+fn r128_tags() -> Vec<(ItemKey, &'static str)> {
+    let key = |key: &str| ItemKey::from_key(TagType::VorbisComments, key);
+    vec![
+        (key("R128_TRACK_GAIN"), "-256"),
+        (key("R128_ALBUM_GAIN"), "-256"),
+    ]
+}
+
+/// The files that were given to rsgain, the arguments after the options
+fn normalized_files(run: &[String]) -> Vec<String> {
+    run.iter()
+        .skip_while(|arg| *arg != "i")
+        .skip(1)
+        .map(|file| {
+            Path::new(file)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect()
+}
+
+fn normalize(dir: &Path, files: &[&PathBuf], force: bool) -> bool {
+    let files: Vec<&Path> = files.iter().map(|file| file.as_path()).collect();
+    normalize_files(dir, &files, &true, &force).unwrap()
+}
+
+#[test]
+fn album_without_tags_is_normalized() {
+    let dir = test_dir("without_tags");
+    let (a, b) = (wav(&dir, "a.wav"), wav(&dir, "b.wav"));
+
+    assert!(normalize(&dir, &[&a, &b], false));
+    let runs = rsgain_runs(&dir);
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0][..4], ["custom", "-aq", "-s", "i"]);
+    assert_eq!(normalized_files(&runs[0]), ["a.wav", "b.wav"]);
+}
+
+#[test]
+fn album_with_tags_is_skipped() {
+    let dir = test_dir("with_tags");
+    let (a, b) = (wav(&dir, "a.wav"), wav(&dir, "b.wav"));
+    write_tags(&a, &replaygain_tags());
+    write_tags(&b, &replaygain_tags());
+
+    assert!(!normalize(&dir, &[&a, &b], false));
+    assert!(rsgain_runs(&dir).is_empty());
+}
+
+#[test]
+fn album_with_one_file_without_tags_is_normalized_whole() {
+    let dir = test_dir("one_without_tags");
+    let (a, b, c) = (wav(&dir, "a.wav"), wav(&dir, "b.wav"), wav(&dir, "c.wav"));
+    write_tags(&a, &replaygain_tags());
+    write_tags(&c, &replaygain_tags());
+
+    assert!(normalize(&dir, &[&a, &b, &c], false));
+    let runs = rsgain_runs(&dir);
+    assert_eq!(runs.len(), 1);
+    assert_eq!(normalized_files(&runs[0]), ["a.wav", "b.wav", "c.wav"]);
+}
+
+#[test]
+fn track_gain_without_album_gain_is_normalized() {
+    let dir = test_dir("without_album_gain");
+    let a = wav(&dir, "a.wav");
+    write_tags(&a, &replaygain_tags()[..2]);
+
+    assert!(normalize(&dir, &[&a], false));
+    assert_eq!(rsgain_runs(&dir).len(), 1);
+}
+
+#[test]
+fn force_normalizes_album_with_tags() {
+    let dir = test_dir("force");
+    let (a, b) = (wav(&dir, "a.wav"), wav(&dir, "b.wav"));
+    write_tags(&a, &replaygain_tags());
+    write_tags(&b, &replaygain_tags());
+
+    assert!(normalize(&dir, &[&a, &b], true));
+    let runs = rsgain_runs(&dir);
+    assert_eq!(runs.len(), 1);
+    assert!(runs[0].iter().all(|arg| !arg.is_empty()));
+    assert_eq!(normalized_files(&runs[0]), ["a.wav", "b.wav"]);
+}
+
+#[test]
+fn opus_needs_r128_tags() {
+    let dir = test_dir("opus_replaygain");
+    let a = opus(&dir, "a.opus");
+    write_tags(&a, &replaygain_tags());
+
+    assert!(normalize(&dir, &[&a], false));
+    assert_eq!(rsgain_runs(&dir).len(), 1);
+}
+
+#[test]
+fn opus_with_r128_tags_is_skipped() {
+    let dir = test_dir("opus_r128");
+    let a = opus(&dir, "a.opus");
+    write_tags(&a, &r128_tags());
+
+    assert!(!normalize(&dir, &[&a], false));
+    assert!(rsgain_runs(&dir).is_empty());
+}
+
+#[test]
+fn mixed_formats_use_their_own_tags() {
+    let dir = test_dir("mixed_formats");
+    let (a, b) = (wav(&dir, "a.wav"), opus(&dir, "b.opus"));
+    write_tags(&a, &replaygain_tags());
+    write_tags(&b, &r128_tags());
+
+    assert!(!normalize(&dir, &[&a, &b], false));
+    assert!(rsgain_runs(&dir).is_empty());
+}
+
+/// The albums in directories of their own in the test directory
+fn albums(dir: &Path, albums: &[(&str, &[&str])]) -> Vec<Album> {
+    albums
+        .iter()
+        .map(|(name, files)| {
+            let album_dir = dir.join(name);
+            fs::create_dir_all(&album_dir).unwrap();
+            Album {
+                files: files.iter().map(|file| wav(&album_dir, file)).collect(),
+                dir: album_dir,
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn albums_are_normalized_apart() {
+    let dir = test_dir("albums");
+    let albums = albums(
+        &dir,
+        &[
+            ("first", &["a.wav", "b.wav"]),
+            ("second", &["c.wav"]),
+            ("third", &["d.wav", "e.wav"]),
+        ],
+    );
+    write_tags(&albums[1].files[0], &replaygain_tags());
+
+    let results = normalize_albums(&albums, &true, &false, Some(2)).unwrap();
+    let normalized: Vec<bool> = results.into_iter().map(Result::unwrap).collect();
+    assert_eq!(normalized, [true, false, true]);
+    for (album, files) in [
+        (&albums[0], ["a.wav", "b.wav"]),
+        (&albums[2], ["d.wav", "e.wav"]),
+    ] {
+        let runs = rsgain_runs(&album.dir);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(normalized_files(&runs[0]), files);
+    }
+    assert!(rsgain_runs(&albums[1].dir).is_empty());
+}
+
+#[test]
+fn failing_album_does_not_stop_the_others() {
+    let dir = test_dir("failing_album");
+    let albums = albums(
+        &dir,
+        &[
+            ("first", &["a.wav"]),
+            ("broken", &["broken.wav"]),
+            ("third", &["c.wav"]),
+        ],
+    );
+
+    let results = normalize_albums(&albums, &true, &false, Some(2)).unwrap();
+    assert!(matches!(results[0], Ok(true)));
+    assert!(results[1].is_err());
+    assert!(matches!(results[2], Ok(true)));
+    for album in &albums {
+        assert_eq!(rsgain_runs(&album.dir).len(), 1);
+    }
+}